@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE templates(
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  version INTEGER NOT NULL,
+  subject TEXT NOT NULL,
+  html TEXT NOT NULL,
+  text TEXT NOT NULL,
+  created_at timestamptz NOT NULL,
+  UNIQUE (name, version)
+);
@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE newsletter_issues(
+  id uuid NOT NULL,
+  PRIMARY KEY (id),
+  title TEXT NOT NULL,
+  html_content TEXT,
+  text_content TEXT,
+  markdown_content TEXT,
+  status TEXT NOT NULL CHECK (status IN ('draft', 'scheduled', 'sending', 'sent', 'failed')),
+  created_at timestamptz NOT NULL,
+  updated_at timestamptz NOT NULL,
+  sent_at timestamptz,
+  -- Why sending of a failed issue stopped.
+  failure TEXT
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues ADD COLUMN send_at timestamptz;
+CREATE INDEX newsletter_issues_due_idx ON newsletter_issues (send_at) WHERE status = 'scheduled';
@@ -0,0 +1,10 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN timezone TEXT;
+ALTER TABLE newsletter_issues ADD COLUMN local_send_at timestamp;
+CREATE TABLE newsletter_issue_deliveries(
+  issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+  timezone TEXT NOT NULL,
+  PRIMARY KEY (issue_id, timezone),
+  send_at timestamptz NOT NULL,
+  status TEXT NOT NULL CHECK (status IN ('pending', 'sending', 'sent'))
+);
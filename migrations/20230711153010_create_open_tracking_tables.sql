@@ -0,0 +1,17 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues ADD COLUMN track_opens BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE subscriptions ADD COLUMN tracking_opt_out BOOLEAN NOT NULL DEFAULT false;
+CREATE TABLE newsletter_issue_recipients(
+  id uuid NOT NULL,
+  PRIMARY KEY (id),
+  issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+  subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+  sent_at timestamptz NOT NULL
+);
+CREATE INDEX newsletter_issue_recipients_issue_idx ON newsletter_issue_recipients (issue_id);
+CREATE TABLE open_events(
+  recipient_id uuid NOT NULL REFERENCES newsletter_issue_recipients (id),
+  opened_at timestamptz NOT NULL,
+  user_agent TEXT
+);
+CREATE INDEX open_events_recipient_idx ON open_events (recipient_id);
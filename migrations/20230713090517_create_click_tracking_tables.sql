@@ -0,0 +1,15 @@
+-- Add migration script here
+ALTER TABLE newsletter_issues ADD COLUMN track_clicks BOOLEAN NOT NULL DEFAULT false;
+CREATE TABLE newsletter_issue_links(
+  id SERIAL PRIMARY KEY,
+  issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+  url TEXT NOT NULL,
+  UNIQUE (issue_id, url)
+);
+CREATE TABLE click_events(
+  recipient_id uuid NOT NULL REFERENCES newsletter_issue_recipients (id),
+  link_id INTEGER NOT NULL REFERENCES newsletter_issue_links (id),
+  clicked_at timestamptz NOT NULL,
+  user_agent TEXT
+);
+CREATE INDEX click_events_link_idx ON click_events (link_id);
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE suppressions(
+  email TEXT NOT NULL,
+  PRIMARY KEY (email),
+  reason TEXT NOT NULL CHECK (reason IN ('bounced', 'complained')),
+  created_at timestamptz NOT NULL
+);
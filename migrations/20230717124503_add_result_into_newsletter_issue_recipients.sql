@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE newsletter_issue_recipients
+  ADD COLUMN status TEXT NOT NULL DEFAULT 'pending'
+    CHECK (status IN ('pending', 'sent', 'failed', 'suppressed')),
+  ADD COLUMN message_id TEXT,
+  ADD COLUMN error TEXT;
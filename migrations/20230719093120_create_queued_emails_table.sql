@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE queued_emails(
+  id uuid NOT NULL,
+  PRIMARY KEY (id),
+  recipient TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  html TEXT NOT NULL,
+  text TEXT NOT NULL,
+  created_at timestamptz NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE queued_emails ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE queued_emails ADD COLUMN next_attempt_at timestamptz NOT NULL DEFAULT now();
+-- Emails given up after the last attempt, or rejected for good, are kept
+-- aside for inspection instead of being retried.
+ALTER TABLE queued_emails ADD COLUMN failed_at timestamptz;
+ALTER TABLE queued_emails ADD COLUMN last_error TEXT;
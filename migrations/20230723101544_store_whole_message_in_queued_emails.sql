@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE queued_emails ADD COLUMN message JSONB;
+UPDATE queued_emails
+  SET message = jsonb_build_object('to', recipient, 'subject', subject, 'html', html, 'text', text);
+ALTER TABLE queued_emails ALTER COLUMN message SET NOT NULL;
+ALTER TABLE queued_emails DROP COLUMN subject, DROP COLUMN html, DROP COLUMN text;
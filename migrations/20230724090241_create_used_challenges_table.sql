@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Solved proof-of-work challenges, kept until they expire so each is used
+-- once across all instances.
+CREATE TABLE used_challenges(
+  token TEXT NOT NULL,
+  PRIMARY KEY (token),
+  expires_at timestamptz NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE rate_limits(
+  key TEXT NOT NULL,
+  PRIMARY KEY (key),
+  window_end timestamptz NOT NULL,
+  count INTEGER NOT NULL
+);
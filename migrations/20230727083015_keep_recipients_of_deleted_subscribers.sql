@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE newsletter_issue_recipients ALTER COLUMN subscriber_id DROP NOT NULL;
+ALTER TABLE newsletter_issue_recipients
+  DROP CONSTRAINT newsletter_issue_recipients_subscriber_id_fkey,
+  ADD CONSTRAINT newsletter_issue_recipients_subscriber_id_fkey
+    FOREIGN KEY (subscriber_id) REFERENCES subscriptions (id) ON DELETE SET NULL;
+CREATE INDEX newsletter_issue_recipients_subscriber_idx ON newsletter_issue_recipients (subscriber_id);
+CREATE INDEX subscriptions_subscribed_at_idx ON subscriptions (subscribed_at DESC, id DESC);
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD COLUMN consent_source TEXT;
+CREATE INDEX subscriptions_lower_email_idx ON subscriptions (lower(email));
@@ -0,0 +1,17 @@
+-- Add migration script here
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check
+  CHECK (status IN ('pending_confirmation', 'confirmed', 'unsubscribed', 'bounced', 'complained'));
+CREATE TABLE subscription_status_history(
+  id BIGSERIAL PRIMARY KEY,
+  subscription_id uuid NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+  -- Null when the subscription was created.
+  from_status TEXT,
+  to_status TEXT NOT NULL,
+  changed_by TEXT NOT NULL,
+  changed_at timestamptz NOT NULL
+);
+CREATE INDEX subscription_status_history_subscription_idx
+  ON subscription_status_history (subscription_id, changed_at);
+-- Nobody knows how existing subscriptions got their status.
+INSERT INTO subscription_status_history (subscription_id, from_status, to_status, changed_by, changed_at)
+  SELECT id, NULL, status, 'migration', subscribed_at FROM subscriptions;
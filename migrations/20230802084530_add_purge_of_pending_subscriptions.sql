@@ -0,0 +1,9 @@
+-- Add migration script here
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_status_check;
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check
+  CHECK (status IN ('pending_confirmation', 'confirmed', 'unsubscribed', 'bounced', 'complained', 'expired'));
+-- Anonymized subscriptions keep no token.
+ALTER TABLE subscriptions ALTER COLUMN confirmation_token DROP NOT NULL;
+ALTER TABLE subscriptions ADD COLUMN reminded_at timestamptz;
+CREATE INDEX subscriptions_pending_idx ON subscriptions (subscribed_at)
+  WHERE status = 'pending_confirmation';
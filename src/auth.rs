@@ -1,15 +1,25 @@
-use actix_web::{dev::ServiceRequest, error::ErrorUnauthorized, web::Data, Error};
+use actix_web::{dev::ServiceRequest, web::Data, Error};
 use actix_web_httpauth::extractors::basic::BasicAuth;
 use argonautica::Verifier;
 use secrecy::ExposeSecret;
 use sqlx::PgPool;
+use subtle::ConstantTimeEq;
 
-use crate::{configuration::ApplicationSettings, domains::users};
+use crate::{
+    configuration::{ApplicationSettings, WebhookSettings},
+    domains::users,
+    error::AppError,
+};
 
+/// Check credentials of an admin user, missing ones are answered with the
+/// same problem as wrong ones.
 pub async fn basic_auth_validator(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let Some(credentials) = credentials else {
+        return Err((AppError::Unauthorized.into(), req));
+    };
     let pool = req
         .app_data::<Data<PgPool>>()
         .expect("Pool for connection not set");
@@ -26,10 +36,39 @@ pub async fn basic_auth_validator(
         ) {
             Ok(req)
         } else {
-            Err((ErrorUnauthorized("Unauthorized Access"), req))
+            Err((AppError::Unauthorized.into(), req))
         }
     } else {
-        Err((ErrorUnauthorized("Unauthorized Access"), req))
+        Err((AppError::Unauthorized.into(), req))
+    }
+}
+
+/// Check credentials of the email provider calling our webhook.
+pub async fn webhook_auth_validator(
+    req: ServiceRequest,
+    credentials: Option<BasicAuth>,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    let Some(credentials) = credentials else {
+        return Err((AppError::Unauthorized.into(), req));
+    };
+    let settings = req
+        .app_data::<Data<WebhookSettings>>()
+        .expect("Failed to get webhook settings");
+
+    // Compared in constant time, not to tell how much of a guess was right.
+    let username = credentials
+        .user_id()
+        .as_bytes()
+        .ct_eq(settings.username.as_bytes());
+    let password = credentials
+        .password()
+        .unwrap_or_default()
+        .as_bytes()
+        .ct_eq(settings.password.expose_secret().as_bytes());
+    if bool::from(username & password) {
+        Ok(req)
+    } else {
+        Err((AppError::Unauthorized.into(), req))
     }
 }
 
@@ -0,0 +1,420 @@
+use anyhow::Context;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::configuration::BotProtectionSettings;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds a form token may be used after it was issued.
+const FORM_TOKEN_MAX_AGE: i64 = 24 * 60 * 60;
+/// Seconds a challenge may be solved in.
+const CHALLENGE_MAX_AGE: i64 = 10 * 60;
+
+/// Why a submitted form looks scripted, serialized as the reason code of the
+/// rejection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, thiserror::Error)]
+#[serde(rename_all = "snake_case")]
+pub enum BotRejection {
+    #[error("The hidden field is filled in.")]
+    Honeypot,
+    #[error("The form token is missing, invalid or expired.")]
+    InvalidFormToken,
+    #[error("The form was filled in too fast.")]
+    TooFast,
+    #[error("The challenge is missing, invalid, expired or already used.")]
+    InvalidChallenge,
+    #[error("The solution of the challenge is wrong.")]
+    WrongSolution,
+}
+
+/// Failure of the checks of [`BotProtection`].
+#[derive(Debug, thiserror::Error)]
+pub enum BotCheckError {
+    #[error(transparent)]
+    Rejected(#[from] BotRejection),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Fields of the subscribe form checked by [`BotProtection`].
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct BotFields {
+    /// Hidden from people, only bots fill it in.
+    #[serde(default)]
+    pub website: String,
+    #[serde(default)]
+    pub form_token: Option<String>,
+    #[serde(default)]
+    pub challenge: Option<String>,
+    #[serde(default)]
+    pub solution: Option<String>,
+}
+
+/// Proof-of-work challenge, solved by a `solution` for which the SHA-256 of
+/// `{token}:{solution}` starts with `difficulty` zero bits.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Challenge {
+    pub token: String,
+    pub difficulty: u8,
+}
+
+/// Checks of the subscribe form which keep scripted signups out, each can be
+/// turned off in the configuration.
+///
+/// Form tokens and challenges are signed timestamps, so nothing is stored
+/// until a challenge is used. Used challenges are remembered in Postgres until
+/// they expire, so every instance of the application knows them.
+#[derive(Debug)]
+pub struct BotProtection {
+    settings: BotProtectionSettings,
+    secret: Secret<String>,
+    pool: PgPool,
+}
+
+impl BotProtection {
+    pub fn new(settings: BotProtectionSettings, secret: Secret<String>, pool: PgPool) -> Self {
+        Self {
+            settings,
+            secret,
+            pool,
+        }
+    }
+
+    /// Token the form is rendered with, the fill time is measured from it.
+    pub fn form_token(&self, now: DateTime<Utc>) -> String {
+        self.sign(&now.timestamp().to_string())
+    }
+
+    /// New challenge, `None` when proof of work is turned off.
+    pub fn challenge(&self, now: DateTime<Utc>) -> Option<Challenge> {
+        if self.settings.proof_of_work_difficulty == 0 {
+            return None;
+        }
+        let payload = format!("{}.{}", Uuid::new_v4().simple(), now.timestamp());
+        Some(Challenge {
+            token: self.sign(&payload),
+            difficulty: self.settings.proof_of_work_difficulty,
+        })
+    }
+
+    /// Whether the hidden field of the form is filled in.
+    pub fn is_honeypot_filled(&self, fields: &BotFields) -> bool {
+        self.settings.honeypot && !fields.website.trim().is_empty()
+    }
+
+    /// Check the form token and the challenge solution, a solved challenge
+    /// can not be used again.
+    pub async fn verify(
+        &self,
+        fields: &BotFields,
+        now: DateTime<Utc>,
+    ) -> Result<(), BotCheckError> {
+        if self.settings.min_fill_seconds > 0 {
+            let issued_at = fields
+                .form_token
+                .as_deref()
+                .and_then(|token| self.verify_signature(token))
+                .and_then(timestamp)
+                .filter(|issued_at| now - *issued_at <= Duration::seconds(FORM_TOKEN_MAX_AGE))
+                .ok_or(BotRejection::InvalidFormToken)?;
+            if now - issued_at < Duration::seconds(self.settings.min_fill_seconds as i64) {
+                return Err(BotRejection::TooFast.into());
+            }
+        }
+        if self.settings.proof_of_work_difficulty > 0 {
+            self.verify_solution(fields, now).await?;
+        }
+        Ok(())
+    }
+
+    async fn verify_solution(
+        &self,
+        fields: &BotFields,
+        now: DateTime<Utc>,
+    ) -> Result<(), BotCheckError> {
+        let token = fields
+            .challenge
+            .as_deref()
+            .ok_or(BotRejection::InvalidChallenge)?;
+        let issued_at = self
+            .verify_signature(token)
+            .and_then(|payload| timestamp(payload.rsplit_once('.')?.1))
+            .filter(|issued_at| now - *issued_at <= Duration::seconds(CHALLENGE_MAX_AGE))
+            .ok_or(BotRejection::InvalidChallenge)?;
+        let solution = fields.solution.as_deref().unwrap_or_default();
+        if !is_solved(token, solution, self.settings.proof_of_work_difficulty) {
+            return Err(BotRejection::WrongSolution.into());
+        }
+
+        if !self
+            .use_challenge(token, issued_at + Duration::seconds(CHALLENGE_MAX_AGE), now)
+            .await?
+        {
+            return Err(BotRejection::InvalidChallenge.into());
+        }
+        Ok(())
+    }
+
+    /// Remember the challenge as used until it expires, `false` when it was
+    /// used before. Expired challenges are forgotten.
+    async fn use_challenge(
+        &self,
+        token: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<bool> {
+        sqlx::query!("delete from used_challenges where expires_at <= $1", now)
+            .execute(&self.pool)
+            .await
+            .context("Failed to delete expired challenges.")?;
+        let res = sqlx::query!(
+            r#"insert into used_challenges (token, expires_at) values ($1, $2)
+            on conflict do nothing"#,
+            token,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await
+        .context("Failed to store used challenge.")?;
+        Ok(res.rows_affected() == 1)
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let signature = hex::encode(self.mac(payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Payload of a token signed here.
+    fn verify_signature<'a>(&self, token: &'a str) -> Option<&'a str> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        self.mac(payload)
+            .verify_slice(&hex::decode(signature).ok()?)
+            .ok()?;
+        Some(payload)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(b"bot_protection.");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn timestamp(seconds: &str) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(seconds.parse().ok()?, 0).single()
+}
+
+/// The hash of the challenge and solution starts with `difficulty` zero bits.
+pub fn is_solved(token: &str, solution: &str, difficulty: u8) -> bool {
+    let hash = Sha256::digest(format!("{}:{}", token, solution).as_bytes());
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    zeros >= u32::from(difficulty)
+}
+
+/// Find a solution of the challenge, as the form's script does.
+pub fn solve(challenge: &Challenge) -> String {
+    (0u64..)
+        .map(|n| n.to_string())
+        .find(|solution| is_solved(&challenge.token, solution, challenge.difficulty))
+        .expect("Some number solves the challenge")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+    use secrecy::Secret;
+    use sqlx::PgPool;
+
+    use super::{is_solved, solve, BotCheckError, BotFields, BotProtection, BotRejection};
+    use crate::configuration::BotProtectionSettings;
+
+    fn protection(
+        min_fill_seconds: u64,
+        proof_of_work_difficulty: u8,
+        pool: &PgPool,
+    ) -> BotProtection {
+        BotProtection::new(
+            BotProtectionSettings {
+                honeypot: true,
+                min_fill_seconds,
+                proof_of_work_difficulty,
+            },
+            Secret::new("secret".into()),
+            pool.clone(),
+        )
+    }
+
+    async fn verify(
+        protection: &BotProtection,
+        fields: &BotFields,
+        now: DateTime<Utc>,
+    ) -> Result<(), BotRejection> {
+        protection.verify(fields, now).await.map_err(|e| match e {
+            BotCheckError::Rejected(rejection) => rejection,
+            BotCheckError::UnexpectedError(e) => panic!("{:?}", e),
+        })
+    }
+
+    #[sqlx::test]
+    fn turned_off_checks_pass(pool: PgPool) {
+        let protection = protection(0, 0, &pool);
+
+        assert!(protection.challenge(Utc::now()).is_none());
+        assert_eq!(
+            verify(&protection, &BotFields::default(), Utc::now()).await,
+            Ok(())
+        );
+    }
+
+    #[sqlx::test]
+    fn honeypot_is_detected(pool: PgPool) {
+        let protection = protection(0, 0, &pool);
+        let fields = BotFields {
+            website: "https://spam.com".into(),
+            ..Default::default()
+        };
+
+        assert!(protection.is_honeypot_filled(&fields));
+        assert!(!protection.is_honeypot_filled(&BotFields::default()));
+    }
+
+    #[sqlx::test]
+    fn form_must_not_be_filled_too_fast(pool: PgPool) {
+        let protection = protection(3, 0, &pool);
+        let now = Utc::now();
+        let fields = BotFields {
+            form_token: Some(protection.form_token(now)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            verify(&protection, &fields, now + Duration::seconds(1)).await,
+            Err(BotRejection::TooFast)
+        );
+        assert_eq!(
+            verify(&protection, &fields, now + Duration::seconds(3)).await,
+            Ok(())
+        );
+        assert_eq!(
+            verify(&protection, &fields, now + Duration::days(2)).await,
+            Err(BotRejection::InvalidFormToken)
+        );
+    }
+
+    #[sqlx::test]
+    fn forged_form_token_is_rejected(pool: PgPool) {
+        let protection = protection(3, 0, &pool);
+        let now = Utc::now();
+        let token = protection.form_token(now - Duration::minutes(1));
+        let (_, signature) = token.split_once('.').unwrap();
+        let fields = BotFields {
+            form_token: Some(format!(
+                "{}.{}",
+                (now - Duration::hours(1)).timestamp(),
+                signature
+            )),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            verify(&protection, &fields, now).await,
+            Err(BotRejection::InvalidFormToken)
+        );
+    }
+
+    #[sqlx::test]
+    fn solved_challenge_is_accepted_once(pool: PgPool) {
+        let protection = protection(0, 8, &pool);
+        let now = Utc::now();
+        let challenge = protection.challenge(now).unwrap();
+        let fields = BotFields {
+            challenge: Some(challenge.token.clone()),
+            solution: Some(solve(&challenge)),
+            ..Default::default()
+        };
+
+        assert_eq!(verify(&protection, &fields, now).await, Ok(()));
+        assert_eq!(
+            verify(&protection, &fields, now).await,
+            Err(BotRejection::InvalidChallenge)
+        );
+    }
+
+    #[sqlx::test]
+    fn challenge_used_by_another_instance_is_rejected(pool: PgPool) {
+        let first = protection(0, 8, &pool);
+        let second = protection(0, 8, &pool);
+        let now = Utc::now();
+        let challenge = first.challenge(now).unwrap();
+        let fields = BotFields {
+            challenge: Some(challenge.token.clone()),
+            solution: Some(solve(&challenge)),
+            ..Default::default()
+        };
+
+        assert_eq!(verify(&first, &fields, now).await, Ok(()));
+        assert_eq!(
+            verify(&second, &fields, now).await,
+            Err(BotRejection::InvalidChallenge)
+        );
+
+        // Expired ones are forgotten.
+        let later = first.challenge(now + Duration::minutes(6)).unwrap();
+        let fields = BotFields {
+            challenge: Some(later.token.clone()),
+            solution: Some(solve(&later)),
+            ..Default::default()
+        };
+        verify(&second, &fields, now + Duration::minutes(11))
+            .await
+            .unwrap();
+        let stored = sqlx::query!(r#"select count(*) as "count!" from used_challenges"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored.count, 1);
+    }
+
+    #[sqlx::test]
+    fn wrong_or_late_solution_is_rejected(pool: PgPool) {
+        let protection = protection(0, 8, &pool);
+        let now = Utc::now();
+        let challenge = protection.challenge(now).unwrap();
+        let wrong_solution = (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| !is_solved(&challenge.token, solution, challenge.difficulty))
+            .unwrap();
+        let wrong = BotFields {
+            challenge: Some(challenge.token.clone()),
+            solution: Some(wrong_solution),
+            ..Default::default()
+        };
+        let late = BotFields {
+            challenge: Some(challenge.token.clone()),
+            solution: Some(solve(&challenge)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            verify(&protection, &wrong, now).await,
+            Err(BotRejection::WrongSolution)
+        );
+        assert_eq!(
+            verify(&protection, &late, now + Duration::minutes(11)).await,
+            Err(BotRejection::InvalidChallenge)
+        );
+    }
+}
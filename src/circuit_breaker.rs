@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// State of a circuit breaker, as shown on the health endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go through.
+    Closed,
+    /// Requests fail fast, the service is considered down.
+    Open,
+    /// One probe request is let through to find out whether the service is
+    /// back.
+    HalfOpen,
+}
+
+/// Stops calling a failing service for a while.
+///
+/// The circuit opens when at least `failure_ratio` of the last `window`
+/// requests failed. After `open_for` a single probe request is allowed, the
+/// circuit closes when it succeeds and opens again when it fails.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_ratio: f64,
+    window: usize,
+    open_for: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+enum Inner {
+    /// Outcomes of the last requests, `true` for a failure.
+    Closed(VecDeque<bool>),
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        probing: bool,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_ratio: f64, window: usize, open_for: Duration) -> Self {
+        Self {
+            failure_ratio,
+            window: window.max(1),
+            open_for,
+            inner: Mutex::new(Inner::Closed(VecDeque::new())),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match &*self.inner.lock().unwrap() {
+            Inner::Closed(_) => CircuitState::Closed,
+            Inner::Open { until } if *until <= Instant::now() => CircuitState::HalfOpen,
+            Inner::Open { .. } => CircuitState::Open,
+            Inner::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a request may be made now, the returned attempt reports the
+    /// outcome of the request.
+    pub fn allow(&self) -> Option<Attempt<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let allowed = match &mut *inner {
+            Inner::Closed(_) => true,
+            Inner::Open { until } if *until <= Instant::now() => {
+                *inner = Inner::HalfOpen { probing: true };
+                true
+            }
+            Inner::Open { .. } => false,
+            Inner::HalfOpen { probing } if !*probing => {
+                *probing = true;
+                true
+            }
+            Inner::HalfOpen { .. } => false,
+        };
+        allowed.then(|| Attempt {
+            breaker: self,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, failed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        match &mut *inner {
+            Inner::Closed(outcomes) => {
+                outcomes.push_back(failed);
+                if outcomes.len() > self.window {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|f| **f).count();
+                if outcomes.len() == self.window
+                    && failures as f64 >= self.failure_ratio * self.window as f64
+                {
+                    tracing::warn!("Circuit opened after {} failures", failures);
+                    *inner = self.open();
+                }
+            }
+            Inner::HalfOpen { .. } if failed => *inner = self.open(),
+            Inner::HalfOpen { .. } => {
+                tracing::info!("Circuit closed after successful probe");
+                *inner = Inner::Closed(VecDeque::new());
+            }
+            // Requests allowed before the circuit opened.
+            Inner::Open { .. } => {}
+        }
+    }
+
+    /// The request was given up without an outcome, e.g. its future was
+    /// dropped, another probe may be made.
+    fn abandon(&self) {
+        if let Inner::HalfOpen { probing } = &mut *self.inner.lock().unwrap() {
+            *probing = false;
+        }
+    }
+
+    fn open(&self) -> Inner {
+        Inner::Open {
+            until: Instant::now() + self.open_for,
+        }
+    }
+}
+
+/// A request allowed by the circuit breaker.
+///
+/// Dropping it without [`Attempt::record`] counts neither as a success nor as
+/// a failure, it only frees the place of a half-open probe.
+#[must_use]
+#[derive(Debug)]
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    recorded: bool,
+}
+
+impl Attempt<'_> {
+    /// Report whether the request failed.
+    pub fn record(mut self, failed: bool) {
+        self.recorded = true;
+        self.breaker.record(failed);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.breaker.abandon();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{CircuitBreaker, CircuitState};
+
+    #[test]
+    fn opens_at_failure_ratio() {
+        let breaker = CircuitBreaker::new(0.5, 4, Duration::from_secs(60));
+
+        for failed in [false, true, false] {
+            breaker.allow().unwrap().record(failed);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.allow().unwrap().record(true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.allow().is_none());
+    }
+
+    #[test]
+    fn old_failures_leave_the_window() {
+        let breaker = CircuitBreaker::new(1.0, 2, Duration::from_secs(60));
+
+        for failed in [true, false, false, true] {
+            breaker.allow().unwrap().record(failed);
+        }
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_probe_closes_or_opens_the_circuit() {
+        let breaker = CircuitBreaker::new(1.0, 1, Duration::ZERO);
+        breaker.allow().unwrap().record(true);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        // Only one probe at a time.
+        let probe = breaker.allow().unwrap();
+        assert!(breaker.allow().is_none());
+        probe.record(true);
+
+        breaker.allow().unwrap().record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn dropped_probe_lets_another_one_through() {
+        let breaker = CircuitBreaker::new(1.0, 1, Duration::ZERO);
+        breaker.allow().unwrap().record(true);
+
+        drop(breaker.allow().unwrap());
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.allow().unwrap().record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -1,13 +1,27 @@
+use chrono_tz::Tz;
 use config::Config;
+use ipnet::IpNet;
 use secrecy::{ExposeSecret, Secret};
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 
+use crate::email_client::Senders;
+
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub scheduler: SchedulerSettings,
+    pub outbox: OutboxSettings,
+    pub newsletter: NewsletterSettings,
+    pub webhook: WebhookSettings,
+    pub subscription_policy: SubscriptionPolicySettings,
+    pub bot_protection: BotProtectionSettings,
+    pub rate_limit: RateLimitSettings,
+    pub purge: PurgeSettings,
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
@@ -15,7 +29,13 @@ pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
     pub host: String,
+    /// Public url of the application, used in links in emails.
+    pub base_url: String,
     pub hash_secret: Secret<String>,
+    /// Key of signatures of links in emails, kept apart from `hash_secret`.
+    pub link_secret: Secret<String>,
+    /// Key of signatures of subscribe form tokens and challenges.
+    pub form_secret: Secret<String>,
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
@@ -31,9 +51,154 @@ pub struct DatabaseSettings {
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct EmailClientSettings {
     pub base_url: String,
-    pub sender: String,
+    /// Who emails are sent as, addresses are validated when loaded.
+    pub senders: Senders,
     pub timeout_milliseconds: u64,
     pub token: Secret<String>,
+    /// Provider accepts many messages in one request.
+    #[serde(default)]
+    pub batch_api: bool,
+    /// Most requests made to the provider in a second.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_per_second: u32,
+    /// Most requests to the provider running at once.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_in_flight: usize,
+    pub circuit_breaker: CircuitBreakerSettings,
+}
+
+/// When to stop calling the email provider, see [`crate::circuit_breaker`].
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct CircuitBreakerSettings {
+    /// Share of failed requests in the window which opens the circuit.
+    pub failure_ratio: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub window: usize,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub open_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct OutboxSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SchedulerSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_milliseconds: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct NewsletterSettings {
+    /// Timezone of subscribers who did not set any.
+    pub default_timezone: Tz,
+}
+
+/// Credentials the email provider uses when calling our webhook.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct WebhookSettings {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+/// Addresses which may subscribe, see [`crate::subscription_policy`].
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SubscriptionPolicySettings {
+    /// File with blocked and allowed domains, reloaded by an admin request.
+    #[serde(default)]
+    pub domains_file: Option<String>,
+    /// Local parts of addresses of roles rather than people, e.g. `noreply`.
+    #[serde(default)]
+    pub role_accounts: Vec<String>,
+    /// Most `+` aliases in the local part, `tom+news@` has one.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_alias_depth: usize,
+}
+
+/// Checks of the subscribe form, see [`crate::bot_protection`].
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct BotProtectionSettings {
+    /// Silently drop forms with the hidden field filled in.
+    pub honeypot: bool,
+    /// Least seconds between issuing the form token and submitting the form,
+    /// 0 turns the form token off.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_seconds: u64,
+    /// Leading zero bits of a solved challenge, 0 turns proof of work off.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub proof_of_work_difficulty: u8,
+}
+
+/// Request limits of route groups, see [`crate::rate_limit`].
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct RateLimitSettings {
+    pub store: RateLimitStore,
+    /// Proxies whose `X-Forwarded-For` header tells the client address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    pub public: RateLimit,
+    pub auth: RateLimit,
+    pub admin: RateLimit,
+    /// Subscriptions of one email address.
+    pub per_email: RateLimit,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitStore {
+    /// Counted by each instance on its own.
+    Memory,
+    /// Counted in the database, shared by all instances.
+    Postgres,
+}
+
+/// At most `requests` in a window of `window_seconds`.
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub requests: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub window_seconds: u64,
+}
+
+/// Removal of subscriptions nobody confirmed, see [`crate::purge`].
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct PurgeSettings {
+    /// Days a subscription may stay pending before it is purged.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retention_days: u32,
+    /// Days after subscribing the one reminder is sent, none when unset.
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub reminder_after_days: Option<u32>,
+    pub mode: PurgeMode,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_seconds: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeMode {
+    /// The subscription is deleted with its history.
+    Delete,
+    /// The subscription expires, its address, name and token are removed.
+    Anonymize,
+}
+
+impl PurgeSettings {
+    /// The reminder has to come before the subscription is purged.
+    pub fn validate(&self) -> Result<(), config::ConfigError> {
+        match self.reminder_after_days {
+            Some(days) if days >= self.retention_days => {
+                Err(config::ConfigError::Message(format!(
+                    "purge.reminder_after_days ({}) must be less than purge.retention_days ({})",
+                    days, self.retention_days
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl DatabaseSettings {
@@ -76,5 +241,7 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
                 .separator("__"),
         )
         .build()?;
-    settings.try_deserialize::<Settings>()
+    let settings = settings.try_deserialize::<Settings>()?;
+    settings.purge.validate()?;
+    Ok(settings)
 }
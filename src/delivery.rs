@@ -0,0 +1,303 @@
+use anyhow::Context;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::{
+    domains::{
+        issue::{Issue, IssueStatus},
+        issues,
+        subscriber::{NewSubscriber, Subscriber},
+        subscribers::get_confirmed_subscribers,
+        template::{RenderedEmail, TemplateName},
+        templates, tracking,
+    },
+    email_client::{EmailClient, EmailMessage, MessageStatus, MAX_BATCH_SIZE},
+    links::{find_links, rewrite_links, TrackingLinks},
+};
+
+/// Placeholders available in newsletters for each recipient.
+pub fn recipient_vars(subscriber: &Subscriber) -> [(&str, &str); 2] {
+    [
+        ("name", subscriber.name.as_ref()),
+        ("email", subscriber.email.as_ref()),
+    ]
+}
+
+/// Email asking the subscriber to confirm the subscription by the link.
+#[tracing::instrument(name = "Rendering confirmation email to subscriber", skip(pool))]
+pub async fn confirmation_email(
+    subscriber: &NewSubscriber,
+    confirmation_link: &str,
+    pool: &PgPool,
+) -> EmailMessage {
+    let email = templates::get_or_default(TemplateName::Confirmation, pool)
+        .await
+        .render(&[
+            ("name", subscriber.name().as_ref()),
+            ("confirmation_link", confirmation_link),
+        ]);
+
+    EmailMessage::new(subscriber.email(), &email.subject, &email.html, &email.text)
+        .sent_as(TemplateName::Confirmation.identity())
+        .tag("confirmation")
+}
+
+/// Email welcoming the subscriber who just confirmed the subscription.
+#[tracing::instrument(name = "Rendering welcome email to subscriber", skip(pool))]
+pub async fn welcome_email(subscriber: &Subscriber, pool: &PgPool) -> EmailMessage {
+    let email = templates::get_or_default(TemplateName::Welcome, pool)
+        .await
+        .render(&[("name", subscriber.name.as_ref())]);
+
+    EmailMessage::new(&subscriber.email, &email.subject, &email.html, &email.text)
+        .sent_as(TemplateName::Welcome.identity())
+        .tag("welcome")
+}
+
+/// Email reminding the subscriber who did not confirm the subscription yet.
+#[tracing::instrument(name = "Rendering confirmation reminder to subscriber", skip(pool))]
+pub async fn confirmation_reminder_email(
+    subscriber: &NewSubscriber,
+    confirmation_link: &str,
+    pool: &PgPool,
+) -> EmailMessage {
+    let email = templates::get_or_default(TemplateName::ConfirmationReminder, pool)
+        .await
+        .render(&[
+            ("name", subscriber.name().as_ref()),
+            ("confirmation_link", confirmation_link),
+        ]);
+
+    EmailMessage::new(subscriber.email(), &email.subject, &email.html, &email.text)
+        .sent_as(TemplateName::ConfirmationReminder.identity())
+        .tag("confirmation_reminder")
+}
+
+/// Put an invisible image at the end of the html body.
+fn with_open_pixel(html: &str, url: &str) -> String {
+    let pixel = format!(
+        "<img src=\"{}\" width=\"1\" height=\"1\" alt=\"\" style=\"display:none\">",
+        url
+    );
+    append_to_body(html, &pixel)
+}
+
+/// Add the link to the page opting out of tracking at the end of both parts.
+fn with_opt_out_link(mut email: RenderedEmail, url: &str) -> RenderedEmail {
+    let link = format!(
+        "<p style=\"font-size:small\"><a href=\"{}\">Stop tracking of opens and clicks</a></p>",
+        url
+    );
+    email.html = append_to_body(&email.html, &link);
+    email.text = format!(
+        "{}\n\nStop tracking of opens and clicks: {}",
+        email.text, url
+    );
+    email
+}
+
+fn append_to_body(html: &str, fragment: &str) -> String {
+    match html.rfind("</body>") {
+        Some(i) => format!("{}{}{}", &html[..i], fragment, &html[i..]),
+        None => format!("{}{}", html, fragment),
+    }
+}
+
+#[tracing::instrument(
+    name = "Delivering newsletter to confirmed subscribers",
+    skip_all,
+    fields(issue_id = %issue.id)
+)]
+pub async fn send_to_confirmed_subscribers(
+    issue: &Issue,
+    email: &RenderedEmail,
+    links: &TrackingLinks,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<()> {
+    let subscribers = get_confirmed_subscribers(pool)
+        .await
+        .context("Failed to get confirmed subscribers.")?;
+    send_to_subscribers(issue, email, &subscribers, links, pool, email_client).await
+}
+
+/// Send the issue personalized for each of the subscribers, every delivery is
+/// recorded so opens and clicks can be tracked. Subscribers who already have
+/// a delivery of the issue, from an earlier try, are skipped.
+pub async fn send_to_subscribers(
+    issue: &Issue,
+    email: &RenderedEmail,
+    subscribers: &[Subscriber],
+    links: &TrackingLinks,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<()> {
+    let link_ids = if issue.track_clicks {
+        register_links(issue, email, pool).await?
+    } else {
+        HashMap::new()
+    };
+    let delivered: HashSet<Uuid> = tracking::get_recipient_subscribers(issue.id, pool)
+        .await
+        .context("Failed to get recipients of the issue.")?
+        .into_iter()
+        .collect();
+    let subscribers: Vec<&Subscriber> = subscribers
+        .iter()
+        .filter(|s| !delivered.contains(&s.id))
+        .collect();
+
+    for chunk in subscribers.chunks(MAX_BATCH_SIZE) {
+        let mut recipient_ids = Vec::with_capacity(chunk.len());
+        let mut emails = Vec::with_capacity(chunk.len());
+        for subscriber in chunk {
+            let recipient_id = tracking::insert_recipient(issue.id, subscriber.id, pool)
+                .await
+                .context("Failed to record recipient.")?;
+            let mut email = email.personalize(&recipient_vars(subscriber));
+            if !subscriber.tracking_opt_out {
+                let click = |url: &str| link_ids.get(url).map(|id| links.click(recipient_id, *id));
+                email.html = rewrite_links(&email.html, true, click);
+                email.text = rewrite_links(&email.text, false, click);
+                if issue.track_opens {
+                    email.html = with_open_pixel(&email.html, &links.open_pixel(recipient_id));
+                }
+                if issue.track_opens || issue.track_clicks {
+                    email = with_opt_out_link(email, &links.opt_out(recipient_id));
+                }
+            }
+            recipient_ids.push(recipient_id);
+            emails.push(
+                EmailMessage::new(&subscriber.email, &email.subject, &email.html, &email.text)
+                    .sent_as(TemplateName::NewsletterLayout.identity())
+                    .tag("newsletter")
+                    .metadata("issue_id", &issue.id.to_string())
+                    .metadata("recipient_id", &recipient_id.to_string()),
+            );
+        }
+
+        let statuses = email_client
+            .send_many(&emails)
+            .await
+            .context("Failed to send newsletter.")?;
+        for (recipient_id, status) in recipient_ids.into_iter().zip(statuses) {
+            if let MessageStatus::Failed { error } = &status {
+                tracing::warn!("Failed to send newsletter to {}: {}", recipient_id, error);
+            }
+            tracking::record_result(recipient_id, &status, pool)
+                .await
+                .context("Failed to record result of sending.")?;
+        }
+        issues::touch(issue.id, pool)
+            .await
+            .context("Failed to note sending of the issue.")?;
+    }
+    Ok(())
+}
+
+/// Store links of the issue, only these are redirected when clicked.
+async fn register_links(
+    issue: &Issue,
+    email: &RenderedEmail,
+    pool: &PgPool,
+) -> anyhow::Result<HashMap<String, i32>> {
+    let mut urls = find_links(&email.html, true);
+    for url in find_links(&email.text, false) {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+
+    let mut link_ids = HashMap::new();
+    for url in urls {
+        let id = tracking::insert_link(issue.id, &url, pool)
+            .await
+            .context("Failed to store link.")?;
+        link_ids.insert(url, id);
+    }
+    Ok(link_ids)
+}
+
+/// Send the rendered issue to all confirmed subscribers.
+///
+/// Returns `false` without sending anything when the issue is no longer in
+/// the `from` status, e.g. it was already picked by someone else.
+#[tracing::instrument(
+    name = "Sending newsletter issue",
+    skip(issue, email, pool, email_client),
+    fields(issue_id = %issue.id)
+)]
+pub async fn send_issue(
+    issue: &Issue,
+    from: IssueStatus,
+    email: &RenderedEmail,
+    links: &TrackingLinks,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<bool> {
+    if !issues::transition(issue.id, from, IssueStatus::Sending, pool)
+        .await
+        .context("Failed to start sending of the issue.")?
+    {
+        return Ok(false);
+    }
+
+    deliver_issue(issue, email, links, pool, email_client).await?;
+    Ok(true)
+}
+
+/// Send an issue which is already in the sending status and mark it as sent,
+/// or as failed when sending stops on an error.
+pub async fn deliver_issue(
+    issue: &Issue,
+    email: &RenderedEmail,
+    links: &TrackingLinks,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<()> {
+    if let Err(e) = send_to_confirmed_subscribers(issue, email, links, pool, email_client).await {
+        issues::fail(issue.id, &format!("{:#}", e), pool)
+            .await
+            .context("Failed to mark the issue as failed.")?;
+        return Err(e);
+    }
+
+    issues::transition(issue.id, IssueStatus::Sending, IssueStatus::Sent, pool)
+        .await
+        .context("Failed to mark the issue as sent.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_is_put_at_the_end_of_body() {
+        assert_eq!(
+            with_open_pixel("<html><body><p>Hi</p></body></html>", "http://pixel"),
+            "<html><body><p>Hi</p><img src=\"http://pixel\" width=\"1\" height=\"1\" alt=\"\" style=\"display:none\"></body></html>"
+        );
+        assert!(with_open_pixel("<p>Hi</p>", "http://pixel").starts_with("<p>Hi</p><img"));
+    }
+
+    #[test]
+    fn opt_out_link_is_put_into_both_parts() {
+        let email = RenderedEmail {
+            subject: "News".into(),
+            html: "<html><body><p>Hi</p></body></html>".into(),
+            text: "Hi".into(),
+        };
+
+        let email = with_opt_out_link(email, "http://opt-out");
+
+        assert!(email.html.ends_with(
+            "<a href=\"http://opt-out\">Stop tracking of opens and clicks</a></p></body></html>"
+        ));
+        assert_eq!(
+            email.text,
+            "Hi\n\nStop tracking of opens and clicks: http://opt-out"
+        );
+    }
+}
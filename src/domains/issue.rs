@@ -0,0 +1,221 @@
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use uuid::Uuid;
+use validator::{Validate, ValidationErrors};
+
+use crate::domains::{
+    newsletter::{is_valid_content, Content},
+    template::{RenderedEmail, Template},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueStatus {
+    Draft,
+    Scheduled,
+    Sending,
+    Sent,
+    /// Sending stopped on an error, the issue can be sent again.
+    Failed,
+}
+
+impl IssueStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueStatus::Draft => "draft",
+            IssueStatus::Scheduled => "scheduled",
+            IssueStatus::Sending => "sending",
+            IssueStatus::Sent => "sent",
+            IssueStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "draft" => Some(IssueStatus::Draft),
+            "scheduled" => Some(IssueStatus::Scheduled),
+            "sending" => Some(IssueStatus::Sending),
+            "sent" => Some(IssueStatus::Sent),
+            "failed" => Some(IssueStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Issue of the newsletter, it can be edited only while it is a draft.
+#[derive(Debug, Clone, serde::Serialize, Validate)]
+pub struct Issue {
+    pub id: Uuid,
+    #[validate(length(min = 1, max = 998))]
+    pub title: String,
+    #[validate(required, custom = "is_valid_content")]
+    pub content: Option<Content>,
+    pub status: IssueStatus,
+    /// Add an open tracking pixel into the html part.
+    pub track_opens: bool,
+    /// Replace links by redirects through the application.
+    pub track_clicks: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// When a scheduled issue is going to be sent, for local time delivery
+    /// the first moment any subscriber can get it.
+    pub send_at: Option<DateTime<Utc>>,
+    /// Local time of subscribers at which they get the issue.
+    pub local_send_at: Option<NaiveDateTime>,
+    pub sent_at: Option<DateTime<Utc>>,
+    /// Error which stopped sending of a failed issue.
+    pub failure: Option<String>,
+}
+
+/// Editable part of an issue, a draft does not need to be complete.
+#[derive(Debug, serde::Deserialize)]
+pub struct IssueDraft {
+    #[serde(default)]
+    pub title: String,
+    pub content: Option<Content>,
+    #[serde(default)]
+    pub track_opens: bool,
+    #[serde(default)]
+    pub track_clicks: bool,
+}
+
+impl Issue {
+    /// Render the issue, recipient placeholders are left to be personalized.
+    /// Fails when the issue is not complete.
+    pub fn render(&self, layout: &Template) -> Result<RenderedEmail, ValidationErrors> {
+        self.validate()?;
+        let content = self
+            .content
+            .as_ref()
+            .expect("Validated issue without content");
+        Ok(content.render(&self.title, layout))
+    }
+}
+
+/// Largest UTC offset in use, places with it reach any local time first.
+const MAX_UTC_OFFSET_HOURS: i64 = 14;
+
+/// First moment when it is `local` time somewhere in the world.
+pub fn earliest_send_at(local: NaiveDateTime) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&(local - Duration::hours(MAX_UTC_OFFSET_HOURS)))
+}
+
+/// Moment when it is `local` time in the timezone. A local time skipped by
+/// a daylight saving change is moved by an hour.
+pub fn local_send_at_in(local: NaiveDateTime, timezone: Tz) -> DateTime<Utc> {
+    timezone
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .expect("Local time skipped by more than an hour")
+        .with_timezone(&Utc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domains::template::TemplateName;
+
+    fn issue(title: &str, content: Option<Content>) -> Issue {
+        Issue {
+            id: Uuid::new_v4(),
+            title: title.into(),
+            content,
+            status: IssueStatus::Draft,
+            track_opens: false,
+            track_clicks: false,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            send_at: None,
+            local_send_at: None,
+            sent_at: None,
+            failure: None,
+        }
+    }
+
+    #[test]
+    fn complete_issue_is_rendered_for_recipient() {
+        let issue = issue(
+            "Hello {{name}}",
+            Some(Content::Explicit {
+                html: "<p>Hi {{name}}</p>".into(),
+                text: "Hi {{name}}".into(),
+            }),
+        );
+        let layout = Template::default_for(TemplateName::NewsletterLayout);
+        let email = issue
+            .render(&layout)
+            .unwrap()
+            .personalize(&[("name", "Tom")]);
+        assert_eq!(email.subject, "Hello Tom");
+        assert_eq!(email.html, "<p>Hi Tom</p>");
+        assert_eq!(email.text, "Hi Tom");
+    }
+
+    #[test]
+    fn issue_without_content_is_invalid() {
+        let layout = Template::default_for(TemplateName::NewsletterLayout);
+        match issue("title", None).render(&layout) {
+            Ok(_) => panic!("Issue without content rendered"),
+            Err(e) => assert!(ValidationErrors::has_error(&Err(e), "content")),
+        }
+    }
+
+    #[test]
+    fn issue_with_empty_parts_is_invalid() {
+        let issue = issue(
+            "",
+            Some(Content::Markdown {
+                markdown: "  ".into(),
+            }),
+        );
+        match issue.validate() {
+            Ok(_) => panic!("Empty issue is valid"),
+            Err(e) => {
+                assert!(ValidationErrors::has_error(&Err(e.clone()), "content"));
+                assert!(ValidationErrors::has_error(&Err(e), "title"));
+            }
+        }
+    }
+
+    #[test]
+    fn status_is_parsed() {
+        for status in [
+            IssueStatus::Draft,
+            IssueStatus::Scheduled,
+            IssueStatus::Sending,
+            IssueStatus::Sent,
+            IssueStatus::Failed,
+        ] {
+            assert_eq!(IssueStatus::parse(status.as_str()), Some(status));
+        }
+        assert_eq!(IssueStatus::parse("unknown"), None);
+    }
+
+    #[test]
+    fn local_time_is_reached_per_timezone() {
+        let local = NaiveDateTime::parse_from_str("2023-07-10 08:00", "%Y-%m-%d %H:%M").unwrap();
+        assert_eq!(
+            local_send_at_in(local, chrono_tz::Europe::Prague).to_rfc3339(),
+            "2023-07-10T06:00:00+00:00"
+        );
+        assert_eq!(
+            local_send_at_in(local, chrono_tz::America::New_York).to_rfc3339(),
+            "2023-07-10T12:00:00+00:00"
+        );
+        assert!(earliest_send_at(local) <= local_send_at_in(local, chrono_tz::Pacific::Kiritimati));
+    }
+
+    #[test]
+    fn skipped_local_time_is_moved() {
+        let local = NaiveDateTime::parse_from_str("2023-03-26 02:30", "%Y-%m-%d %H:%M").unwrap();
+        assert_eq!(
+            local_send_at_in(local, chrono_tz::Europe::Prague).to_rfc3339(),
+            "2023-03-26T01:30:00+00:00"
+        );
+    }
+}
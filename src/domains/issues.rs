@@ -0,0 +1,701 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::domains::{
+    issue::{Issue, IssueDraft, IssueStatus},
+    newsletter::Content,
+};
+
+struct IssueRow {
+    id: Uuid,
+    title: String,
+    html_content: Option<String>,
+    text_content: Option<String>,
+    markdown_content: Option<String>,
+    status: String,
+    track_opens: bool,
+    track_clicks: bool,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    sent_at: Option<DateTime<Utc>>,
+    send_at: Option<DateTime<Utc>>,
+    local_send_at: Option<NaiveDateTime>,
+    failure: Option<String>,
+}
+
+impl From<IssueRow> for Issue {
+    fn from(row: IssueRow) -> Self {
+        let content = match (row.html_content, row.text_content, row.markdown_content) {
+            (_, _, Some(markdown)) => Some(Content::Markdown { markdown }),
+            (Some(html), Some(text), None) => Some(Content::Explicit { html, text }),
+            _ => None,
+        };
+        Issue {
+            id: row.id,
+            title: row.title,
+            content,
+            status: IssueStatus::parse(&row.status).expect("Unknown issue status"),
+            track_opens: row.track_opens,
+            track_clicks: row.track_clicks,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            send_at: row.send_at,
+            local_send_at: row.local_send_at,
+            sent_at: row.sent_at,
+            failure: row.failure,
+        }
+    }
+}
+
+fn content_columns(content: &Option<Content>) -> (Option<&str>, Option<&str>, Option<&str>) {
+    match content {
+        Some(Content::Explicit { html, text }) => (Some(html), Some(text), None),
+        Some(Content::Markdown { markdown }) => (None, None, Some(markdown)),
+        None => (None, None, None),
+    }
+}
+
+#[tracing::instrument(name = "Saving a new issue draft", skip(pool))]
+pub async fn insert_draft(draft: &IssueDraft, pool: &PgPool) -> sqlx::Result<Issue> {
+    let (html, text, markdown) = content_columns(&draft.content);
+    sqlx::query_as!(
+        IssueRow,
+        r#"
+    INSERT INTO newsletter_issues
+      (id, title, html_content, text_content, markdown_content, track_opens, track_clicks,
+       status, created_at, updated_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, 'draft', $8, $8)
+    RETURNING *
+            "#,
+        Uuid::new_v4(),
+        draft.title,
+        html,
+        text,
+        markdown,
+        draft.track_opens,
+        draft.track_clicks,
+        Utc::now()
+    )
+    .fetch_one(pool)
+    .await
+    .map(Issue::from)
+    .map_err(|e| {
+        tracing::error!("Error from saving new issue {:?}", e);
+        e
+    })
+}
+
+/// Update the issue only when it is still a draft.
+#[tracing::instrument(name = "Updating an issue draft", skip(pool))]
+pub async fn update_draft(
+    id: Uuid,
+    draft: &IssueDraft,
+    pool: &PgPool,
+) -> sqlx::Result<Option<Issue>> {
+    let (html, text, markdown) = content_columns(&draft.content);
+    let res = sqlx::query_as!(
+        IssueRow,
+        r#"
+        update newsletter_issues
+        set title = $2, html_content = $3, text_content = $4, markdown_content = $5,
+            track_opens = $6, track_clicks = $7, updated_at = $8
+        where id = $1 and status = 'draft'
+        returning *
+        "#,
+        id,
+        draft.title,
+        html,
+        text,
+        markdown,
+        draft.track_opens,
+        draft.track_clicks,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(Issue::from);
+    Ok(res)
+}
+
+/// Delete the issue only when it is still a draft.
+pub async fn delete_draft(id: Uuid, pool: &PgPool) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        "delete from newsletter_issues where id = $1 and status = 'draft'",
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+pub async fn get(id: Uuid, pool: &PgPool) -> sqlx::Result<Option<Issue>> {
+    let res = sqlx::query_as!(
+        IssueRow,
+        "select * from newsletter_issues where id = $1",
+        id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(Issue::from);
+    Ok(res)
+}
+
+pub async fn get_all(pool: &PgPool) -> sqlx::Result<Vec<Issue>> {
+    let res = sqlx::query_as!(
+        IssueRow,
+        "select * from newsletter_issues order by created_at desc"
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(Issue::from)
+    .collect();
+    Ok(res)
+}
+
+/// Move the issue from one status to another.
+///
+/// Returns `false` when the issue is not in the `from` status anymore, so only
+/// one caller can win the transition.
+#[tracing::instrument(name = "Changing issue status", skip(pool))]
+pub async fn transition(
+    id: Uuid,
+    from: IssueStatus,
+    to: IssueStatus,
+    pool: &PgPool,
+) -> sqlx::Result<bool> {
+    let now = Utc::now();
+    let sent_at = (to == IssueStatus::Sent).then_some(now);
+    let res = sqlx::query!(
+        r#"
+        update newsletter_issues
+        set status = $3, updated_at = $4, sent_at = coalesce($5, sent_at)
+        where id = $1 and status = $2
+        "#,
+        id,
+        from.as_str(),
+        to.as_str(),
+        now,
+        sent_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// Mark the issue being sent as failed with the error which stopped it.
+#[tracing::instrument(name = "Failing issue", skip(pool))]
+pub async fn fail(id: Uuid, failure: &str, pool: &PgPool) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"
+        update newsletter_issues set status = 'failed', failure = $2, updated_at = $3
+        where id = $1 and status = 'sending'
+        "#,
+        id,
+        failure,
+        Utc::now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// Schedule a draft, or reschedule an issue which is not being sent yet or
+/// whose sending failed.
+///
+/// With `local_send_at` the issue is delivered at that local time of each
+/// subscriber, `send_at` is then the earliest of those moments. Deliveries
+/// which were not sent before are planned again.
+#[tracing::instrument(name = "Scheduling an issue", skip(pool))]
+pub async fn schedule(
+    id: Uuid,
+    send_at: DateTime<Utc>,
+    local_send_at: Option<NaiveDateTime>,
+    pool: &PgPool,
+) -> sqlx::Result<Option<Issue>> {
+    let mut transaction = pool.begin().await?;
+    let res = sqlx::query_as!(
+        IssueRow,
+        r#"
+        update newsletter_issues
+        set status = 'scheduled', send_at = $2, local_send_at = $3, updated_at = $4,
+            failure = null
+        where id = $1 and status in ('draft', 'scheduled', 'failed')
+        returning *
+        "#,
+        id,
+        send_at,
+        local_send_at,
+        Utc::now()
+    )
+    .fetch_optional(&mut transaction)
+    .await?
+    .map(Issue::from);
+    if res.is_some() {
+        sqlx::query!(
+            "delete from newsletter_issue_deliveries where issue_id = $1 and status != 'sent'",
+            id
+        )
+        .execute(&mut transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(res)
+}
+
+/// Turn a scheduled issue back into a draft.
+#[tracing::instrument(name = "Cancelling scheduled issue", skip(pool))]
+pub async fn cancel_schedule(id: Uuid, pool: &PgPool) -> sqlx::Result<Option<Issue>> {
+    let res = sqlx::query_as!(
+        IssueRow,
+        r#"
+        update newsletter_issues
+        set status = 'draft', send_at = null, local_send_at = null, updated_at = $2
+        where id = $1 and status = 'scheduled'
+        returning *
+        "#,
+        id,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(Issue::from);
+    Ok(res)
+}
+
+/// Move one scheduled issue which is due at `now` into sending.
+///
+/// Concurrent callers, e.g. several replicas of the server, never get the same
+/// issue: the row is locked and skipped by the others until its status changes.
+pub async fn claim_due(now: DateTime<Utc>, pool: &PgPool) -> sqlx::Result<Option<Issue>> {
+    let res = sqlx::query_as!(
+        IssueRow,
+        r#"
+        update newsletter_issues
+        set status = 'sending', updated_at = $2
+        where id = (
+            select id from newsletter_issues
+            where status = 'scheduled' and send_at <= $1
+            order by send_at
+            limit 1
+            for update skip locked
+        )
+        returning *
+        "#,
+        now,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(Issue::from);
+    Ok(res)
+}
+
+/// Part of a local time delivery of an issue, subscribers of one timezone.
+#[derive(Debug, PartialEq)]
+pub struct Delivery {
+    pub issue_id: Uuid,
+    pub timezone: String,
+}
+
+/// Plan the delivery of an issue for each timezone and its send time.
+#[tracing::instrument(name = "Planning issue deliveries", skip(pool))]
+pub async fn insert_deliveries(
+    issue_id: Uuid,
+    deliveries: &[(String, DateTime<Utc>)],
+    pool: &PgPool,
+) -> sqlx::Result<()> {
+    let (timezones, send_ats): (Vec<String>, Vec<DateTime<Utc>>) =
+        deliveries.iter().cloned().unzip();
+    sqlx::query!(
+        r#"
+        insert into newsletter_issue_deliveries (issue_id, timezone, send_at, status)
+        select $1, timezone, send_at, 'pending'
+        from unnest($2::text[], $3::timestamptz[]) as d(timezone, send_at)
+        on conflict do nothing
+        "#,
+        issue_id,
+        &timezones,
+        &send_ats
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Move one due delivery of an issue being sent into sending, concurrent
+/// callers never get the same. Claiming counts as activity of the issue.
+pub async fn claim_due_delivery(
+    now: DateTime<Utc>,
+    pool: &PgPool,
+) -> sqlx::Result<Option<Delivery>> {
+    sqlx::query_as!(
+        Delivery,
+        r#"
+        with claimed as (
+            update newsletter_issue_deliveries
+            set status = 'sending'
+            where (issue_id, timezone) = (
+                select d.issue_id, d.timezone from newsletter_issue_deliveries d
+                join newsletter_issues i on i.id = d.issue_id
+                where d.status = 'pending' and d.send_at <= $1 and i.status = 'sending'
+                order by d.send_at
+                limit 1
+                for update of d skip locked
+            )
+            returning issue_id, timezone
+        ), touched as (
+            update newsletter_issues set updated_at = $2
+            where id in (select issue_id from claimed)
+        )
+        select issue_id as "issue_id!", timezone as "timezone!" from claimed
+        "#,
+        now,
+        Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Note that sending of the issue is still going on, issues which are sending
+/// without any activity are considered stuck and are reclaimed.
+pub async fn touch(id: Uuid, pool: &PgPool) -> sqlx::Result<()> {
+    sqlx::query!(
+        "update newsletter_issues set updated_at = $2 where id = $1 and status = 'sending'",
+        id,
+        Utc::now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Send again issues stuck in sending since `stale_before`, e.g. after the
+/// server stopped in the middle of sending them. Their deliveries being sent
+/// become pending, issues without deliveries are scheduled again for `now`
+/// at the latest.
+///
+/// Returns how many issues and deliveries were reclaimed.
+#[tracing::instrument(name = "Reclaiming stale issues", skip(pool))]
+pub async fn reclaim_stale(
+    stale_before: DateTime<Utc>,
+    now: DateTime<Utc>,
+    pool: &PgPool,
+) -> sqlx::Result<u64> {
+    let mut transaction = pool.begin().await?;
+    let deliveries = sqlx::query!(
+        r#"
+        update newsletter_issue_deliveries set status = 'pending'
+        where status = 'sending' and issue_id in (
+            select id from newsletter_issues where status = 'sending' and updated_at < $1
+        )
+        "#,
+        stale_before
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    let issues = sqlx::query!(
+        r#"
+        update newsletter_issues
+        set status = 'scheduled', send_at = coalesce(send_at, $2), updated_at = $3
+        where status = 'sending' and updated_at < $1 and not exists (
+            select 1 from newsletter_issue_deliveries where issue_id = newsletter_issues.id
+        )
+        "#,
+        stale_before,
+        now,
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await?
+    .rows_affected();
+    transaction.commit().await?;
+    Ok(deliveries + issues)
+}
+
+/// Mark the delivery as sent, returns `true` when it was the last one of the
+/// issue.
+pub async fn finish_delivery(delivery: &Delivery, pool: &PgPool) -> sqlx::Result<bool> {
+    sqlx::query!(
+        r#"
+        update newsletter_issue_deliveries set status = 'sent'
+        where issue_id = $1 and timezone = $2
+        "#,
+        delivery.issue_id,
+        delivery.timezone
+    )
+    .execute(pool)
+    .await?;
+    let remaining = sqlx::query!(
+        r#"
+        select count(*) as "count!" from newsletter_issue_deliveries
+        where issue_id = $1 and status != 'sent'
+        "#,
+        delivery.issue_id
+    )
+    .fetch_one(pool)
+    .await?
+    .count;
+    Ok(remaining == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draft(title: &str) -> IssueDraft {
+        IssueDraft {
+            title: title.into(),
+            content: Some(Content::Markdown {
+                markdown: "Hello".into(),
+            }),
+            track_opens: false,
+            track_clicks: false,
+        }
+    }
+
+    #[sqlx::test]
+    fn saving_and_updating_draft(pool: PgPool) {
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        assert_eq!(issue.status, IssueStatus::Draft);
+
+        let updated = update_draft(issue.id, &draft("second"), &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.title, "second");
+        assert_eq!(updated.content, draft("second").content);
+
+        let saved = get(issue.id, &pool).await.unwrap().unwrap();
+        assert_eq!(saved.title, "second");
+    }
+
+    #[sqlx::test]
+    fn only_drafts_are_editable(pool: PgPool) {
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        assert!(
+            transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+                .await
+                .unwrap()
+        );
+
+        assert!(update_draft(issue.id, &draft("second"), &pool)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!delete_draft(issue.id, &pool).await.unwrap());
+    }
+
+    #[sqlx::test]
+    fn transition_happens_once(pool: PgPool) {
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        assert!(
+            transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+                .await
+                .unwrap()
+        );
+        assert!(
+            transition(issue.id, IssueStatus::Sending, IssueStatus::Sent, &pool)
+                .await
+                .unwrap()
+        );
+        let sent = get(issue.id, &pool).await.unwrap().unwrap();
+        assert_eq!(sent.status, IssueStatus::Sent);
+        assert!(sent.sent_at.is_some());
+    }
+
+    #[sqlx::test]
+    fn scheduled_issue_can_be_rescheduled_and_cancelled(pool: PgPool) {
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        let send_at = Utc::now() + chrono::Duration::hours(1);
+        let scheduled = schedule(issue.id, send_at, None, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(scheduled.status, IssueStatus::Scheduled);
+
+        let later = send_at + chrono::Duration::hours(1);
+        let rescheduled = schedule(issue.id, later, None, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rescheduled.send_at.unwrap() > send_at);
+
+        let cancelled = cancel_schedule(issue.id, &pool).await.unwrap().unwrap();
+        assert_eq!(cancelled.status, IssueStatus::Draft);
+        assert_eq!(cancelled.send_at, None);
+        assert!(cancel_schedule(issue.id, &pool).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    fn due_issue_is_claimed_once(pool: PgPool) {
+        let now = Utc::now();
+        let due = insert_draft(&draft("due"), &pool).await.unwrap();
+        schedule(due.id, now - chrono::Duration::minutes(1), None, &pool)
+            .await
+            .unwrap();
+        let future = insert_draft(&draft("future"), &pool).await.unwrap();
+        schedule(future.id, now + chrono::Duration::minutes(1), None, &pool)
+            .await
+            .unwrap();
+
+        let (first, second) = tokio::join!(claim_due(now, &pool), claim_due(now, &pool));
+        let claimed: Vec<Issue> = [first.unwrap(), second.unwrap()]
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, due.id);
+        assert_eq!(claimed[0].status, IssueStatus::Sending);
+
+        assert!(schedule(due.id, now, None, &pool).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    fn failed_issue_can_be_scheduled_again(pool: PgPool) {
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        assert!(!fail(issue.id, "not sending", &pool).await.unwrap());
+        transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+            .await
+            .unwrap();
+
+        assert!(fail(issue.id, "provider is down", &pool).await.unwrap());
+        let failed = get(issue.id, &pool).await.unwrap().unwrap();
+        assert_eq!(failed.status, IssueStatus::Failed);
+        assert_eq!(failed.failure.as_deref(), Some("provider is down"));
+        assert!(update_draft(issue.id, &draft("second"), &pool)
+            .await
+            .unwrap()
+            .is_none());
+
+        let scheduled = schedule(issue.id, Utc::now(), None, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(scheduled.status, IssueStatus::Scheduled);
+        assert_eq!(scheduled.failure, None);
+    }
+
+    #[sqlx::test]
+    fn deliveries_are_claimed_when_due(pool: PgPool) {
+        let now = Utc::now();
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+            .await
+            .unwrap();
+        insert_deliveries(
+            issue.id,
+            &[
+                ("Europe/Prague".into(), now - chrono::Duration::minutes(1)),
+                ("America/New_York".into(), now + chrono::Duration::hours(6)),
+            ],
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let delivery = claim_due_delivery(now, &pool).await.unwrap().unwrap();
+        assert_eq!(delivery.timezone, "Europe/Prague");
+        assert!(claim_due_delivery(now, &pool).await.unwrap().is_none());
+        assert!(!finish_delivery(&delivery, &pool).await.unwrap());
+
+        let later = now + chrono::Duration::hours(6);
+        let delivery = claim_due_delivery(later, &pool).await.unwrap().unwrap();
+        assert_eq!(delivery.timezone, "America/New_York");
+        assert!(finish_delivery(&delivery, &pool).await.unwrap());
+    }
+
+    #[sqlx::test]
+    fn deliveries_of_failed_issue_are_not_claimed(pool: PgPool) {
+        let now = Utc::now();
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+            .await
+            .unwrap();
+        insert_deliveries(issue.id, &[("Europe/Prague".into(), now)], &pool)
+            .await
+            .unwrap();
+        fail(issue.id, "provider is down", &pool).await.unwrap();
+
+        assert!(claim_due_delivery(now, &pool).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    fn deliveries_of_issue_being_sent_survive_scheduling(pool: PgPool) {
+        let now = Utc::now();
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+            .await
+            .unwrap();
+        insert_deliveries(issue.id, &[("Europe/Prague".into(), now)], &pool)
+            .await
+            .unwrap();
+
+        assert!(schedule(issue.id, now, None, &pool)
+            .await
+            .unwrap()
+            .is_none());
+        let delivery = claim_due_delivery(now, &pool).await.unwrap().unwrap();
+        assert_eq!(delivery.timezone, "Europe/Prague");
+    }
+
+    async fn make_stale(pool: &PgPool) {
+        sqlx::query!("update newsletter_issues set updated_at = updated_at - interval '1 hour'")
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    fn stuck_issues_are_reclaimed(pool: PgPool) {
+        let now = Utc::now();
+        let stale_before = now - chrono::Duration::minutes(15);
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        schedule(issue.id, now, None, &pool).await.unwrap();
+        let issue = claim_due(now, &pool).await.unwrap().unwrap();
+        assert_eq!(reclaim_stale(stale_before, now, &pool).await.unwrap(), 0);
+
+        make_stale(&pool).await;
+        assert_eq!(reclaim_stale(stale_before, now, &pool).await.unwrap(), 1);
+        let reclaimed = claim_due(now, &pool).await.unwrap().unwrap();
+        assert_eq!(reclaimed.id, issue.id);
+    }
+
+    #[sqlx::test]
+    fn stuck_deliveries_are_reclaimed(pool: PgPool) {
+        let now = Utc::now();
+        let stale_before = now - chrono::Duration::minutes(15);
+        let issue = insert_draft(&draft("first"), &pool).await.unwrap();
+        transition(issue.id, IssueStatus::Draft, IssueStatus::Sending, &pool)
+            .await
+            .unwrap();
+        insert_deliveries(
+            issue.id,
+            &[
+                ("Europe/Prague".into(), now),
+                ("America/New_York".into(), now + chrono::Duration::hours(6)),
+            ],
+            &pool,
+        )
+        .await
+        .unwrap();
+        claim_due_delivery(now, &pool).await.unwrap().unwrap();
+
+        make_stale(&pool).await;
+        // Only the delivery being sent, the issue waits for the other one.
+        assert_eq!(reclaim_stale(stale_before, now, &pool).await.unwrap(), 1);
+        let delivery = claim_due_delivery(now, &pool).await.unwrap().unwrap();
+        assert_eq!(delivery.timezone, "Europe/Prague");
+        assert_eq!(
+            get(issue.id, &pool).await.unwrap().unwrap().status,
+            IssueStatus::Sending
+        );
+    }
+}
@@ -1,3 +1,13 @@
+pub mod issue;
+pub mod issues;
+pub mod newsletter;
+pub mod queued_emails;
 pub mod subscriber;
+pub mod subscriber_email;
+pub mod subscriber_name;
 pub mod subscribers;
+pub mod suppressions;
+pub mod template;
+pub mod templates;
+pub mod tracking;
 pub mod users;
@@ -0,0 +1,203 @@
+use pulldown_cmark::{Event, Parser, Tag};
+use validator::ValidationError;
+
+use crate::domains::template::{RenderedEmail, Template};
+
+/// Newsletter body as sent by the caller.
+///
+/// Either both parts are written by hand, or a single markdown source is given
+/// and the html/text parts are generated from it.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Content {
+    Explicit { html: String, text: String },
+    Markdown { markdown: String },
+}
+
+impl Content {
+    /// Produce the subject, html and text parts of the email.
+    ///
+    /// `layout` is used only for markdown content, it gets the newsletter
+    /// `title` and the rendered `content`.
+    pub fn render(&self, title: &str, layout: &Template) -> RenderedEmail {
+        match self {
+            Content::Explicit { html, text } => RenderedEmail {
+                subject: title.into(),
+                html: html.clone(),
+                text: text.clone(),
+            },
+            Content::Markdown { markdown } => {
+                let html =
+                    layout.render(&[("title", title), ("content", &markdown_to_html(markdown))]);
+                let text =
+                    layout.render(&[("title", title), ("content", &markdown_to_text(markdown))]);
+                RenderedEmail {
+                    subject: html.subject,
+                    html: html.html,
+                    text: text.text,
+                }
+            }
+        }
+    }
+}
+
+pub fn is_valid_content(content: &Content) -> Result<(), ValidationError> {
+    let is_empty = match content {
+        Content::Explicit { html, text } => html.trim().is_empty() || text.trim().is_empty(),
+        Content::Markdown { markdown } => markdown.trim().is_empty(),
+    };
+    if is_empty {
+        return Err(ValidationError::new("empty"));
+    }
+
+    Ok(())
+}
+
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new(markdown));
+    ammonia::clean(&html)
+}
+
+/// Render markdown as plain text, links are replaced by numbered references
+/// which are listed at the end of the text.
+pub fn markdown_to_text(markdown: &str) -> String {
+    let mut text = String::new();
+    let mut links: Vec<String> = Vec::new();
+    let mut lists: Vec<Option<u64>> = Vec::new();
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Item) => {
+                let indent = "  ".repeat(lists.len().saturating_sub(1));
+                match lists.last_mut() {
+                    Some(Some(n)) => {
+                        text.push_str(&format!("{}{}. ", indent, n));
+                        *n += 1;
+                    }
+                    _ => text.push_str(&format!("{}- ", indent)),
+                }
+            }
+            Event::Start(Tag::List(start)) => {
+                if !lists.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                lists.push(start);
+            }
+            Event::End(Tag::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    text.push('\n');
+                }
+            }
+            Event::End(Tag::Item) if !text.ends_with('\n') => text.push('\n'),
+            Event::End(Tag::Paragraph) => {
+                text.push('\n');
+                if lists.is_empty() {
+                    text.push('\n');
+                }
+            }
+            Event::End(Tag::Heading(..)) | Event::End(Tag::CodeBlock(_)) => text.push_str("\n\n"),
+            Event::End(Tag::Link(_, url, _)) | Event::End(Tag::Image(_, url, _)) => {
+                let url = url.to_string();
+                let n = match links.iter().position(|l| *l == url) {
+                    Some(i) => i + 1,
+                    None => {
+                        links.push(url);
+                        links.len()
+                    }
+                };
+                text.push_str(&format!(" [{}]", n));
+            }
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak => text.push(' '),
+            Event::HardBreak => text.push('\n'),
+            Event::Rule => text.push_str("----\n\n"),
+            _ => {}
+        }
+    }
+
+    let mut text = text.trim_end().to_string();
+    if !links.is_empty() {
+        text.push_str("\n\nLinks:");
+        for (i, link) in links.iter().enumerate() {
+            text.push_str(&format!("\n[{}] {}", i + 1, link));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domains::template::TemplateName;
+
+    #[test]
+    fn explicit_content_is_kept() {
+        let content = Content::Explicit {
+            html: "<p>html</p>".into(),
+            text: "text".into(),
+        };
+        assert_eq!(
+            content.render(
+                "title",
+                &Template::default_for(TemplateName::NewsletterLayout)
+            ),
+            RenderedEmail {
+                subject: "title".into(),
+                html: "<p>html</p>".into(),
+                text: "text".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn markdown_is_rendered_into_layout() {
+        let content = Content::Markdown {
+            markdown: "# Hello\n\nSome *text*".into(),
+        };
+        let layout = Template {
+            html: "<h1>{{title}}</h1>{{{content}}}".into(),
+            text: "{{title}}\n\n{{{content}}}".into(),
+            ..Template::default_for(TemplateName::NewsletterLayout)
+        };
+        let rendered = content.render("<Title>", &layout);
+        assert_eq!(rendered.subject, "<Title>");
+        assert_eq!(
+            rendered.html,
+            "<h1>&lt;Title&gt;</h1><h1>Hello</h1>\n<p>Some <em>text</em></p>\n"
+        );
+        assert_eq!(rendered.text, "<Title>\n\nHello\n\nSome text");
+    }
+
+    #[test]
+    fn html_is_sanitized() {
+        let html = markdown_to_html("Hi <script>alert(1)</script><b onclick='x()'>there</b>");
+        assert!(!html.contains("script"));
+        assert!(!html.contains("onclick"));
+        assert!(html.contains("<b>there</b>"));
+    }
+
+    #[test]
+    fn links_are_listed_as_references() {
+        let text = markdown_to_text(
+            "Read [the blog](https://blog.com) or [docs](https://docs.com).\n\nAgain [blog](https://blog.com).",
+        );
+        assert_eq!(
+            text,
+            "Read the blog [1] or docs [2].\n\nAgain blog [1].\n\nLinks:\n[1] https://blog.com\n[2] https://docs.com"
+        );
+    }
+
+    #[test]
+    fn lists_are_rendered() {
+        let text = markdown_to_text("Items:\n\n- one\n- two\n\n1. first\n2. second");
+        assert_eq!(text, "Items:\n\n- one\n- two\n\n1. first\n2. second");
+    }
+
+    #[test]
+    fn unknown_content_is_rejected() {
+        let content = serde_json::from_str::<Content>(r#"{"html": "only html"}"#);
+        assert!(content.is_err());
+    }
+}
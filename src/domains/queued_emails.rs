@@ -0,0 +1,220 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::email_client::EmailMessage;
+
+/// Email waiting to be sent by the outbox worker.
+#[derive(Debug)]
+pub struct QueuedEmail {
+    pub id: Uuid,
+    pub message: EmailMessage,
+    /// Failed attempts to send it so far.
+    pub attempts: i32,
+}
+
+#[tracing::instrument(
+    name = "Queueing email",
+    skip(message, executor),
+    fields(recipient = %message.to())
+)]
+pub async fn enqueue(message: &EmailMessage, executor: impl PgExecutor<'_>) -> sqlx::Result<Uuid> {
+    let id = Uuid::new_v4();
+    let json = serde_json::to_string(message).expect("Email message is always serializable");
+    sqlx::query!(
+        r#"
+        insert into queued_emails (id, recipient, message, created_at)
+        values ($1, $2, $3::text::jsonb, $4)
+        "#,
+        id,
+        message.to().as_ref(),
+        json,
+        Utc::now()
+    )
+    .execute(executor)
+    .await?;
+    Ok(id)
+}
+
+/// Lock the oldest queued email due to be sent which nobody else is sending,
+/// it stays locked until the transaction ends.
+///
+/// Emails whose message cannot be read are marked as failed in the
+/// transaction and skipped, they would never be sent.
+pub async fn next(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> sqlx::Result<Option<QueuedEmail>> {
+    loop {
+        let Some(row) = sqlx::query!(
+            r#"
+            select id, message::text as "message!", attempts from queued_emails
+            where next_attempt_at <= now() and failed_at is null
+            order by created_at
+            for update skip locked
+            limit 1
+            "#
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        else {
+            return Ok(None);
+        };
+        match serde_json::from_str(&row.message) {
+            Ok(message) => {
+                return Ok(Some(QueuedEmail {
+                    id: row.id,
+                    message,
+                    attempts: row.attempts,
+                }))
+            }
+            Err(e) => {
+                tracing::error!("Queued email {} cannot be read {:?}", row.id, e);
+                fail(row.id, &e.to_string(), &mut *transaction).await?;
+            }
+        }
+    }
+}
+
+/// Give up the email, it is kept with the error but never tried again.
+pub async fn fail(id: Uuid, error: &str, executor: impl PgExecutor<'_>) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        update queued_emails
+        set attempts = attempts + 1, failed_at = now(), last_error = $2
+        where id = $1
+        "#,
+        id,
+        error
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Count a failed attempt and try again at `next_attempt_at`.
+pub async fn postpone(
+    id: Uuid,
+    next_attempt_at: DateTime<Utc>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        update queued_emails set attempts = attempts + 1, next_attempt_at = $2
+        where id = $1
+        "#,
+        id,
+        next_attempt_at
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+pub async fn remove(id: Uuid, transaction: &mut Transaction<'_, Postgres>) -> sqlx::Result<()> {
+    sqlx::query!("delete from queued_emails where id = $1", id)
+        .execute(transaction)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use chrono::{Duration, Utc};
+
+    use super::{enqueue, next, postpone, remove};
+    use crate::domains::subscriber_email::SubscriberEmail;
+    use crate::email_client::EmailMessage;
+    use uuid::Uuid;
+
+    fn email(to: &str) -> EmailMessage {
+        EmailMessage::new(
+            &SubscriberEmail::parse(to).unwrap(),
+            "Subject",
+            "<p>Hi</p>",
+            "Hi",
+        )
+    }
+
+    #[sqlx::test]
+    async fn locked_email_is_skipped(pool: PgPool) {
+        enqueue(&email("tom@gmail.com"), &pool).await.unwrap();
+        enqueue(&email("petr@gmail.com"), &pool).await.unwrap();
+
+        let mut first = pool.begin().await.unwrap();
+        let mut second = pool.begin().await.unwrap();
+        let a = next(&mut first).await.unwrap().unwrap();
+        let b = next(&mut second).await.unwrap().unwrap();
+        assert_eq!(a.message.to().as_ref(), "tom@gmail.com");
+        assert_eq!(b.message.to().as_ref(), "petr@gmail.com");
+
+        remove(a.id, &mut first).await.unwrap();
+        first.commit().await.unwrap();
+        // Not removed, so it is queued again.
+        second.rollback().await.unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        let rest = next(&mut transaction).await.unwrap().unwrap();
+        assert_eq!(rest.message.to().as_ref(), "petr@gmail.com");
+        remove(rest.id, &mut transaction).await.unwrap();
+        assert!(next(&mut transaction).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn postponed_email_waits(pool: PgPool) {
+        enqueue(&email("tom@gmail.com"), &pool).await.unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        let queued = next(&mut transaction).await.unwrap().unwrap();
+        assert_eq!(queued.attempts, 0);
+        assert_eq!(queued.message, email("tom@gmail.com"));
+        postpone(queued.id, Utc::now() + Duration::hours(1), &mut transaction)
+            .await
+            .unwrap();
+        transaction.commit().await.unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        assert!(next(&mut transaction).await.unwrap().is_none());
+        let attempts = sqlx::query!("select attempts from queued_emails")
+            .fetch_one(&mut transaction)
+            .await
+            .unwrap()
+            .attempts;
+        assert_eq!(attempts, 1);
+    }
+
+    #[sqlx::test]
+    async fn unreadable_email_is_failed_and_skipped(pool: PgPool) {
+        let id = Uuid::new_v4();
+        sqlx::query!(
+            r#"
+            insert into queued_emails (id, recipient, message, created_at)
+            values ($1, 'tom@gmail.com', '{"to": 42}', now() - interval '1 minute')
+            "#,
+            id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        enqueue(&email("petr@gmail.com"), &pool).await.unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        let queued = next(&mut transaction).await.unwrap().unwrap();
+        assert_eq!(queued.message.to().as_ref(), "petr@gmail.com");
+        remove(queued.id, &mut transaction).await.unwrap();
+        assert!(next(&mut transaction).await.unwrap().is_none());
+        transaction.commit().await.unwrap();
+
+        let failed = sqlx::query!(
+            "select attempts, failed_at, last_error from queued_emails where id = $1",
+            id
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(failed.attempts, 1);
+        assert!(failed.failed_at.is_some());
+        assert!(failed.last_error.is_some());
+    }
+}
@@ -1,62 +1,192 @@
-use derive_getters::Getters;
-use validator::{Validate, ValidationError, ValidationErrors};
+use std::fmt;
+use std::str::FromStr;
 
-fn is_valid_name(name: &str) -> Result<(), ValidationError> {
-    let forbidden_characters = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
-    if name.chars().any(|ref ch| forbidden_characters.contains(ch)) {
-        return Err(ValidationError::new("contains"));
-    };
+use chrono_tz::Tz;
+use derive_getters::Getters;
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+use sqlx::{Decode, Encode, Postgres, Type};
+use uuid::Uuid;
+use validator::{ValidationError, ValidationErrors};
 
-    Ok(())
-}
+use crate::domains::{subscriber_email::SubscriberEmail, subscriber_name::SubscriberName};
 
-#[derive(Debug, Validate, Getters)]
+#[derive(Debug, Getters)]
 pub struct NewSubscriber {
-    #[validate(length(max = 256, min = 1), custom = "is_valid_name")]
-    name: String,
-    #[validate(email)]
-    email: String,
+    name: SubscriberName,
+    email: SubscriberEmail,
+    timezone: Option<Tz>,
 }
 
 impl NewSubscriber {
     pub fn parse(name: &str, email: &str) -> Result<Self, ValidationErrors> {
-        let s = Self {
-            name: name.to_string(),
-            email: email.to_string(),
-        };
-        match s.validate() {
-            Ok(_) => Ok(s),
-            Err(e) => Err(e),
+        let mut errors = ValidationErrors::new();
+        let name = SubscriberName::parse(name).map_err(|e| errors.add("name", e));
+        let email = SubscriberEmail::parse(email).map_err(|e| errors.add("email", e));
+        match (name, email) {
+            (Ok(name), Ok(email)) => Ok(Self {
+                name,
+                email,
+                timezone: None,
+            }),
+            _ => Err(errors),
+        }
+    }
+
+    /// Set IANA timezone of the subscriber, an empty value leaves it unset.
+    pub fn with_timezone(mut self, timezone: &str) -> Result<Self, ValidationErrors> {
+        let timezone = timezone.trim();
+        if timezone.is_empty() {
+            return Ok(self);
+        }
+        match timezone.parse::<Tz>() {
+            Ok(tz) => {
+                self.timezone = Some(tz);
+                Ok(self)
+            }
+            Err(_) => {
+                let mut errors = ValidationErrors::new();
+                errors.add("timezone", ValidationError::new("timezone"));
+                Err(errors)
+            }
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use claims::assert_ok;
-    use fake::{faker::internet::en::SafeEmail, Fake};
-    use rand::SeedableRng;
-    use validator::ValidationErrors;
+/// Status of a subscription, stored as text the table checks.
+///
+/// A subscription starts pending, or confirmed when its consent was given
+/// elsewhere. Bounced and complained addresses never get any email again,
+/// pending subscriptions nobody confirmed in time expire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    Bounced,
+    Complained,
+    Expired,
+}
 
-    use crate::domains::subscriber::NewSubscriber;
+impl SubscriptionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::PendingConfirmation => "pending_confirmation",
+            SubscriptionStatus::Confirmed => "confirmed",
+            SubscriptionStatus::Unsubscribed => "unsubscribed",
+            SubscriptionStatus::Bounced => "bounced",
+            SubscriptionStatus::Complained => "complained",
+            SubscriptionStatus::Expired => "expired",
+        }
+    }
 
-    #[derive(Debug, Clone)]
-    struct ValidEmailFixture(pub String);
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending_confirmation" => Some(SubscriptionStatus::PendingConfirmation),
+            "confirmed" => Some(SubscriptionStatus::Confirmed),
+            "unsubscribed" => Some(SubscriptionStatus::Unsubscribed),
+            "bounced" => Some(SubscriptionStatus::Bounced),
+            "complained" => Some(SubscriptionStatus::Complained),
+            "expired" => Some(SubscriptionStatus::Expired),
+            _ => None,
+        }
+    }
 
-    impl quickcheck::Arbitrary for ValidEmailFixture {
-        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-            //let mut rng = rand::rngs::std::StdRng::seed_from_u64(u64::arbitrary(g));
-            let mut rng = rand::rngs::StdRng::seed_from_u64(u64::arbitrary(g));
+    /// Whether a subscription of this status may change to the other one.
+    pub fn can_become(&self, to: SubscriptionStatus) -> bool {
+        use SubscriptionStatus::*;
+        matches!(
+            (self, to),
+            (
+                PendingConfirmation,
+                Confirmed | Unsubscribed | Bounced | Complained | Expired
+            ) | (Confirmed, Unsubscribed | Bounced | Complained)
+                | (Unsubscribed, Confirmed | Bounced | Complained)
+        )
+    }
+}
 
-            Self(SafeEmail().fake_with_rng(&mut rng))
-        }
+impl fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SubscriptionStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).ok_or_else(|| format!("{} is not a subscription status", s))
+    }
+}
+
+impl Type<Postgres> for SubscriptionStatus {
+    fn type_info() -> PgTypeInfo {
+        <&str as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <&str as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for SubscriptionStatus {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <&str as Encode<Postgres>>::encode(self.as_str(), buf)
     }
+}
 
-    impl AsRef<str> for ValidEmailFixture {
-        fn as_ref(&self) -> &str {
-            &self.0
+impl<'r> Decode<'r, Postgres> for SubscriptionStatus {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let status = <&str as Decode<Postgres>>::decode(value)?;
+        Ok(status.parse()?)
+    }
+}
+
+/// Who or what changed the status of a subscription, kept in its history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangedBy {
+    /// The subscriber, by subscribing or by the confirmation link.
+    Subscriber,
+    /// Administrator of the given name.
+    Admin(String),
+    Import,
+    /// Bounce or complaint reported by the email provider.
+    EmailProvider,
+    /// Purge of pending subscriptions, see [`crate::purge`].
+    Purge,
+}
+
+impl fmt::Display for ChangedBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangedBy::Subscriber => f.write_str("subscriber"),
+            ChangedBy::Admin(name) => write!(f, "admin:{}", name),
+            ChangedBy::Import => f.write_str("import"),
+            ChangedBy::EmailProvider => f.write_str("email_provider"),
+            ChangedBy::Purge => f.write_str("purge"),
         }
     }
+}
+
+/// Stored subscription.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Subscriber {
+    pub id: Uuid,
+    pub email: SubscriberEmail,
+    pub name: SubscriberName,
+    /// Subscriber does not want opens of newsletters to be tracked.
+    pub tracking_opt_out: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::assert_ok;
+    use validator::ValidationErrors;
+
+    use crate::domains::subscriber::{NewSubscriber, SubscriptionStatus};
 
     #[test]
     fn valid_case() {
@@ -93,8 +223,68 @@ mod tests {
         }
     }
 
-    #[quickcheck_macros::quickcheck]
-    fn property_test_on_email(valid_email: ValidEmailFixture) -> bool {
-        NewSubscriber::parse("name", valid_email.as_ref()).is_ok()
+    #[test]
+    fn timezone_is_optional() {
+        let subscriber = NewSubscriber::parse("test", "test@test.com")
+            .unwrap()
+            .with_timezone("")
+            .unwrap();
+        assert_eq!(subscriber.timezone(), &None);
+
+        let subscriber = NewSubscriber::parse("test", "test@test.com")
+            .unwrap()
+            .with_timezone("Europe/Prague")
+            .unwrap();
+        assert_eq!(subscriber.timezone(), &Some(chrono_tz::Europe::Prague));
+    }
+
+    #[test]
+    fn unknown_timezone_is_rejected() {
+        match NewSubscriber::parse("test", "test@test.com")
+            .unwrap()
+            .with_timezone("Mars/Olympus")
+        {
+            Ok(_) => panic!("NewSubscriber parsing error"),
+            Err(e) => assert!(ValidationErrors::has_error(&Err(e), "timezone")),
+        }
+    }
+
+    #[test]
+    fn email_is_normalized() {
+        let subscriber = NewSubscriber::parse(" tom ", "Tom@GMAIL.com ").unwrap();
+        assert_eq!(subscriber.name().as_ref(), "tom");
+        assert_eq!(subscriber.email().as_ref(), "Tom@gmail.com");
+    }
+
+    #[test]
+    fn only_allowed_status_changes_are_possible() {
+        use SubscriptionStatus::*;
+
+        assert!(PendingConfirmation.can_become(Confirmed));
+        assert!(Confirmed.can_become(Unsubscribed));
+        assert!(Unsubscribed.can_become(Confirmed));
+        assert!(Confirmed.can_become(Bounced));
+        assert!(!Confirmed.can_become(Confirmed));
+        assert!(!Confirmed.can_become(PendingConfirmation));
+        assert!(!Bounced.can_become(Confirmed));
+        assert!(!Complained.can_become(Unsubscribed));
+        assert!(PendingConfirmation.can_become(Expired));
+        assert!(!Confirmed.can_become(Expired));
+        assert!(!Expired.can_become(Confirmed));
+    }
+
+    #[test]
+    fn status_is_stored_as_text() {
+        for status in [
+            "pending_confirmation",
+            "confirmed",
+            "unsubscribed",
+            "bounced",
+            "complained",
+            "expired",
+        ] {
+            assert_eq!(SubscriptionStatus::parse(status).unwrap().as_str(), status);
+        }
+        assert_eq!(SubscriptionStatus::parse("deleted"), None);
     }
 }
@@ -0,0 +1,110 @@
+use std::fmt;
+
+use validator::{validate_email, ValidationError};
+
+/// Valid and normalized email address of a subscriber.
+///
+/// Surrounding whitespace is trimmed and the domain is case-folded,
+/// internationalized domains are stored in punycode. The local part is kept
+/// as it is, its case may matter to the mail server.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SubscriberEmail(String);
+
+impl SubscriberEmail {
+    pub fn parse(email: &str) -> Result<Self, ValidationError> {
+        let (local, domain) = email
+            .trim()
+            .rsplit_once('@')
+            .ok_or_else(|| ValidationError::new("email"))?;
+        let domain = idna::domain_to_ascii(domain).map_err(|_| ValidationError::new("email"))?;
+        let email = format!("{}@{}", local, domain);
+        if validate_email(&email) {
+            Ok(Self(email))
+        } else {
+            Err(ValidationError::new("email"))
+        }
+    }
+}
+
+impl TryFrom<String> for SubscriberEmail {
+    type Error = String;
+
+    fn try_from(email: String) -> Result<Self, Self::Error> {
+        Self::parse(&email).map_err(|_| format!("{} is not a valid email address.", email))
+    }
+}
+
+impl From<SubscriberEmail> for String {
+    fn from(email: SubscriberEmail) -> Self {
+        email.0
+    }
+}
+
+impl AsRef<str> for SubscriberEmail {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SubscriberEmail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::assert_err;
+    use fake::{faker::internet::en::SafeEmail, Fake};
+    use rand::SeedableRng;
+
+    use super::SubscriberEmail;
+
+    #[derive(Debug, Clone)]
+    struct ValidEmailFixture(pub String);
+
+    impl quickcheck::Arbitrary for ValidEmailFixture {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(u64::arbitrary(g));
+            Self(SafeEmail().fake_with_rng(&mut rng))
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn valid_emails_are_parsed(valid_email: ValidEmailFixture) -> bool {
+        SubscriberEmail::parse(&valid_email.0).is_ok()
+    }
+
+    #[test]
+    fn email_is_trimmed_and_domain_case_folded() {
+        let email = SubscriberEmail::parse("  Tom.Smith@Gmail.COM \n").unwrap();
+        assert_eq!(email.as_ref(), "Tom.Smith@gmail.com");
+    }
+
+    #[test]
+    fn idn_domain_is_converted_to_punycode() {
+        let email = SubscriberEmail::parse("jan@příklad.cz").unwrap();
+        assert_eq!(email.as_ref(), "jan@xn--pklad-zsa96e.cz");
+    }
+
+    #[test]
+    fn invalid_emails_are_rejected() {
+        for email in [
+            "",
+            " ",
+            "tom",
+            "@gmail.com",
+            "tom@",
+            "tom@.com",
+            "to m@gmail.com",
+        ] {
+            assert_err!(SubscriberEmail::parse(email), "{}", email);
+        }
+    }
+
+    #[test]
+    fn invalid_email_cannot_be_deserialized() {
+        assert_err!(serde_json::from_str::<SubscriberEmail>(r#""tom@""#));
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+use unicode_segmentation::UnicodeSegmentation;
+use validator::ValidationError;
+
+/// Most grapheme clusters a name may have.
+pub const MAX_NAME_LENGTH: usize = 256;
+
+const FORBIDDEN_CHARACTERS: [char; 9] = ['/', '(', ')', '"', '<', '>', '\\', '{', '}'];
+
+/// Validated and trimmed name of a subscriber.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SubscriberName(String);
+
+impl SubscriberName {
+    /// The length is measured in grapheme clusters, i.e. characters as
+    /// readers see them, so `é` written with a combining accent counts once.
+    pub fn parse(name: &str) -> Result<Self, ValidationError> {
+        let name = name.trim();
+        let length = name.graphemes(true).count();
+        if length == 0 || length > MAX_NAME_LENGTH {
+            return Err(ValidationError::new("length"));
+        }
+        if name.chars().any(|ch| FORBIDDEN_CHARACTERS.contains(&ch)) {
+            return Err(ValidationError::new("contains"));
+        }
+        Ok(Self(name.to_owned()))
+    }
+}
+
+impl TryFrom<String> for SubscriberName {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Self::parse(&name).map_err(|_| format!("{} is not a valid name.", name))
+    }
+}
+
+impl From<SubscriberName> for String {
+    fn from(name: SubscriberName) -> Self {
+        name.0
+    }
+}
+
+impl AsRef<str> for SubscriberName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SubscriberName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use claims::{assert_err, assert_ok};
+
+    use super::{SubscriberName, MAX_NAME_LENGTH};
+
+    #[test]
+    fn name_of_max_graphemes_is_valid() {
+        // Each "ё" is two chars, but one grapheme.
+        let name = "е\u{0308}".repeat(MAX_NAME_LENGTH);
+        assert_ok!(SubscriberName::parse(&name));
+        assert_err!(SubscriberName::parse(&format!("{}a", name)));
+    }
+
+    #[test]
+    fn blank_name_is_rejected() {
+        assert_err!(SubscriberName::parse(""));
+        assert_err!(SubscriberName::parse("  \t"));
+    }
+
+    #[test]
+    fn name_is_trimmed() {
+        assert_eq!(
+            SubscriberName::parse(" Ursula ").unwrap().as_ref(),
+            "Ursula"
+        );
+    }
+
+    #[test]
+    fn forbidden_characters_are_rejected() {
+        for ch in ['/', '(', ')', '"', '<', '>', '\\', '{', '}'] {
+            assert_err!(SubscriberName::parse(&format!("tom{}", ch)));
+        }
+    }
+}
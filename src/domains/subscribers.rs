@@ -1,62 +1,692 @@
-use crate::domains::subscriber::NewSubscriber;
-use sqlx::{types::chrono::Utc, PgPool};
+use crate::domains::{
+    subscriber::{ChangedBy, NewSubscriber, Subscriber, SubscriptionStatus},
+    subscriber_email::SubscriberEmail,
+    subscriber_name::SubscriberName,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::stream::BoxStream;
+use sqlx::{Acquire, PgExecutor, PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
-#[tracing::instrument(name = "Saving a new subscriber", skip(pool))]
-pub async fn insert_subscriber(
+/// Postgres error code of a unique constraint violation.
+const UNIQUE_VIOLATION: &str = "23505";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TransitionError {
+    #[error("Subscription not found.")]
+    NotFound,
+    #[error("Subscription can not become {to} when it is {from}.")]
+    NotAllowed {
+        from: SubscriptionStatus,
+        to: SubscriptionStatus,
+    },
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum InsertError {
+    #[error("Address is already subscribed.")]
+    Exists,
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+/// Save the subscription together with the first entry of its history.
+async fn insert(
     subscriber: &NewSubscriber,
     conf_token: &str,
-    pool: &PgPool,
-) -> sqlx::Result<()> {
+    status: SubscriptionStatus,
+    consent_source: Option<&str>,
+    changed_by: &ChangedBy,
+    executor: impl PgExecutor<'_>,
+) -> Result<(), InsertError> {
     sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, confirmation_token, subscribed_at, status)
-    VALUES ($1, $2, $3, $4, $5, 'pending_confirmation')
+    WITH inserted AS (
+      INSERT INTO subscriptions
+        (id, email, name, confirmation_token, subscribed_at, status, timezone, consent_source)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+      RETURNING id, status, subscribed_at
+    )
+    INSERT INTO subscription_status_history
+      (subscription_id, from_status, to_status, changed_by, changed_at)
+    SELECT id, NULL, status, $9, subscribed_at FROM inserted
             "#,
         Uuid::new_v4(),
-        subscriber.email(),
-        subscriber.name(),
+        subscriber.email().as_ref(),
+        subscriber.name().as_ref(),
         conf_token,
-        Utc::now()
+        Utc::now(),
+        status.as_str(),
+        subscriber.timezone().map(|tz| tz.name()),
+        consent_source,
+        changed_by.to_string()
+    )
+    .execute(executor)
+    .await
+    .map_err(|e| match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some(UNIQUE_VIOLATION) => {
+            InsertError::Exists
+        }
+        _ => e.into(),
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Saving a new subscriber", skip(executor))]
+pub async fn insert_subscriber(
+    subscriber: &NewSubscriber,
+    conf_token: &str,
+    executor: impl PgExecutor<'_>,
+) -> Result<(), InsertError> {
+    insert(
+        subscriber,
+        conf_token,
+        SubscriptionStatus::PendingConfirmation,
+        None,
+        &ChangedBy::Subscriber,
+        executor,
     )
-    .execute(pool)
     .await
     .map_err(|e| {
         tracing::error!("Error from saving new subscriber {:?}", e);
         e
-    })?;
-    Ok(())
+    })
+}
+
+/// Save a subscriber of another list. Confirmed ones gave consent elsewhere,
+/// its source is kept with the subscription.
+#[tracing::instrument(name = "Saving an imported subscriber", skip(executor))]
+pub async fn insert_imported_subscriber(
+    subscriber: &NewSubscriber,
+    conf_token: &str,
+    status: SubscriptionStatus,
+    consent_source: Option<&str>,
+    executor: impl PgExecutor<'_>,
+) -> Result<(), InsertError> {
+    insert(
+        subscriber,
+        conf_token,
+        status,
+        consent_source,
+        &ChangedBy::Import,
+        executor,
+    )
+    .await
 }
 
-pub async fn confirm_subscriber(token: &str, pool: &PgPool) -> sqlx::Result<()> {
+/// Whether a subscription of the address exists, whatever the case of it.
+pub async fn exists(email: &str, pool: &PgPool) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"select exists(select 1 from subscriptions where lower(email) = lower($1)) as "exists!""#,
+        email
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(res.exists)
+}
+
+/// Change the status of the subscription when the change is allowed, it is
+/// recorded in the status history. Every change of a status goes through
+/// here.
+#[tracing::instrument(name = "Changing subscription status", skip(conn))]
+pub async fn transition<'c>(
+    id: Uuid,
+    to: SubscriptionStatus,
+    changed_by: &ChangedBy,
+    conn: impl Acquire<'c, Database = Postgres>,
+) -> Result<SubscriptionStatus, TransitionError> {
+    let mut transaction = conn.begin().await?;
+    let from = sqlx::query!(
+        r#"
+        select status as "status: SubscriptionStatus" from subscriptions
+        where id = $1 for update
+        "#,
+        id
+    )
+    .fetch_optional(&mut transaction)
+    .await?
+    .ok_or(TransitionError::NotFound)?
+    .status;
+    if !from.can_become(to) {
+        return Err(TransitionError::NotAllowed { from, to });
+    }
+
     sqlx::query!(
+        "update subscriptions set status = $2 where id = $1",
+        id,
+        to.as_str()
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        insert into subscription_status_history
+            (subscription_id, from_status, to_status, changed_by, changed_at)
+        values ($1, $2, $3, $4, $5)
+        "#,
+        id,
+        from.as_str(),
+        to.as_str(),
+        changed_by.to_string(),
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(from)
+}
+
+/// Confirm the pending subscription of the token, confirming it again
+/// changes nothing. Returns its id when this call confirmed it.
+pub async fn confirm_subscriber<'c>(
+    token: &str,
+    conn: impl Acquire<'c, Database = Postgres>,
+) -> Result<Option<Uuid>, TransitionError> {
+    let mut conn = conn.acquire().await?;
+    let subscription = sqlx::query!(
         r#"
-        update subscriptions set status='confirmed' where confirmation_token = $1
+        select id, status as "status: SubscriptionStatus" from subscriptions
+        where confirmation_token = $1
         "#,
         token,
     )
-    .execute(pool)
+    .fetch_optional(&mut *conn)
     .await
     .map_err(|e| {
         tracing::error!("Failed to execute query {:?}", e);
         e
-    })?;
-    Ok(())
+    })?
+    .ok_or(TransitionError::NotFound)?;
+    match subscription.status {
+        SubscriptionStatus::Confirmed => Ok(None),
+        // Whoever left must not be subscribed again by an old link.
+        SubscriptionStatus::PendingConfirmation => match transition(
+            subscription.id,
+            SubscriptionStatus::Confirmed,
+            &ChangedBy::Subscriber,
+            &mut *conn,
+        )
+        .await
+        {
+            Ok(_) => Ok(Some(subscription.id)),
+            // Confirmed by another request meanwhile.
+            Err(TransitionError::NotAllowed {
+                from: SubscriptionStatus::Confirmed,
+                ..
+            }) => Ok(None),
+            Err(e) => Err(e),
+        },
+        from => Err(TransitionError::NotAllowed {
+            from,
+            to: SubscriptionStatus::Confirmed,
+        }),
+    }
 }
 
-pub async fn get_confirmed_subscriber_emails<'a>(pool: &PgPool) -> sqlx::Result<Vec<String>> {
-    let res = sqlx::query!(r#"select email from subscriptions where status = 'confirmed'"#)
-        .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|s| s.email)
-        .collect::<Vec<String>>();
+struct SubscriberRow {
+    id: Uuid,
+    email: String,
+    name: String,
+    tracking_opt_out: bool,
+}
+
+impl SubscriberRow {
+    /// Rows saved before the current validation rules may not pass them, such
+    /// subscribers are skipped with a warning.
+    fn parse(self) -> Option<Subscriber> {
+        match (
+            SubscriberEmail::parse(&self.email),
+            SubscriberName::parse(&self.name),
+        ) {
+            (Ok(email), Ok(name)) => Some(Subscriber {
+                id: self.id,
+                email,
+                name,
+                tracking_opt_out: self.tracking_opt_out,
+            }),
+            _ => {
+                tracing::warn!("Skipping subscriber {} with invalid email or name", self.id);
+                None
+            }
+        }
+    }
+}
+
+pub async fn get_confirmed_subscribers(pool: &PgPool) -> sqlx::Result<Vec<Subscriber>> {
+    let rows = sqlx::query_as!(
+        SubscriberRow,
+        r#"select id, email, name, tracking_opt_out from subscriptions where status = 'confirmed'"#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().filter_map(SubscriberRow::parse).collect())
+}
+
+/// Timezones of confirmed subscribers, `default` stands for those without one.
+pub async fn get_confirmed_timezones(default: &str, pool: &PgPool) -> sqlx::Result<Vec<String>> {
+    let res = sqlx::query!(
+        r#"
+        select distinct coalesce(timezone, $1) as "timezone!" from subscriptions
+        where status = 'confirmed'
+        "#,
+        default
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|s| s.timezone)
+    .collect();
     Ok(res)
 }
 
+/// Confirmed subscribers in the timezone, `default` is used for those without one.
+pub async fn get_confirmed_subscribers_in_timezone(
+    timezone: &str,
+    default: &str,
+    pool: &PgPool,
+) -> sqlx::Result<Vec<Subscriber>> {
+    let rows = sqlx::query_as!(
+        SubscriberRow,
+        r#"
+        select id, email, name, tracking_opt_out from subscriptions
+        where status = 'confirmed' and coalesce(timezone, $2) = $1
+        "#,
+        timezone,
+        default
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().filter_map(SubscriberRow::parse).collect())
+}
+
+pub async fn get_subscriber(
+    id: Uuid,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<Option<Subscriber>> {
+    let row = sqlx::query_as!(
+        SubscriberRow,
+        r#"select id, email, name, tracking_opt_out from subscriptions where id = $1"#,
+        id
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(row.and_then(SubscriberRow::parse))
+}
+
+/// Subscription as administrators see it. Email and name are as stored, rows
+/// saved before the current validation rules are listed too.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SubscriptionRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: SubscriptionStatus,
+    pub subscribed_at: DateTime<Utc>,
+    pub timezone: Option<String>,
+    pub tracking_opt_out: bool,
+    /// Where a subscriber added without confirmation gave consent.
+    pub consent_source: Option<String>,
+    /// Reason the address is on the suppression list.
+    pub suppression: Option<String>,
+    pub suppressed_at: Option<DateTime<Utc>>,
+    /// Last time the subscription got the status, from its history.
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub unsubscribed_at: Option<DateTime<Utc>>,
+    pub expired_at: Option<DateTime<Utc>>,
+}
+
+/// Which subscriptions to list, every filter is optional.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+pub struct SubscriptionFilter {
+    pub status: Option<SubscriptionStatus>,
+    pub subscribed_after: Option<DateTime<Utc>>,
+    pub subscribed_before: Option<DateTime<Utc>>,
+    /// List the subscriber was imported from, its consent source.
+    pub list: Option<String>,
+    /// Whether the address is on the suppression list.
+    pub suppressed: Option<bool>,
+    /// Case insensitive prefix of the email or the name.
+    pub search: Option<String>,
+}
+
+impl SubscriptionFilter {
+    /// `LIKE` pattern of the search prefix.
+    fn search_pattern(&self) -> Option<String> {
+        self.search.as_ref().map(|search| {
+            let escaped = search
+                .trim()
+                .to_lowercase()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("{}%", escaped)
+        })
+    }
+}
+
+/// Position in the list of subscriptions, newest first, the next page starts
+/// after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionCursor {
+    pub subscribed_at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl SubscriptionCursor {
+    pub fn after(record: &SubscriptionRecord) -> Self {
+        Self {
+            subscribed_at: record.subscribed_at,
+            id: record.id,
+        }
+    }
+
+    /// Opaque form sent to clients.
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}|{}",
+            self.subscribed_at.timestamp_micros(),
+            self.id
+        ))
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let (micros, id) = decoded.split_once('|')?;
+        let micros: i64 = micros.parse().ok()?;
+        let subscribed_at = Utc
+            .timestamp_opt(
+                micros.div_euclid(1_000_000),
+                (micros.rem_euclid(1_000_000) * 1000) as u32,
+            )
+            .single()?;
+        Some(Self {
+            subscribed_at,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+/// Event in the history of a subscriber, `issue_id` is set for events of a
+/// newsletter issue.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HistoryEvent {
+    pub at: DateTime<Utc>,
+    pub event: String,
+    pub issue_id: Option<Uuid>,
+    pub detail: Option<String>,
+}
+
+/// Subscriptions matching the filter, newest first, starting after the
+/// cursor.
+#[tracing::instrument(name = "Searching subscriptions", skip(pool))]
+pub async fn search(
+    filter: &SubscriptionFilter,
+    after: Option<SubscriptionCursor>,
+    limit: i64,
+    pool: &PgPool,
+) -> sqlx::Result<Vec<SubscriptionRecord>> {
+    sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+        select s.id, s.email, s.name, s.status as "status: SubscriptionStatus", s.subscribed_at, s.timezone, s.tracking_opt_out,
+            s.consent_source, p.reason as "suppression?", p.created_at as "suppressed_at?",
+            h.confirmed_at as "confirmed_at?", h.unsubscribed_at as "unsubscribed_at?",
+            h.expired_at as "expired_at?"
+        from subscriptions s left join suppressions p on p.email = lower(s.email)
+        left join lateral (
+            select max(changed_at) filter (where to_status = 'confirmed') as confirmed_at,
+                max(changed_at) filter (where to_status = 'unsubscribed') as unsubscribed_at,
+                max(changed_at) filter (where to_status = 'expired') as expired_at
+            from subscription_status_history where subscription_id = s.id
+        ) h on true
+        where ($1::text is null or s.status = $1)
+            and ($2::timestamptz is null or s.subscribed_at >= $2)
+            and ($3::timestamptz is null or s.subscribed_at < $3)
+            and ($4::text is null or s.consent_source = $4)
+            and ($5::bool is null or (p.email is not null) = $5)
+            and ($6::text is null or lower(s.email) like $6 or lower(s.name) like $6)
+            and ($7::timestamptz is null or (s.subscribed_at, s.id) < ($7, $8))
+        order by s.subscribed_at desc, s.id desc
+        limit $9
+        "#,
+        filter.status.map(|status| status.as_str()),
+        filter.subscribed_after,
+        filter.subscribed_before,
+        filter.list.as_deref(),
+        filter.suppressed,
+        filter.search_pattern(),
+        after.map(|cursor| cursor.subscribed_at),
+        after.map(|cursor| cursor.id),
+        limit
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Every subscription matching the filter, oldest first. Rows are read from a
+/// cursor as the stream is polled, so none of them are collected.
+pub fn stream<'a>(
+    filter: &SubscriptionFilter,
+    pool: &'a PgPool,
+) -> BoxStream<'a, sqlx::Result<SubscriptionRecord>> {
+    sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+        select s.id, s.email, s.name, s.status as "status: SubscriptionStatus", s.subscribed_at, s.timezone, s.tracking_opt_out,
+            s.consent_source, p.reason as "suppression?", p.created_at as "suppressed_at?",
+            h.confirmed_at as "confirmed_at?", h.unsubscribed_at as "unsubscribed_at?",
+            h.expired_at as "expired_at?"
+        from subscriptions s left join suppressions p on p.email = lower(s.email)
+        left join lateral (
+            select max(changed_at) filter (where to_status = 'confirmed') as confirmed_at,
+                max(changed_at) filter (where to_status = 'unsubscribed') as unsubscribed_at,
+                max(changed_at) filter (where to_status = 'expired') as expired_at
+            from subscription_status_history where subscription_id = s.id
+        ) h on true
+        where ($1::text is null or s.status = $1)
+            and ($2::timestamptz is null or s.subscribed_at >= $2)
+            and ($3::timestamptz is null or s.subscribed_at < $3)
+            and ($4::text is null or s.consent_source = $4)
+            and ($5::bool is null or (p.email is not null) = $5)
+            and ($6::text is null or lower(s.email) like $6 or lower(s.name) like $6)
+        order by s.subscribed_at, s.id
+        "#,
+        filter.status.map(|status| status.as_str()),
+        filter.subscribed_after,
+        filter.subscribed_before,
+        filter.list.as_deref(),
+        filter.suppressed,
+        filter.search_pattern()
+    )
+    .fetch(pool)
+}
+
+pub async fn get_record(id: Uuid, pool: &PgPool) -> sqlx::Result<Option<SubscriptionRecord>> {
+    sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+        select s.id, s.email, s.name, s.status as "status: SubscriptionStatus", s.subscribed_at, s.timezone, s.tracking_opt_out,
+            s.consent_source, p.reason as "suppression?", p.created_at as "suppressed_at?",
+            h.confirmed_at as "confirmed_at?", h.unsubscribed_at as "unsubscribed_at?",
+            h.expired_at as "expired_at?"
+        from subscriptions s left join suppressions p on p.email = lower(s.email)
+        left join lateral (
+            select max(changed_at) filter (where to_status = 'confirmed') as confirmed_at,
+                max(changed_at) filter (where to_status = 'unsubscribed') as unsubscribed_at,
+                max(changed_at) filter (where to_status = 'expired') as expired_at
+            from subscription_status_history where subscription_id = s.id
+        ) h on true
+        where s.id = $1
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Changes of the status, deliveries of issues, opens and clicks of the
+/// subscriber, oldest first. Status changes name who made them.
+pub async fn get_history(id: Uuid, pool: &PgPool) -> sqlx::Result<Vec<HistoryEvent>> {
+    sqlx::query_as!(
+        HistoryEvent,
+        r#"
+        select at as "at!", event as "event!", issue_id, detail from (
+            select changed_at as at, to_status as event, null::uuid as issue_id,
+                changed_by as detail
+            from subscription_status_history where subscription_id = $1
+            union all
+            select sent_at, 'sent', issue_id, status
+            from newsletter_issue_recipients where subscriber_id = $1
+            union all
+            select o.opened_at, 'opened', r.issue_id, null
+            from open_events o join newsletter_issue_recipients r on r.id = o.recipient_id
+            where r.subscriber_id = $1
+            union all
+            select c.clicked_at, 'clicked', r.issue_id, l.url
+            from click_events c
+                join newsletter_issue_recipients r on r.id = c.recipient_id
+                join newsletter_issue_links l on l.id = c.link_id
+            where r.subscriber_id = $1
+        ) history
+        order by at
+        "#,
+        id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Delete the subscription, deliveries of issues to it are kept without the
+/// subscriber. The suppression of the address is kept as well.
+#[tracing::instrument(name = "Deleting subscriber", skip(pool))]
+pub async fn delete(id: Uuid, pool: &PgPool) -> sqlx::Result<bool> {
+    let res = sqlx::query!("delete from subscriptions where id = $1", id)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Subscription nobody confirmed yet, as stored.
+#[derive(Debug)]
+pub struct PendingSubscription {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub confirmation_token: Option<String>,
+}
+
+/// Lock the oldest pending subscription made in the period which was not
+/// reminded yet, others skip it until the transaction ends.
+pub async fn claim_pending_to_remind(
+    subscribed_after: DateTime<Utc>,
+    subscribed_before: DateTime<Utc>,
+    transaction: &mut Transaction<'_, Postgres>,
+) -> sqlx::Result<Option<PendingSubscription>> {
+    sqlx::query_as!(
+        PendingSubscription,
+        r#"
+        select id, email, name, confirmation_token from subscriptions
+        where status = 'pending_confirmation' and reminded_at is null
+            and subscribed_at > $1 and subscribed_at <= $2
+        order by subscribed_at
+        for update skip locked
+        limit 1
+        "#,
+        subscribed_after,
+        subscribed_before
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// Remember the subscription was reminded, it is not reminded again.
+pub async fn set_reminded(
+    id: Uuid,
+    at: DateTime<Utc>,
+    executor: impl PgExecutor<'_>,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        "update subscriptions set reminded_at = $2 where id = $1",
+        id,
+        at
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Delete pending subscriptions made before the time with their history,
+/// returns how many were deleted.
+pub async fn delete_pending(subscribed_before: DateTime<Utc>, pool: &PgPool) -> sqlx::Result<u64> {
+    let res = sqlx::query!(
+        r#"
+        delete from subscriptions
+        where status = 'pending_confirmation' and subscribed_at <= $1
+        "#,
+        subscribed_before
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+/// Expire pending subscriptions made before the time. Their address, name
+/// and token are removed, so the address may subscribe again, the history is
+/// kept. Returns how many expired.
+pub async fn anonymize_pending(
+    subscribed_before: DateTime<Utc>,
+    pool: &PgPool,
+) -> Result<u64, TransitionError> {
+    let pending = sqlx::query!(
+        r#"
+        select id from subscriptions
+        where status = 'pending_confirmation' and subscribed_at <= $1
+        "#,
+        subscribed_before
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut anonymized = 0;
+    for subscription in pending {
+        let mut transaction = pool.begin().await?;
+        match transition(
+            subscription.id,
+            SubscriptionStatus::Expired,
+            &ChangedBy::Purge,
+            &mut transaction,
+        )
+        .await
+        {
+            Ok(_) => {}
+            // Confirmed or deleted meanwhile.
+            Err(TransitionError::NotAllowed { .. } | TransitionError::NotFound) => continue,
+            Err(e) => return Err(e),
+        }
+        sqlx::query!(
+            r#"
+            update subscriptions
+            set email = id || '@anonymized.invalid', name = '', confirmation_token = null,
+                timezone = null, consent_source = null
+            where id = $1
+            "#,
+            subscription.id
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        anonymized += 1;
+    }
+    Ok(anonymized)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domains::suppressions::{suppress, SuppressionReason};
+    use futures::TryStreamExt;
 
     #[sqlx::test]
     fn saving_subscriber(pool: PgPool) {
@@ -69,27 +699,260 @@ mod tests {
             .expect("Failed to select subscriber");
         assert_eq!(res.name, "tom");
         assert_eq!(res.status, "pending_confirmation");
-        assert_eq!(res.confirmation_token, "conf_token");
+        assert_eq!(res.confirmation_token.as_deref(), Some("conf_token"));
     }
 
     #[sqlx::test]
-    fn get_confirmed_subscriber_emails(pool: PgPool) {
-        let conf_subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
-        let not_conf_subscriber = NewSubscriber::parse("petr", "petr@gmail.com").unwrap();
-        let _ = insert_subscriber(&conf_subscriber, "conf_token", &pool)
+    fn subscribers_without_timezone_use_default(pool: PgPool) {
+        let prague = NewSubscriber::parse("tom", "tom@gmail.com")
+            .unwrap()
+            .with_timezone("Europe/Prague")
+            .unwrap();
+        let unknown = NewSubscriber::parse("petr", "petr@gmail.com").unwrap();
+        insert_subscriber(&prague, "conf_token", &pool)
             .await
             .unwrap();
-        let _ = insert_subscriber(&not_conf_subscriber, "conf_token2", &pool)
+        insert_subscriber(&unknown, "conf_token2", &pool)
             .await
             .unwrap();
+        confirm_subscriber("conf_token", &pool).await.unwrap();
+        confirm_subscriber("conf_token2", &pool).await.unwrap();
 
-        let _ = confirm_subscriber("conf_token", &pool).await.unwrap();
+        let mut timezones = get_confirmed_timezones("UTC", &pool).await.unwrap();
+        timezones.sort();
+        assert_eq!(timezones, vec!["Europe/Prague", "UTC"]);
 
-        let res = sqlx::query!("select * from subscriptions where status = 'confirmed'")
-            .fetch_all(&pool)
+        let res = get_confirmed_subscribers_in_timezone("UTC", "UTC", &pool)
             .await
-            .expect("Failed to select subscriber");
+            .unwrap();
         assert_eq!(res.len(), 1);
-        assert_eq!(&res[0].name, conf_subscriber.name());
+        assert_eq!(res[0].name.as_ref(), "petr");
+    }
+
+    async fn add(name: &str, email: &str, pool: &PgPool) -> Uuid {
+        let subscriber = NewSubscriber::parse(name, email).unwrap();
+        insert_subscriber(&subscriber, &Uuid::new_v4().to_string(), pool)
+            .await
+            .unwrap();
+        sqlx::query!("select id from subscriptions where email = $1", email)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .id
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = SubscriptionCursor {
+            subscribed_at: Utc.timestamp_opt(1_690_000_000, 123_456_000).unwrap(),
+            id: Uuid::new_v4(),
+        };
+
+        assert_eq!(SubscriptionCursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(SubscriptionCursor::decode("not a cursor"), None);
+    }
+
+    #[sqlx::test]
+    fn search_pages_through_matching_subscriptions(pool: PgPool) {
+        for name in ["tom", "tomas", "petr"] {
+            add(name, &format!("{}@gmail.com", name), &pool).await;
+        }
+        let filter = SubscriptionFilter {
+            search: Some("TOM".into()),
+            ..Default::default()
+        };
+
+        let first = search(&filter, None, 1, &pool).await.unwrap();
+        let second = search(
+            &filter,
+            Some(SubscriptionCursor::after(&first[0])),
+            1,
+            &pool,
+        )
+        .await
+        .unwrap();
+        let third = search(
+            &filter,
+            Some(SubscriptionCursor::after(&second[0])),
+            1,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(first[0].name, "tomas");
+        assert_eq!(second[0].name, "tom");
+        assert!(third.is_empty());
+    }
+
+    #[sqlx::test]
+    fn search_escapes_like_wildcards(pool: PgPool) {
+        add("tom", "tom@gmail.com", &pool).await;
+        let filter = SubscriptionFilter {
+            search: Some("%".into()),
+            ..Default::default()
+        };
+
+        assert!(search(&filter, None, 10, &pool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    fn search_filters_by_status_and_suppression(pool: PgPool) {
+        let tom = add("tom", "tom@gmail.com", &pool).await;
+        add("petr", "petr@gmail.com", &pool).await;
+        transition(
+            tom,
+            SubscriptionStatus::Confirmed,
+            &ChangedBy::Subscriber,
+            &pool,
+        )
+        .await
+        .unwrap();
+        suppress("Petr@gmail.com", SuppressionReason::Bounced, &pool)
+            .await
+            .unwrap();
+
+        let confirmed = SubscriptionFilter {
+            status: Some(SubscriptionStatus::Confirmed),
+            ..Default::default()
+        };
+        let suppressed = SubscriptionFilter {
+            suppressed: Some(true),
+            ..Default::default()
+        };
+
+        let res = search(&confirmed, None, 10, &pool).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].id, tom);
+        let res = search(&suppressed, None, 10, &pool).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].suppression.as_deref(), Some("bounced"));
+    }
+
+    #[sqlx::test]
+    fn search_filters_by_list(pool: PgPool) {
+        add("tom", "tom@gmail.com", &pool).await;
+        let petr = NewSubscriber::parse("petr", "petr@gmail.com").unwrap();
+        insert_imported_subscriber(
+            &petr,
+            "petr_token",
+            SubscriptionStatus::Confirmed,
+            Some("old list"),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let old_list = SubscriptionFilter {
+            list: Some("old list".into()),
+            ..Default::default()
+        };
+
+        let res = search(&old_list, None, 10, &pool).await.unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].name, "petr");
+        let streamed: Vec<_> = stream(&old_list, &pool).try_collect().await.unwrap();
+        assert_eq!(streamed.len(), 1);
+    }
+
+    #[sqlx::test]
+    fn status_changes_are_recorded(pool: PgPool) {
+        let tom = add("tom", "tom@gmail.com", &pool).await;
+        let admin = ChangedBy::Admin("admin".into());
+
+        let from = transition(tom, SubscriptionStatus::Unsubscribed, &admin, &pool)
+            .await
+            .unwrap();
+        assert_eq!(from, SubscriptionStatus::PendingConfirmation);
+        assert!(matches!(
+            transition(tom, SubscriptionStatus::PendingConfirmation, &admin, &pool).await,
+            Err(TransitionError::NotAllowed { .. })
+        ));
+        assert!(matches!(
+            transition(Uuid::new_v4(), SubscriptionStatus::Confirmed, &admin, &pool).await,
+            Err(TransitionError::NotFound)
+        ));
+
+        let history: Vec<_> = get_history(tom, &pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| (event.event, event.detail))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                ("pending_confirmation".into(), Some("subscriber".into())),
+                ("unsubscribed".into(), Some("admin:admin".into())),
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    fn confirming_by_token_only_confirms_pending_subscriptions(pool: PgPool) {
+        let tom = add("tom", "tom@gmail.com", &pool).await;
+        let token = sqlx::query!(
+            "select confirmation_token from subscriptions where id = $1",
+            tom
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .confirmation_token
+        .unwrap();
+
+        confirm_subscriber(&token, &pool).await.unwrap();
+        confirm_subscriber(&token, &pool).await.unwrap();
+        transition(
+            tom,
+            SubscriptionStatus::Unsubscribed,
+            &ChangedBy::Subscriber,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            confirm_subscriber(&token, &pool).await,
+            Err(TransitionError::NotAllowed { .. })
+        ));
+        assert!(matches!(
+            confirm_subscriber("unknown", &pool).await,
+            Err(TransitionError::NotFound)
+        ));
+        assert_eq!(
+            get_record(tom, &pool).await.unwrap().unwrap().status,
+            SubscriptionStatus::Unsubscribed
+        );
+    }
+
+    #[sqlx::test]
+    fn confirmation_rolled_back_can_be_done_again(pool: PgPool) {
+        let tom = add("tom", "tom@gmail.com", &pool).await;
+        let token = sqlx::query!(
+            "select confirmation_token from subscriptions where id = $1",
+            tom
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .confirmation_token
+        .unwrap();
+
+        let mut transaction = pool.begin().await.unwrap();
+        let confirmed = confirm_subscriber(&token, &mut transaction).await.unwrap();
+        assert_eq!(confirmed, Some(tom));
+        transaction.rollback().await.unwrap();
+
+        assert_eq!(confirm_subscriber(&token, &pool).await.unwrap(), Some(tom));
+    }
+
+    #[sqlx::test]
+    fn deleting_subscription(pool: PgPool) {
+        let tom = add("tom", "tom@gmail.com", &pool).await;
+
+        assert!(delete(tom, &pool).await.unwrap());
+        assert!(!delete(tom, &pool).await.unwrap());
+        assert!(get_record(tom, &pool).await.unwrap().is_none());
     }
 }
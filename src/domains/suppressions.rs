@@ -0,0 +1,140 @@
+use chrono::Utc;
+use sqlx::PgPool;
+
+use crate::domains::subscriber::{ChangedBy, SubscriptionStatus};
+use crate::domains::subscribers::{transition, TransitionError};
+
+/// Why an address must not get any more emails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuppressionReason {
+    Bounced,
+    Complained,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionReason::Bounced => "bounced",
+            SuppressionReason::Complained => "complained",
+        }
+    }
+
+    /// Status of the subscription of a suppressed address.
+    pub fn status(&self) -> SubscriptionStatus {
+        match self {
+            SuppressionReason::Bounced => SubscriptionStatus::Bounced,
+            SuppressionReason::Complained => SubscriptionStatus::Complained,
+        }
+    }
+}
+
+/// Put the address on the suppression list, its subscription gets the reason
+/// as status. The first reason of an address is kept.
+#[tracing::instrument(name = "Suppressing address", skip(pool))]
+pub async fn suppress(
+    email: &str,
+    reason: SuppressionReason,
+    pool: &PgPool,
+) -> Result<(), TransitionError> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        r#"
+        insert into suppressions (email, reason, created_at) values (lower($1), $2, $3)
+        on conflict do nothing
+        "#,
+        email,
+        reason.as_str(),
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await?;
+    let subscriptions = sqlx::query!(
+        "select id from subscriptions where lower(email) = lower($1)",
+        email
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    for subscription in subscriptions {
+        match transition(
+            subscription.id,
+            reason.status(),
+            &ChangedBy::EmailProvider,
+            &mut transaction,
+        )
+        .await
+        {
+            // Already bounced or complained.
+            Ok(_) | Err(TransitionError::NotAllowed { .. }) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+pub async fn is_suppressed(email: &str, pool: &PgPool) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"select exists(select 1 from suppressions where email = lower($1)) as "exists!""#,
+        email
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(res.exists)
+}
+
+/// Addresses of the list which are suppressed, in lower case.
+pub async fn get_suppressed(emails: &[String], pool: &PgPool) -> sqlx::Result<Vec<String>> {
+    let emails: Vec<String> = emails.iter().map(|e| e.to_lowercase()).collect();
+    let res = sqlx::query!(
+        "select email from suppressions where email = any($1)",
+        &emails
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|s| s.email)
+    .collect();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domains::{subscriber::NewSubscriber, subscribers::insert_subscriber};
+
+    #[sqlx::test]
+    fn suppressed_address_is_found_case_insensitively(pool: PgPool) {
+        let subscriber = NewSubscriber::parse("tom", "Tom@Gmail.com").unwrap();
+        insert_subscriber(&subscriber, "conf_token", &pool)
+            .await
+            .unwrap();
+
+        suppress("tom@gmail.com", SuppressionReason::Complained, &pool)
+            .await
+            .unwrap();
+        suppress("TOM@gmail.com", SuppressionReason::Bounced, &pool)
+            .await
+            .unwrap();
+
+        assert!(is_suppressed("tom@GMAIL.com", &pool).await.unwrap());
+        assert!(!is_suppressed("petr@gmail.com", &pool).await.unwrap());
+        assert_eq!(
+            get_suppressed(&["petr@gmail.com".into(), "tom@gmail.COM".into()], &pool)
+                .await
+                .unwrap(),
+            vec!["tom@gmail.com"]
+        );
+        let reason = sqlx::query!("select reason from suppressions")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .reason;
+        assert_eq!(reason, "complained");
+        let status = sqlx::query!("select status from subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .status;
+        assert_eq!(status, "complained");
+    }
+}
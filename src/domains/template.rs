@@ -0,0 +1,286 @@
+use chrono::{DateTime, Utc};
+use validator::Validate;
+
+use crate::email_client::Identity;
+
+/// Emails which content is editable by admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateName {
+    Welcome,
+    Confirmation,
+    /// Sent once to subscribers who did not confirm, see [`crate::purge`].
+    ConfirmationReminder,
+    /// Editable ahead of the password reset flow, nothing sends it yet.
+    PasswordReset,
+    NewsletterLayout,
+}
+
+impl TemplateName {
+    pub const ALL: [TemplateName; 5] = [
+        TemplateName::Welcome,
+        TemplateName::Confirmation,
+        TemplateName::ConfirmationReminder,
+        TemplateName::PasswordReset,
+        TemplateName::NewsletterLayout,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateName::Welcome => "welcome",
+            TemplateName::Confirmation => "confirmation",
+            TemplateName::ConfirmationReminder => "confirmation_reminder",
+            TemplateName::PasswordReset => "password_reset",
+            TemplateName::NewsletterLayout => "newsletter_layout",
+        }
+    }
+
+    /// Sender the emails of the template are sent as.
+    pub fn identity(&self) -> Identity {
+        match self {
+            TemplateName::Welcome
+            | TemplateName::Confirmation
+            | TemplateName::ConfirmationReminder => Identity::Transactional,
+            TemplateName::PasswordReset => Identity::Security,
+            TemplateName::NewsletterLayout => Identity::Newsletter,
+        }
+    }
+
+    /// Values of placeholders used for template previews.
+    pub fn sample_data(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            TemplateName::Welcome => vec![("name", "Jane Doe")],
+            TemplateName::Confirmation | TemplateName::ConfirmationReminder => vec![
+                ("name", "Jane Doe"),
+                (
+                    "confirmation_link",
+                    "https://example.com/subscriptions/confirm?token=sample",
+                ),
+            ],
+            TemplateName::PasswordReset => vec![
+                ("name", "Jane Doe"),
+                (
+                    "reset_link",
+                    "https://example.com/password/reset?token=sample",
+                ),
+            ],
+            TemplateName::NewsletterLayout => vec![
+                ("title", "Sample newsletter"),
+                ("content", "Sample newsletter content."),
+            ],
+        }
+    }
+}
+
+/// Template of an email.
+///
+/// `{{key}}` placeholders are replaced by values given to `render`, in the html
+/// part the values are escaped, `{{{key}}}` inserts the value unescaped.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Template {
+    pub name: TemplateName,
+    /// Version 0 is the compiled-in default.
+    pub version: i32,
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+impl RenderedEmail {
+    /// Fill in per-recipient placeholders like `{{name}}`.
+    pub fn personalize(&self, vars: &[(&str, &str)]) -> RenderedEmail {
+        RenderedEmail {
+            subject: substitute(&self.subject, vars, false),
+            html: substitute(&self.html, vars, true),
+            text: substitute(&self.text, vars, false),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Validate)]
+pub struct NewTemplate {
+    #[validate(length(min = 1, max = 998))]
+    pub subject: String,
+    #[validate(length(min = 1))]
+    pub html: String,
+    #[validate(length(min = 1))]
+    pub text: String,
+}
+
+impl Template {
+    pub fn default_for(name: TemplateName) -> Self {
+        let (subject, html, text) = match name {
+            TemplateName::Welcome => (
+                "Welcome!",
+                "Hi {{name}},<br/> thank you for confirming your subscription to our newsletter.",
+                "Hi {{name}}, thank you for confirming your subscription to our newsletter.",
+            ),
+            TemplateName::Confirmation => (
+                "Welcome!",
+                "Welcome to our newsletter!<br/> Click <a href='{{confirmation_link}}'>here</a> to confirm your subscription.",
+                "Welcome to our newsletter! Visit {{confirmation_link}} to confirm your subscription.",
+            ),
+            TemplateName::ConfirmationReminder => (
+                "Do you still want our newsletter?",
+                "Hi {{name}},<br/> your subscription to our newsletter is not confirmed yet. Click <a href='{{confirmation_link}}'>here</a> to confirm it, otherwise it will be removed.",
+                "Hi {{name}}, your subscription to our newsletter is not confirmed yet. Visit {{confirmation_link}} to confirm it, otherwise it will be removed.",
+            ),
+            TemplateName::PasswordReset => (
+                "Password reset",
+                "Click <a href='{{reset_link}}'>here</a> to reset your password.<br/> If you did not ask for it, ignore this email.",
+                "Visit {{reset_link}} to reset your password. If you did not ask for it, ignore this email.",
+            ),
+            TemplateName::NewsletterLayout => (
+                "{{title}}",
+                concat!(
+                    "<!DOCTYPE html>\n",
+                    "<html>\n",
+                    "  <head><meta charset=\"utf-8\"><title>{{title}}</title></head>\n",
+                    "  <body>\n",
+                    "    <h1>{{title}}</h1>\n",
+                    "    {{{content}}}\n",
+                    "  </body>\n",
+                    "</html>\n",
+                ),
+                "{{{content}}}",
+            ),
+        };
+
+        Self {
+            name,
+            version: 0,
+            subject: subject.into(),
+            html: html.into(),
+            text: text.into(),
+            created_at: None,
+        }
+    }
+
+    pub fn render(&self, vars: &[(&str, &str)]) -> RenderedEmail {
+        RenderedEmail {
+            subject: self.subject.clone(),
+            html: self.html.clone(),
+            text: self.text.clone(),
+        }
+        .personalize(vars)
+    }
+}
+
+/// Replace placeholders in one pass from left to right, so placeholders
+/// inside values are never replaced. Unknown placeholders are kept.
+fn substitute(template: &str, vars: &[(&str, &str)], escape: bool) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (open, close) = if rest.starts_with("{{{") {
+            ("{{{", "}}}")
+        } else {
+            ("{{", "}}")
+        };
+        let found = rest[open.len()..].find(close).and_then(|end| {
+            let key = &rest[open.len()..open.len() + end];
+            let (_, value) = vars.iter().find(|(k, _)| *k == key)?;
+            Some((*value, open.len() + end + close.len()))
+        });
+        match found {
+            Some((value, len)) => {
+                if escape && open == "{{" {
+                    res.push_str(&escape_html(value));
+                } else {
+                    res.push_str(value);
+                }
+                rest = &rest[len..];
+            }
+            // The brace may start a placeholder one position further.
+            None => {
+                res.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_escaped_only_in_html() {
+        let template = Template {
+            subject: "Hi {{name}}".into(),
+            html: "<p>Hi {{name}}</p>".into(),
+            text: "Hi {{name}}".into(),
+            ..Template::default_for(TemplateName::Welcome)
+        };
+        assert_eq!(
+            template.render(&[("name", "<Tom & Jerry>")]),
+            RenderedEmail {
+                subject: "Hi <Tom & Jerry>".into(),
+                html: "<p>Hi &lt;Tom &amp; Jerry&gt;</p>".into(),
+                text: "Hi <Tom & Jerry>".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn triple_braces_are_not_escaped() {
+        let template = Template::default_for(TemplateName::NewsletterLayout);
+        let rendered = template.render(&[("title", "News"), ("content", "<p>Body</p>")]);
+        assert_eq!(rendered.subject, "News");
+        assert!(rendered.html.contains("<h1>News</h1>\n    <p>Body</p>"));
+        assert_eq!(rendered.text, "<p>Body</p>");
+    }
+
+    #[test]
+    fn values_are_not_searched_for_placeholders() {
+        let template = Template::default_for(TemplateName::Confirmation);
+        let rendered = template.render(&[
+            ("name", "{{confirmation_link}}"),
+            ("confirmation_link", "https://example.com/{{name}}"),
+        ]);
+        assert_eq!(
+            rendered.text,
+            "Welcome to our newsletter! Visit https://example.com/{{name}} to confirm your subscription."
+        );
+        let welcome =
+            Template::default_for(TemplateName::Welcome).render(&[("name", "{{{name}}}")]);
+        assert!(welcome.text.starts_with("Hi {{{name}}}, thank you"));
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        let template = Template::default_for(TemplateName::Confirmation);
+        let rendered = template.render(&[]);
+        assert!(rendered.text.contains("{{confirmation_link}}"));
+    }
+
+    #[test]
+    fn name_is_serialized_in_snake_case() {
+        for name in TemplateName::ALL {
+            assert_eq!(
+                serde_json::to_value(name).unwrap(),
+                serde_json::Value::String(name.as_str().into())
+            );
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::domains::template::{NewTemplate, Template, TemplateName};
+use chrono::Utc;
+use sqlx::PgPool;
+
+#[tracing::instrument(name = "Saving a new template version", skip(template, pool))]
+pub async fn add_version(
+    template_name: TemplateName,
+    template: &NewTemplate,
+    pool: &PgPool,
+) -> sqlx::Result<Template> {
+    let res = sqlx::query!(
+        r#"
+    INSERT INTO templates (name, version, subject, html, text, created_at)
+    SELECT $1, COALESCE(MAX(version), 0) + 1, $2, $3, $4, $5
+    FROM templates WHERE name = $1
+    RETURNING version, created_at
+            "#,
+        template_name.as_str(),
+        template.subject,
+        template.html,
+        template.text,
+        Utc::now()
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Error from saving new template {:?}", e);
+        e
+    })?;
+
+    Ok(Template {
+        name: template_name,
+        version: res.version,
+        subject: template.subject.clone(),
+        html: template.html.clone(),
+        text: template.text.clone(),
+        created_at: Some(res.created_at),
+    })
+}
+
+pub async fn get_latest(name: TemplateName, pool: &PgPool) -> sqlx::Result<Option<Template>> {
+    let res = sqlx::query!(
+        r#"
+        select version, subject, html, text, created_at from templates
+        where name = $1 order by version desc limit 1
+        "#,
+        name.as_str()
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|t| Template {
+        name,
+        version: t.version,
+        subject: t.subject,
+        html: t.html,
+        text: t.text,
+        created_at: Some(t.created_at),
+    });
+    Ok(res)
+}
+
+pub async fn get_version(
+    name: TemplateName,
+    version: i32,
+    pool: &PgPool,
+) -> sqlx::Result<Option<Template>> {
+    let res = sqlx::query!(
+        r#"
+        select version, subject, html, text, created_at from templates
+        where name = $1 and version = $2
+        "#,
+        name.as_str(),
+        version
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|t| Template {
+        name,
+        version: t.version,
+        subject: t.subject,
+        html: t.html,
+        text: t.text,
+        created_at: Some(t.created_at),
+    });
+    Ok(res)
+}
+
+pub async fn get_versions(name: TemplateName, pool: &PgPool) -> sqlx::Result<Vec<Template>> {
+    let res = sqlx::query!(
+        r#"
+        select version, subject, html, text, created_at from templates
+        where name = $1 order by version desc
+        "#,
+        name.as_str()
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|t| Template {
+        name,
+        version: t.version,
+        subject: t.subject,
+        html: t.html,
+        text: t.text,
+        created_at: Some(t.created_at),
+    })
+    .collect();
+    Ok(res)
+}
+
+/// Latest stored version of the template, the compiled-in default is used when
+/// there is none or the database is not reachable.
+pub async fn get_or_default(name: TemplateName, pool: &PgPool) -> Template {
+    match get_latest(name, pool).await {
+        Ok(Some(template)) => template,
+        Ok(None) => Template::default_for(name),
+        Err(e) => {
+            tracing::error!(
+                "Failed to load template {}, using default {:?}",
+                name.as_str(),
+                e
+            );
+            Template::default_for(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_template(subject: &str) -> NewTemplate {
+        NewTemplate {
+            subject: subject.into(),
+            html: "<p>{{name}}</p>".into(),
+            text: "{{name}}".into(),
+        }
+    }
+
+    #[sqlx::test]
+    fn versions_are_incremented(pool: PgPool) {
+        let first = add_version(TemplateName::Welcome, &new_template("first"), &pool)
+            .await
+            .unwrap();
+        let second = add_version(TemplateName::Welcome, &new_template("second"), &pool)
+            .await
+            .unwrap();
+        let other = add_version(TemplateName::Confirmation, &new_template("other"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(first.version, 1);
+        assert_eq!(second.version, 2);
+        assert_eq!(other.version, 1);
+
+        let latest = get_latest(TemplateName::Welcome, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.subject, "second");
+        let first = get_version(TemplateName::Welcome, 1, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.subject, "first");
+        assert_eq!(
+            get_versions(TemplateName::Welcome, &pool)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[sqlx::test]
+    fn default_is_used_when_not_stored(pool: PgPool) {
+        let template = get_or_default(TemplateName::ConfirmationReminder, &pool).await;
+        assert_eq!(template.version, 0);
+        assert_eq!(template.subject, "Do you still want our newsletter?");
+    }
+}
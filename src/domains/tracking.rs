@@ -0,0 +1,405 @@
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::email_client::MessageStatus;
+
+/// Opens of an issue, unique counts each recipient once.
+#[derive(Debug, Default, PartialEq, serde::Serialize)]
+pub struct OpenStats {
+    pub unique: i64,
+    pub total: i64,
+}
+
+/// Record that the issue is sent to the subscriber, the returned id identifies
+/// this delivery in tracking links.
+pub async fn insert_recipient(
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+    pool: &PgPool,
+) -> sqlx::Result<Uuid> {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        insert into newsletter_issue_recipients (id, issue_id, subscriber_id, sent_at)
+        values ($1, $2, $3, $4)
+        "#,
+        id,
+        issue_id,
+        subscriber_id,
+        Utc::now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(id)
+}
+
+/// Subscribers the issue was already sent to, they are skipped when sending
+/// of the issue is tried again.
+pub async fn get_recipient_subscribers(issue_id: Uuid, pool: &PgPool) -> sqlx::Result<Vec<Uuid>> {
+    let res = sqlx::query!(
+        r#"
+        select subscriber_id as "subscriber_id!" from newsletter_issue_recipients
+        where issue_id = $1 and subscriber_id is not null
+        "#,
+        issue_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| r.subscriber_id)
+    .collect();
+    Ok(res)
+}
+
+/// Record how sending to the recipient went.
+pub async fn record_result(
+    recipient_id: Uuid,
+    status: &MessageStatus,
+    pool: &PgPool,
+) -> sqlx::Result<()> {
+    let (message_id, error) = match status {
+        MessageStatus::Sent { message_id } => (message_id.as_deref(), None),
+        MessageStatus::Failed { error } => (None, Some(error.as_str())),
+        MessageStatus::Suppressed => (None, None),
+    };
+    sqlx::query!(
+        r#"
+        update newsletter_issue_recipients set status = $2, message_id = $3, error = $4
+        where id = $1
+        "#,
+        recipient_id,
+        status.as_str(),
+        message_id,
+        error
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Record an open of the delivery, unless the subscriber opted out of tracking.
+///
+/// Returns `false` when nothing was recorded.
+#[tracing::instrument(name = "Recording open", skip(pool))]
+pub async fn record_open(
+    recipient_id: Uuid,
+    user_agent: Option<&str>,
+    pool: &PgPool,
+) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"
+        insert into open_events (recipient_id, opened_at, user_agent)
+        select r.id, $2, $3 from newsletter_issue_recipients r
+        join subscriptions s on s.id = r.subscriber_id
+        where r.id = $1 and not s.tracking_opt_out
+        "#,
+        recipient_id,
+        Utc::now(),
+        user_agent
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// Stop tracking the subscriber who got the delivery.
+#[tracing::instrument(name = "Opting out of tracking", skip(pool))]
+pub async fn opt_out(recipient_id: Uuid, pool: &PgPool) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"
+        update subscriptions set tracking_opt_out = true
+        where id = (select subscriber_id from newsletter_issue_recipients where id = $1)
+        "#,
+        recipient_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// Clicks of one link of an issue, unique counts each recipient once.
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct LinkStats {
+    pub url: String,
+    pub unique: i64,
+    pub total: i64,
+}
+
+/// Store a link of the issue, the same url always gets the same id.
+pub async fn insert_link(issue_id: Uuid, url: &str, pool: &PgPool) -> sqlx::Result<i32> {
+    let res = sqlx::query!(
+        r#"
+        insert into newsletter_issue_links (issue_id, url) values ($1, $2)
+        on conflict (issue_id, url) do update set url = excluded.url
+        returning id
+        "#,
+        issue_id,
+        url
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(res.id)
+}
+
+/// Url of the link when it belongs to the issue the recipient got.
+pub async fn get_link_url(
+    recipient_id: Uuid,
+    link_id: i32,
+    pool: &PgPool,
+) -> sqlx::Result<Option<String>> {
+    let res = sqlx::query!(
+        r#"
+        select l.url from newsletter_issue_links l
+        join newsletter_issue_recipients r on r.issue_id = l.issue_id
+        where r.id = $1 and l.id = $2
+        "#,
+        recipient_id,
+        link_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .map(|l| l.url);
+    Ok(res)
+}
+
+/// Record a click of the link, unless the subscriber opted out of tracking.
+#[tracing::instrument(name = "Recording click", skip(pool))]
+pub async fn record_click(
+    recipient_id: Uuid,
+    link_id: i32,
+    user_agent: Option<&str>,
+    pool: &PgPool,
+) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"
+        insert into click_events (recipient_id, link_id, clicked_at, user_agent)
+        select r.id, $2, $3, $4 from newsletter_issue_recipients r
+        join subscriptions s on s.id = r.subscriber_id
+        where r.id = $1 and not s.tracking_opt_out
+        "#,
+        recipient_id,
+        link_id,
+        Utc::now(),
+        user_agent
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+pub async fn get_click_stats(issue_id: Uuid, pool: &PgPool) -> sqlx::Result<Vec<LinkStats>> {
+    sqlx::query_as!(
+        LinkStats,
+        r#"
+        select l.url, count(distinct e.recipient_id) as "unique!", count(e.link_id) as "total!"
+        from newsletter_issue_links l
+        left join click_events e on e.link_id = l.id
+        where l.issue_id = $1
+        group by l.id
+        order by l.id
+        "#,
+        issue_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_open_stats(issue_id: Uuid, pool: &PgPool) -> sqlx::Result<OpenStats> {
+    let res = sqlx::query_as!(
+        OpenStats,
+        r#"
+        select count(distinct e.recipient_id) as "unique!", count(*) as "total!"
+        from open_events e
+        join newsletter_issue_recipients r on r.id = e.recipient_id
+        where r.issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domains::{
+        issue::IssueDraft,
+        issues::insert_draft,
+        subscriber::NewSubscriber,
+        subscribers::{get_confirmed_subscribers, insert_subscriber},
+    };
+
+    #[sqlx::test]
+    fn opens_are_counted_per_issue(pool: PgPool) {
+        let draft = IssueDraft {
+            title: "News".into(),
+            content: None,
+            track_opens: true,
+            track_clicks: false,
+        };
+        let issue = insert_draft(&draft, &pool).await.unwrap();
+        let other = insert_draft(&draft, &pool).await.unwrap();
+        for (name, email) in [("tom", "tom@gmail.com"), ("petr", "petr@gmail.com")] {
+            let subscriber = NewSubscriber::parse(name, email).unwrap();
+            insert_subscriber(&subscriber, email, &pool).await.unwrap();
+        }
+        sqlx::query!("update subscriptions set status = 'confirmed'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let subscribers = get_confirmed_subscribers(&pool).await.unwrap();
+
+        let first = insert_recipient(issue.id, subscribers[0].id, &pool)
+            .await
+            .unwrap();
+        let second = insert_recipient(issue.id, subscribers[1].id, &pool)
+            .await
+            .unwrap();
+        let elsewhere = insert_recipient(other.id, subscribers[0].id, &pool)
+            .await
+            .unwrap();
+        for recipient_id in [first, first, second, elsewhere] {
+            assert!(record_open(recipient_id, Some("Mail"), &pool)
+                .await
+                .unwrap());
+        }
+
+        assert_eq!(
+            get_open_stats(issue.id, &pool).await.unwrap(),
+            OpenStats {
+                unique: 2,
+                total: 3
+            }
+        );
+    }
+
+    #[sqlx::test]
+    fn opens_are_not_recorded_after_opt_out(pool: PgPool) {
+        let draft = IssueDraft {
+            title: "News".into(),
+            content: None,
+            track_opens: true,
+            track_clicks: false,
+        };
+        let issue = insert_draft(&draft, &pool).await.unwrap();
+        let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+        insert_subscriber(&subscriber, "conf_token", &pool)
+            .await
+            .unwrap();
+        let subscriber_id = sqlx::query!("select id from subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .id;
+        let recipient_id = insert_recipient(issue.id, subscriber_id, &pool)
+            .await
+            .unwrap();
+
+        assert!(opt_out(recipient_id, &pool).await.unwrap());
+        assert!(!record_open(recipient_id, None, &pool).await.unwrap());
+        assert!(!record_open(Uuid::new_v4(), None, &pool).await.unwrap());
+        assert_eq!(
+            get_open_stats(issue.id, &pool).await.unwrap(),
+            OpenStats::default()
+        );
+    }
+
+    #[sqlx::test]
+    fn recipient_subscribers_are_listed_per_issue(pool: PgPool) {
+        let draft = IssueDraft {
+            title: "News".into(),
+            content: None,
+            track_opens: false,
+            track_clicks: false,
+        };
+        let issue = insert_draft(&draft, &pool).await.unwrap();
+        let other = insert_draft(&draft, &pool).await.unwrap();
+        let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+        insert_subscriber(&subscriber, "conf_token", &pool)
+            .await
+            .unwrap();
+        let subscriber_id = sqlx::query!("select id from subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .id;
+        insert_recipient(issue.id, subscriber_id, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_recipient_subscribers(issue.id, &pool).await.unwrap(),
+            vec![subscriber_id]
+        );
+        assert!(get_recipient_subscribers(other.id, &pool)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test]
+    fn clicks_are_counted_per_link(pool: PgPool) {
+        let draft = IssueDraft {
+            title: "News".into(),
+            content: None,
+            track_opens: false,
+            track_clicks: true,
+        };
+        let issue = insert_draft(&draft, &pool).await.unwrap();
+        let other = insert_draft(&draft, &pool).await.unwrap();
+        let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+        insert_subscriber(&subscriber, "conf_token", &pool)
+            .await
+            .unwrap();
+        let subscriber_id = sqlx::query!("select id from subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .id;
+        let recipient_id = insert_recipient(issue.id, subscriber_id, &pool)
+            .await
+            .unwrap();
+
+        let first = insert_link(issue.id, "https://a.com", &pool).await.unwrap();
+        insert_link(issue.id, "https://b.com", &pool).await.unwrap();
+        assert_eq!(
+            insert_link(issue.id, "https://a.com", &pool).await.unwrap(),
+            first
+        );
+        let foreign = insert_link(other.id, "https://evil.com", &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_link_url(recipient_id, first, &pool).await.unwrap(),
+            Some("https://a.com".into())
+        );
+        assert_eq!(
+            get_link_url(recipient_id, foreign, &pool).await.unwrap(),
+            None
+        );
+
+        for _ in 0..2 {
+            assert!(record_click(recipient_id, first, None, &pool)
+                .await
+                .unwrap());
+        }
+        assert_eq!(
+            get_click_stats(issue.id, &pool).await.unwrap(),
+            vec![
+                LinkStats {
+                    url: "https://a.com".into(),
+                    unique: 1,
+                    total: 2
+                },
+                LinkStats {
+                    url: "https://b.com".into(),
+                    unique: 0,
+                    total: 0
+                }
+            ]
+        );
+    }
+}
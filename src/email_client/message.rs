@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::sender::{Identity, Senders};
+use crate::domains::subscriber_email::SubscriberEmail;
+
+/// Most bytes of attachments one message may carry.
+pub const MAX_ATTACHMENTS_SIZE: usize = 10 * 1024 * 1024;
+
+/// Email for one recipient, options are added builder style:
+///
+/// ```
+/// # use zero2prod::{domains::subscriber_email::SubscriberEmail, email_client::EmailMessage};
+/// let to = SubscriberEmail::parse("tom@gmail.com").unwrap();
+/// let support = SubscriberEmail::parse("support@gmail.com").unwrap();
+/// let message = EmailMessage::new(&to, "Hello", "<p>Hi</p>", "Hi")
+///     .reply_to(&support)
+///     .header("List-Unsubscribe", "<https://newsletter.com/unsubscribe>")
+///     .tag("newsletter");
+/// ```
+///
+/// Empty options are left out when serialized, the message is stored in this
+/// form while it waits in the outbox.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmailMessage {
+    #[serde(default)]
+    identity: Identity,
+    to: SubscriberEmail,
+    subject: String,
+    html: String,
+    text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<SubscriberEmail>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cc: Vec<SubscriberEmail>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bcc: Vec<SubscriberEmail>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_stream: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+/// File sent along the message, its content is base64 encoded when
+/// serialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub content_type: String,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub content: Vec<u8>,
+}
+
+impl EmailMessage {
+    pub fn new(to: &SubscriberEmail, subject: &str, html: &str, text: &str) -> Self {
+        Self {
+            identity: Identity::default(),
+            to: to.clone(),
+            subject: subject.into(),
+            html: html.into(),
+            text: text.into(),
+            reply_to: None,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            headers: Vec::new(),
+            message_stream: None,
+            tag: None,
+            metadata: BTreeMap::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    /// Send it as the sender of `identity`, transactional by default.
+    pub fn sent_as(mut self, identity: Identity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    pub fn identity(&self) -> Identity {
+        self.identity
+    }
+
+    pub fn to(&self) -> &SubscriberEmail {
+        &self.to
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn html(&self) -> &str {
+        &self.html
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn reply_to(mut self, address: &SubscriberEmail) -> Self {
+        self.reply_to = Some(address.clone());
+        self
+    }
+
+    pub fn cc(mut self, address: &SubscriberEmail) -> Self {
+        self.cc.push(address.clone());
+        self
+    }
+
+    pub fn bcc(mut self, address: &SubscriberEmail) -> Self {
+        self.bcc.push(address.clone());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push(Header {
+            name: name.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Stream of the provider the message is sent by, e.g. broadcasts are
+    /// kept apart from transactional emails.
+    pub fn message_stream(mut self, stream: &str) -> Self {
+        self.message_stream = Some(stream.into());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Kept by the provider with the message and sent back in webhooks.
+    pub fn metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn attachment(mut self, name: &str, content_type: &str, content: Vec<u8>) -> Self {
+        self.attachments.push(Attachment {
+            name: name.into(),
+            content_type: content_type.into(),
+            content,
+        });
+        self
+    }
+
+    /// Bytes of all attachments.
+    pub fn attachments_size(&self) -> usize {
+        self.attachments.iter().map(|a| a.content.len()).sum()
+    }
+
+    /// Body of the request to the provider, replies go to the sender's
+    /// reply-to address unless the message has its own.
+    pub(super) fn request<'a>(&'a self, senders: &'a Senders) -> SendEmailRequest<'a> {
+        let sender = senders.get(self.identity);
+        SendEmailRequest {
+            from: sender.to_string(),
+            to: self.to.as_ref(),
+            subject: &self.subject,
+            html_body: &self.html,
+            text_body: &self.text,
+            reply_to: self
+                .reply_to
+                .as_ref()
+                .map(AsRef::as_ref)
+                .or(sender.reply_to.as_ref().map(AsRef::as_ref)),
+            cc: join(&self.cc),
+            bcc: join(&self.bcc),
+            headers: self
+                .headers
+                .iter()
+                .map(|h| HeaderRequest {
+                    name: &h.name,
+                    value: &h.value,
+                })
+                .collect(),
+            message_stream: self.message_stream.as_deref(),
+            tag: self.tag.as_deref(),
+            metadata: &self.metadata,
+            attachments: self
+                .attachments
+                .iter()
+                .map(|a| AttachmentRequest {
+                    name: &a.name,
+                    content: STANDARD.encode(&a.content),
+                    content_type: &a.content_type,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Addresses as one comma separated list, `None` when there are none.
+fn join(addresses: &[SubscriberEmail]) -> Option<String> {
+    (!addresses.is_empty()).then(|| {
+        addresses
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<&str>>()
+            .join(", ")
+    })
+}
+
+/// Body of a request for one message, the same for single and batch
+/// requests. Fields are named as Postmark expects them.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub(super) struct SendEmailRequest<'a> {
+    from: String,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bcc: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<HeaderRequest<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_stream: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentRequest<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HeaderRequest<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AttachmentRequest<'a> {
+    name: &'a str,
+    content: String,
+    content_type: &'a str,
+}
+
+fn to_base64<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(content))
+}
+
+fn from_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::EmailMessage;
+    use crate::domains::subscriber_email::SubscriberEmail;
+    use crate::email_client::{Identity, Sender, Senders};
+
+    fn tom() -> SubscriberEmail {
+        address("tom@gmail.com")
+    }
+
+    fn address(email: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(email).unwrap()
+    }
+
+    #[test]
+    fn empty_options_are_not_serialized() {
+        let message = EmailMessage::new(&tom(), "Subject", "<p>Hi</p>", "Hi");
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({
+                "identity": "transactional",
+                "to": "tom@gmail.com",
+                "subject": "Subject",
+                "html": "<p>Hi</p>",
+                "text": "Hi"
+            })
+        );
+    }
+
+    #[test]
+    fn request_is_from_sender_of_identity() {
+        let senders = Senders {
+            newsletter: Sender {
+                name: "Newsletter".into(),
+                reply_to: Some("editor@gmail.com".to_owned().try_into().unwrap()),
+                ..Sender::from_address("newsletter@gmail.com").unwrap()
+            },
+            ..Senders::single(Sender::from_address("info@gmail.com").unwrap())
+        };
+        let message = EmailMessage::new(&tom(), "Subject", "<p>Hi</p>", "Hi");
+
+        let request = serde_json::to_value(message.request(&senders)).unwrap();
+        assert_eq!(request["From"], "info@gmail.com");
+        assert!(request.get("ReplyTo").is_none());
+
+        let message = message.sent_as(Identity::Newsletter);
+        let request = serde_json::to_value(message.request(&senders)).unwrap();
+        assert_eq!(request["From"], "\"Newsletter\" <newsletter@gmail.com>");
+        assert_eq!(request["ReplyTo"], "editor@gmail.com");
+
+        let message = message.reply_to(&tom());
+        let request = serde_json::to_value(message.request(&senders)).unwrap();
+        assert_eq!(request["ReplyTo"], "tom@gmail.com");
+    }
+
+    #[test]
+    fn request_has_postmark_fields() {
+        let senders = Senders::single(Sender::from_address("info@gmail.com").unwrap());
+        let message = EmailMessage::new(&tom(), "Subject", "<p>Hi</p>", "Hi")
+            .cc(&address("petr@gmail.com"))
+            .cc(&address("jan@gmail.com"))
+            .header("List-Unsubscribe", "<https://blog.com/unsubscribe>")
+            .message_stream("broadcast")
+            .tag("newsletter")
+            .metadata("issue_id", "42")
+            .attachment("hello.txt", "text/plain", b"hello".to_vec());
+
+        assert_eq!(
+            serde_json::to_value(message.request(&senders)).unwrap(),
+            serde_json::json!({
+                "From": "info@gmail.com",
+                "To": "tom@gmail.com",
+                "Subject": "Subject",
+                "HtmlBody": "<p>Hi</p>",
+                "TextBody": "Hi",
+                "Cc": "petr@gmail.com, jan@gmail.com",
+                "Headers": [
+                    { "Name": "List-Unsubscribe", "Value": "<https://blog.com/unsubscribe>" }
+                ],
+                "MessageStream": "broadcast",
+                "Tag": "newsletter",
+                "Metadata": { "issue_id": "42" },
+                "Attachments": [
+                    { "Name": "hello.txt", "Content": "aGVsbG8=", "ContentType": "text/plain" }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn options_are_serialized() {
+        let message = EmailMessage::new(&tom(), "Subject", "<p>Hi</p>", "Hi")
+            .reply_to(&address("support@gmail.com"))
+            .cc(&address("petr@gmail.com"))
+            .bcc(&address("archive@gmail.com"))
+            .header("List-Unsubscribe", "<https://blog.com/unsubscribe>")
+            .message_stream("broadcast")
+            .tag("newsletter")
+            .metadata("issue_id", "42")
+            .attachment("hello.txt", "text/plain", b"hello".to_vec())
+            .sent_as(Identity::Newsletter);
+
+        let json = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "identity": "newsletter",
+                "to": "tom@gmail.com",
+                "subject": "Subject",
+                "html": "<p>Hi</p>",
+                "text": "Hi",
+                "reply_to": "support@gmail.com",
+                "cc": ["petr@gmail.com"],
+                "bcc": ["archive@gmail.com"],
+                "headers": [
+                    { "name": "List-Unsubscribe", "value": "<https://blog.com/unsubscribe>" }
+                ],
+                "message_stream": "broadcast",
+                "tag": "newsletter",
+                "metadata": { "issue_id": "42" },
+                "attachments": [
+                    { "name": "hello.txt", "content_type": "text/plain", "content": "aGVsbG8=" }
+                ]
+            })
+        );
+        let parsed: EmailMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, message);
+        assert_eq!(parsed.attachments_size(), 5);
+    }
+
+    #[test]
+    fn invalid_copy_address_is_not_deserialized() {
+        let mut json =
+            serde_json::to_value(EmailMessage::new(&tom(), "Subject", "<p>Hi</p>", "Hi")).unwrap();
+        json["cc"] = serde_json::json!(["not an address"]);
+
+        assert!(serde_json::from_value::<EmailMessage>(json).is_err());
+    }
+}
@@ -0,0 +1,719 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use tracing::field::Empty;
+
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::domains::subscriber_email::SubscriberEmail;
+use crate::domains::suppressions::{get_suppressed, is_suppressed};
+use crate::throttle::Throttle;
+
+mod message;
+mod sender;
+
+pub use message::{Attachment, EmailMessage, Header, MAX_ATTACHMENTS_SIZE};
+pub use sender::{Identity, Sender, SenderAddress, Senders};
+
+/// Most messages Postmark accepts in one batch request.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Recipient {0} is on the suppression list.")]
+    Suppressed(String),
+    #[error("Email provider is unavailable, the circuit is open.")]
+    CircuitOpen,
+    #[error("Attachments of {0} bytes are over the limit of {MAX_ATTACHMENTS_SIZE} bytes.")]
+    AttachmentsTooLarge(usize),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    RequestError(#[from] reqwest::Error),
+}
+
+impl Error {
+    /// Whether sending the same message again fails the same way, e.g. the
+    /// provider rejected it as invalid.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::AttachmentsTooLarge(_) => true,
+            Error::RequestError(e) => e.status().is_some_and(|s| {
+                s.is_client_error() && s != reqwest::StatusCode::TOO_MANY_REQUESTS
+            }),
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Outcome of sending one message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageStatus {
+    Sent { message_id: Option<String> },
+    Failed { error: String },
+    Suppressed,
+}
+
+impl MessageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageStatus::Sent { .. } => "sent",
+            MessageStatus::Failed { .. } => "failed",
+            MessageStatus::Suppressed => "suppressed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EmailClient {
+    http_client: reqwest::Client,
+    base_url: String,
+    senders: Senders,
+    token: Secret<String>,
+    /// Database with the suppression list, recipients on it never get emails.
+    suppressions: Option<PgPool>,
+    batch: bool,
+    /// Shared by all requests to the provider.
+    throttle: Throttle,
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+/// Result of one message of a batch request.
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResponseItem {
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+impl EmailClient {
+    pub fn new(
+        base_url: String,
+        senders: Senders,
+        timeout_milliseconds: u64,
+        token: Secret<String>,
+    ) -> Self {
+        let timeout = Duration::from_millis(timeout_milliseconds);
+
+        Self {
+            http_client: reqwest::Client::builder().timeout(timeout).build().unwrap(),
+            base_url,
+            senders,
+            token,
+            suppressions: None,
+            batch: false,
+            throttle: Throttle::new(u32::MAX, 1),
+            circuit_breaker: None,
+        }
+    }
+
+    /// Fail fast while the provider keeps failing.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker
+            .as_ref()
+            .map_or(CircuitState::Closed, |b| b.state())
+    }
+
+    /// Limit the requests to the provider, by default they are sent one
+    /// at a time with no limit on the rate.
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Send many messages at once by the batch endpoint.
+    pub fn with_batch_api(mut self) -> Self {
+        self.batch = true;
+        self
+    }
+
+    pub fn supports_batch(&self) -> bool {
+        self.batch
+    }
+
+    /// Refuse to send to addresses on the suppression list in the database.
+    pub fn with_suppression_list(mut self, pool: PgPool) -> Self {
+        self.suppressions = Some(pool);
+        self
+    }
+
+    pub async fn send_email(
+        &self,
+        to: &SubscriberEmail,
+        subject: &str,
+        html: &str,
+        text: &str,
+    ) -> Result<()> {
+        self.send(&EmailMessage::new(to, subject, html, text)).await
+    }
+
+    #[tracing::instrument(
+        name = "Sending email",
+        skip_all,
+        fields(in_flight = Empty, backlog = Empty)
+    )]
+    pub async fn send(&self, message: &EmailMessage) -> Result<()> {
+        if let Some(pool) = &self.suppressions {
+            if is_suppressed(message.to().as_ref(), pool).await? {
+                return Err(Error::Suppressed(message.to().to_string()));
+            }
+        }
+        if message.attachments_size() > MAX_ATTACHMENTS_SIZE {
+            return Err(Error::AttachmentsTooLarge(message.attachments_size()));
+        }
+
+        let request = message.request(&self.senders);
+
+        self.call(async {
+            self.http_client
+                .post(format!("{}/email", self.base_url))
+                .header("X-Postmark-Server-Token", self.token.expose_secret())
+                .json(&request)
+                .send()
+                .await?
+                .error_for_status()
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Send the emails, by batch requests when the provider supports them.
+    ///
+    /// Failures of sending are returned as statuses of the messages, only
+    /// a failed check of the suppression list is an error.
+    #[tracing::instrument(
+        name = "Sending emails",
+        skip_all,
+        fields(size = emails.len(), emails_per_second = Empty)
+    )]
+    pub async fn send_many(&self, emails: &[EmailMessage]) -> Result<Vec<MessageStatus>> {
+        let start = Instant::now();
+        let statuses = if self.batch {
+            self.send_batch(emails).await?
+        } else {
+            // Sent concurrently, as many at once as the throttle allows.
+            let results = join_all(emails.iter().map(|e| self.send(e))).await;
+            let mut statuses = Vec::with_capacity(emails.len());
+            for result in results {
+                statuses.push(match result {
+                    Ok(()) => MessageStatus::Sent { message_id: None },
+                    Err(Error::Suppressed(_)) => MessageStatus::Suppressed,
+                    Err(e @ (Error::RequestError(_) | Error::CircuitOpen)) => {
+                        MessageStatus::Failed {
+                            error: e.to_string(),
+                        }
+                    }
+                    Err(e @ Error::AttachmentsTooLarge(_)) => MessageStatus::Failed {
+                        error: e.to_string(),
+                    },
+                    Err(e) => return Err(e),
+                });
+            }
+            statuses
+        };
+        let per_second = emails.len() as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON);
+        tracing::Span::current().record("emails_per_second", per_second.round());
+        Ok(statuses)
+    }
+
+    /// Make the request unless the circuit is open, waiting for the throttle.
+    ///
+    /// Timeouts, connection and server errors count as failures of the
+    /// provider, client errors are ours.
+    async fn call<T>(&self, request: impl Future<Output = reqwest::Result<T>>) -> Result<T> {
+        let attempt = match &self.circuit_breaker {
+            Some(breaker) => Some(breaker.allow().ok_or(Error::CircuitOpen)?),
+            None => None,
+        };
+        let _permit = self.acquire_permit().await;
+        let res = request.await;
+        if let Some(attempt) = attempt {
+            let failed = matches!(&res, Err(e) if e.status().is_none_or(|s| s.is_server_error()));
+            attempt.record(failed);
+        }
+        Ok(res?)
+    }
+
+    /// Wait for the throttle and note its state in the current span.
+    async fn acquire_permit(&self) -> crate::throttle::Permit<'_> {
+        let span = tracing::Span::current();
+        span.record("backlog", self.throttle.backlog() + 1);
+        let permit = self.throttle.acquire().await;
+        span.record("in_flight", self.throttle.in_flight());
+        permit
+    }
+
+    /// Send the emails by batch requests, the status of each message is
+    /// returned in the same order.
+    ///
+    /// A failed request, e.g. the provider being down, fails only the
+    /// messages of its batch, those of other batches keep their statuses.
+    #[tracing::instrument(
+        name = "Sending email batch",
+        skip_all,
+        fields(size = emails.len(), in_flight = Empty, backlog = Empty)
+    )]
+    pub async fn send_batch(&self, emails: &[EmailMessage]) -> Result<Vec<MessageStatus>> {
+        let suppressed = match &self.suppressions {
+            Some(pool) => {
+                let recipients: Vec<String> = emails.iter().map(|e| e.to().to_string()).collect();
+                get_suppressed(&recipients, pool).await?
+            }
+            None => Vec::new(),
+        };
+
+        let mut statuses = vec![MessageStatus::Suppressed; emails.len()];
+        let mut to_send = Vec::with_capacity(emails.len());
+        for (i, email) in emails.iter().enumerate() {
+            if suppressed.contains(&email.to().as_ref().to_lowercase()) {
+                continue;
+            }
+            if email.attachments_size() > MAX_ATTACHMENTS_SIZE {
+                statuses[i] = MessageStatus::Failed {
+                    error: Error::AttachmentsTooLarge(email.attachments_size()).to_string(),
+                };
+                continue;
+            }
+            to_send.push(i);
+        }
+
+        for chunk in to_send.chunks(MAX_BATCH_SIZE) {
+            let request: Vec<_> = chunk
+                .iter()
+                .map(|i| emails[*i].request(&self.senders))
+                .collect();
+
+            let response: Vec<BatchResponseItem> = match self
+                .call(async {
+                    self.http_client
+                        .post(format!("{}/email/batch", self.base_url))
+                        .header("X-Postmark-Server-Token", self.token.expose_secret())
+                        .json(&request)
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await
+                })
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::warn!("Batch of {} emails was not sent {:?}", chunk.len(), e);
+                    for i in chunk {
+                        statuses[*i] = MessageStatus::Failed {
+                            error: e.to_string(),
+                        };
+                    }
+                    continue;
+                }
+            };
+
+            for (n, i) in chunk.iter().enumerate() {
+                statuses[*i] = match response.get(n) {
+                    Some(item) if item.error_code == 0 => MessageStatus::Sent {
+                        message_id: item.message_id.clone(),
+                    },
+                    Some(item) => MessageStatus::Failed {
+                        error: format!("{}: {}", item.error_code, item.message),
+                    },
+                    None => MessageStatus::Failed {
+                        error: "Missing in batch response".into(),
+                    },
+                };
+            }
+        }
+        Ok(statuses)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use claims::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::Fake;
+    use secrecy::Secret;
+    use sqlx::PgPool;
+    use wiremock::matchers::{body_json, body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::{
+        EmailClient, EmailMessage, Error, MessageStatus, Sender, Senders, MAX_ATTACHMENTS_SIZE,
+        MAX_BATCH_SIZE,
+    };
+    use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+    use crate::domains::subscriber_email::SubscriberEmail;
+    use crate::domains::suppressions::{suppress, SuppressionReason};
+    use crate::throttle::Throttle;
+
+    #[tokio::test]
+    async fn send_email_200() {
+        let (server, client) = setup().await;
+
+        let request = get_email_message();
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(header("Content-Type", "application/json"))
+            .and(header("X-Postmark-Server-Token", "secret"))
+            .and(body_json(request_body(&client, &request)))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client.send(&request).await;
+
+        assert_ok!(res);
+    }
+
+    #[tokio::test]
+    async fn send_email_with_options() {
+        let (server, client) = setup().await;
+        let request = get_email_message()
+            .reply_to(&SubscriberEmail::parse("support@gmail.com").unwrap())
+            .cc(&SubscriberEmail::parse("petr@gmail.com").unwrap())
+            .header("List-Unsubscribe", "<https://blog.com/unsubscribe>")
+            .tag("newsletter")
+            .attachment("hello.txt", "text/plain", b"hello".to_vec());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "ReplyTo": "support@gmail.com",
+                "Cc": "petr@gmail.com",
+                "Headers": [
+                    { "Name": "List-Unsubscribe", "Value": "<https://blog.com/unsubscribe>" }
+                ],
+                "Tag": "newsletter",
+                "Attachments": [
+                    { "Name": "hello.txt", "ContentType": "text/plain", "Content": "aGVsbG8=" }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert_ok!(client.send(&request).await);
+    }
+
+    #[tokio::test]
+    async fn too_large_attachments_are_refused() {
+        let (server, client) = setup().await;
+        let request = get_email_message().attachment(
+            "large.bin",
+            "application/octet-stream",
+            vec![0; MAX_ATTACHMENTS_SIZE + 1],
+        );
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let res = client.send(&request).await;
+        assert!(matches!(res, Err(Error::AttachmentsTooLarge(_))));
+        let statuses = client.with_batch_api().send_many(&[request]).await.unwrap();
+        assert!(matches!(statuses[0], MessageStatus::Failed { .. }));
+    }
+
+    #[tokio::test]
+    async fn send_email_500() {
+        let (server, client) = setup().await;
+
+        let request = get_email_message();
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(header("Content-Type", "application/json"))
+            .and(header("X-Postmark-Server-Token", "secret"))
+            .and(body_json(request_body(&client, &request)))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client.send(&request).await;
+
+        assert_err!(res);
+    }
+
+    #[tokio::test]
+    async fn send_email_timeout() {
+        let (server, client) = setup().await;
+
+        let request = get_email_message();
+
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(120));
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(header("Content-Type", "application/json"))
+            .and(header("X-Postmark-Server-Token", "secret"))
+            .and(body_json(request_body(&client, &request)))
+            .respond_with(response)
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client.send(&request).await;
+
+        assert_err!(res);
+    }
+
+    #[sqlx::test]
+    async fn suppressed_recipient_is_refused(pool: PgPool) {
+        let (server, client) = setup().await;
+        let client = client.with_suppression_list(pool.clone());
+        let request = get_email_message();
+        suppress(request.to().as_ref(), SuppressionReason::Bounced, &pool)
+            .await
+            .unwrap();
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let res = client.send(&request).await;
+
+        assert!(matches!(res, Err(Error::Suppressed(_))));
+    }
+
+    fn outgoing(to: &str) -> EmailMessage {
+        EmailMessage::new(
+            &SubscriberEmail::parse(to).unwrap(),
+            &Sentence(1..2).fake::<String>(),
+            &Paragraph(1..3).fake::<String>(),
+            &Paragraph(1..3).fake::<String>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_batch_returns_status_per_message() {
+        let (server, client) = setup().await;
+        let client = client.with_batch_api();
+        let emails = vec![outgoing("tom@gmail.com"), outgoing("bad@gmail.com")];
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .and(header("X-Postmark-Server-Token", "secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {
+                    "ErrorCode": 0,
+                    "Message": "OK",
+                    "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+                    "SubmittedAt": "2023-07-17T12:01:35.1234567-04:00",
+                    "To": "tom@gmail.com"
+                },
+                {
+                    "ErrorCode": 406,
+                    "Message": "You tried to send to a recipient that has been marked as inactive."
+                }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client.send_batch(&emails).await.unwrap();
+
+        assert_eq!(
+            res,
+            vec![
+                MessageStatus::Sent {
+                    message_id: Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817".into())
+                },
+                MessageStatus::Failed {
+                    error:
+                        "406: You tried to send to a recipient that has been marked as inactive."
+                            .into()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_batch_is_split_by_max_size() {
+        let (server, client) = setup().await;
+        let emails: Vec<EmailMessage> = (0..MAX_BATCH_SIZE + 1)
+            .map(|i| outgoing(&format!("user{}@gmail.com", i)))
+            .collect();
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let res = client.send_batch(&emails).await.unwrap();
+        assert_eq!(res.len(), MAX_BATCH_SIZE + 1);
+    }
+
+    #[tokio::test]
+    async fn send_many_runs_up_to_max_in_flight_at_once() {
+        let (server, client) = setup().await;
+        let client = client.with_throttle(Throttle::new(1000, 5));
+        let emails: Vec<EmailMessage> = (0..10)
+            .map(|i| outgoing(&format!("user{}@gmail.com", i)))
+            .collect();
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(50)))
+            .expect(10)
+            .mount(&server)
+            .await;
+
+        let start = Instant::now();
+        let res = client.send_many(&emails).await.unwrap();
+
+        assert!(res.iter().all(|s| matches!(s, MessageStatus::Sent { .. })));
+        // Two rounds of five requests rather than ten one by one.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn open_circuit_fails_fast() {
+        let (server, client) = setup().await;
+        let client =
+            client.with_circuit_breaker(CircuitBreaker::new(1.0, 2, Duration::from_secs(60)));
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        for _ in 0..2 {
+            let request = get_email_message();
+            let res = client.send(&request).await;
+            assert!(matches!(res, Err(Error::RequestError(_))));
+        }
+        assert_eq!(client.circuit_state(), CircuitState::Open);
+
+        let request = get_email_message();
+        let res = client.send(&request).await;
+        assert!(matches!(res, Err(Error::CircuitOpen)));
+    }
+
+    #[tokio::test]
+    async fn send_batch_500() {
+        let (server, client) = setup().await;
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client
+            .send_batch(&[outgoing("tom@gmail.com")])
+            .await
+            .unwrap();
+        assert!(matches!(res[0], MessageStatus::Failed { .. }));
+    }
+
+    #[tokio::test]
+    async fn failed_batch_keeps_statuses_of_sent_ones() {
+        let (server, client) = setup().await;
+        let client = client.with_batch_api();
+        let emails: Vec<EmailMessage> = (0..MAX_BATCH_SIZE + 1)
+            .map(|i| outgoing(&format!("user{}@gmail.com", i)))
+            .collect();
+        let accepted: Vec<_> = (0..MAX_BATCH_SIZE)
+            .map(|_| serde_json::json!({ "ErrorCode": 0, "Message": "OK", "MessageID": "id" }))
+            .collect();
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(accepted))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client.send_many(&emails).await.unwrap();
+
+        assert!(res[..MAX_BATCH_SIZE]
+            .iter()
+            .all(|s| matches!(s, MessageStatus::Sent { .. })));
+        assert!(matches!(res[MAX_BATCH_SIZE], MessageStatus::Failed { .. }));
+    }
+
+    #[sqlx::test]
+    async fn send_batch_skips_suppressed_recipients(pool: PgPool) {
+        let (server, client) = setup().await;
+        let client = client.with_suppression_list(pool.clone());
+        suppress("Tom@gmail.com", SuppressionReason::Bounced, &pool)
+            .await
+            .unwrap();
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                { "ErrorCode": 0, "Message": "OK", "MessageID": "id" }
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let res = client
+            .send_batch(&[outgoing("tom@gmail.com"), outgoing("petr@gmail.com")])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            res,
+            vec![
+                MessageStatus::Suppressed,
+                MessageStatus::Sent {
+                    message_id: Some("id".into())
+                }
+            ]
+        );
+        let request = &server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["To"], "petr@gmail.com");
+    }
+
+    fn get_email_message() -> EmailMessage {
+        outgoing(&SafeEmail().fake::<String>())
+    }
+
+    fn request_body(client: &EmailClient, message: &EmailMessage) -> serde_json::Value {
+        serde_json::json!(message.request(&client.senders))
+    }
+
+    async fn setup() -> (MockServer, EmailClient) {
+        let server = MockServer::start().await;
+
+        let client = EmailClient::new(
+            server.uri(),
+            Senders::single(Sender::from_address(&SafeEmail().fake::<String>()).unwrap()),
+            100u64,
+            Secret::new("secret".to_owned()),
+        );
+        (server, client)
+    }
+}
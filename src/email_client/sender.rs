@@ -0,0 +1,125 @@
+use std::fmt;
+
+use validator::validate_email;
+
+/// Which configured sender an email is sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Identity {
+    Newsletter,
+    #[default]
+    Transactional,
+    Security,
+}
+
+/// Valid email address of a sender, checked when the configuration is loaded.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct SenderAddress(String);
+
+impl TryFrom<String> for SenderAddress {
+    type Error = String;
+
+    fn try_from(address: String) -> Result<Self, Self::Error> {
+        if validate_email(&address) {
+            Ok(Self(address))
+        } else {
+            Err(format!("{} is not a valid sender address.", address))
+        }
+    }
+}
+
+impl AsRef<str> for SenderAddress {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Sender {
+    /// Display name, e.g. `Zero2Prod Newsletter`.
+    pub name: String,
+    pub address: SenderAddress,
+    #[serde(default)]
+    pub reply_to: Option<SenderAddress>,
+}
+
+impl Sender {
+    /// Sender only with an address, for tests.
+    pub fn from_address(address: &str) -> Result<Self, String> {
+        Ok(Self {
+            name: String::new(),
+            address: address.to_owned().try_into()?,
+            reply_to: None,
+        })
+    }
+}
+
+/// Value of the `From` header, the display name is quoted.
+impl fmt::Display for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            return write!(f, "{}", self.address.as_ref());
+        }
+        let name = self.name.replace('\\', "\\\\").replace('"', "\\\"");
+        write!(f, "\"{}\" <{}>", name, self.address.as_ref())
+    }
+}
+
+/// Sender of each identity.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Senders {
+    pub newsletter: Sender,
+    pub transactional: Sender,
+    pub security: Sender,
+}
+
+impl Senders {
+    /// The same sender for every identity.
+    pub fn single(sender: Sender) -> Self {
+        Self {
+            newsletter: sender.clone(),
+            transactional: sender.clone(),
+            security: sender,
+        }
+    }
+
+    pub fn get(&self, identity: Identity) -> &Sender {
+        match identity {
+            Identity::Newsletter => &self.newsletter,
+            Identity::Transactional => &self.transactional,
+            Identity::Security => &self.security,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use claims::assert_err;
+
+    use super::{Sender, Senders};
+
+    #[test]
+    fn invalid_address_is_rejected() {
+        let senders = serde_json::from_value::<Senders>(serde_json::json!({
+            "newsletter": { "name": "Newsletter", "address": "newsletter@gmail.com" },
+            "transactional": { "name": "Zero2Prod", "address": "not an address" },
+            "security": { "name": "Security", "address": "security@gmail.com" }
+        }));
+
+        assert_err!(senders);
+    }
+
+    #[test]
+    fn display_name_is_quoted() {
+        let sender = Sender {
+            name: "Tom \"The\" Editor".into(),
+            ..Sender::from_address("tom@gmail.com").unwrap()
+        };
+
+        assert_eq!(
+            sender.to_string(),
+            r#""Tom \"The\" Editor" <tom@gmail.com>"#
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use std::future::Future;
+
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpResponse, ResponseError};
+use tracing_actix_web::RequestId;
+
+use crate::bot_protection::{BotCheckError, BotRejection};
+use crate::domains::subscriber::SubscriptionStatus;
+use crate::domains::subscribers::{InsertError, TransitionError};
+use crate::import::ImportError;
+use crate::problem::Problem;
+use crate::rate_limit::Usage;
+use crate::subscription_policy::Rejection;
+
+tokio::task_local! {
+    /// Id of the request being handled, as recorded by the tracing logger.
+    static REQUEST_ID: RequestId;
+}
+
+/// Error of any route, the response is a problem detail with a stable `code`
+/// and the `correlation_id` of the request.
+///
+/// Only the message of the error is sent to clients, unexpected errors are
+/// logged with their whole chain and answered without any detail.
+#[derive(thiserror::Error, Debug)]
+pub enum AppError {
+    #[error("{0}")]
+    MalformedRequest(String),
+    #[error(transparent)]
+    ValidationErrors(#[from] validator::ValidationErrors),
+    #[error(transparent)]
+    Rejected(#[from] Rejection),
+    #[error(transparent)]
+    Bot(#[from] BotRejection),
+    #[error("Unauthorized access.")]
+    Unauthorized,
+    #[error("The token is unknown.")]
+    InvalidToken,
+    #[error("{0} not found.")]
+    NotFound(&'static str),
+    #[error("Issue is not a draft.")]
+    NotDraft,
+    #[error("Issue is not scheduled.")]
+    NotScheduled,
+    #[error("Subscription is {0}.")]
+    SubscriptionStatus(SubscriptionStatus),
+    #[error("Address is already subscribed.")]
+    AlreadySubscribed,
+    #[error("Too many requests, try again in {} seconds.", .0.reset_after)]
+    RateLimited(Usage),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl AppError {
+    /// Code clients can tell errors apart by, it does not change.
+    pub fn code(&self) -> &'static str {
+        use AppError::*;
+        match self {
+            MalformedRequest(_) => "malformed_request",
+            ValidationErrors(_) => "invalid_fields",
+            Rejected(_) => "subscription_rejected",
+            Bot(_) => "bot_suspected",
+            Unauthorized => "unauthorized",
+            InvalidToken => "invalid_token",
+            NotFound(_) => "not_found",
+            NotDraft => "issue_not_draft",
+            NotScheduled => "issue_not_scheduled",
+            SubscriptionStatus(_) => "subscription_status_conflict",
+            AlreadySubscribed => "already_subscribed",
+            RateLimited(_) => "rate_limited",
+            UnexpectedError(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        use AppError::*;
+        match self {
+            MalformedRequest(_) => "Malformed request",
+            ValidationErrors(_) => "Invalid fields",
+            Rejected(_) | Bot(_) => "Subscription rejected",
+            Unauthorized => "Unauthorized",
+            InvalidToken => "Invalid token",
+            NotFound(_) => "Not found",
+            NotDraft | NotScheduled => "Conflicting issue status",
+            SubscriptionStatus(_) => "Conflicting subscription status",
+            AlreadySubscribed => "Already subscribed",
+            RateLimited(_) => "Too many requests",
+            UnexpectedError(_) => "Internal server error",
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        use AppError::*;
+        match self {
+            MalformedRequest(_) | ValidationErrors(_) | Rejected(_) | Bot(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Unauthorized | InvalidToken => StatusCode::UNAUTHORIZED,
+            NotFound(_) => StatusCode::NOT_FOUND,
+            NotDraft | NotScheduled | SubscriptionStatus(_) | AlreadySubscribed => {
+                StatusCode::CONFLICT
+            }
+            RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut problem = Problem::new(self.status_code(), self.title()).with("code", self.code());
+        if let Ok(request_id) = REQUEST_ID.try_with(|id| id.to_string()) {
+            problem = problem.with("correlation_id", request_id);
+        }
+        problem = match self {
+            AppError::ValidationErrors(errors) => problem.with_field_errors(errors),
+            AppError::Rejected(reason) => problem
+                .with_detail(&reason.to_string())
+                .with("reason", reason),
+            AppError::Bot(reason) => problem
+                .with_detail(&reason.to_string())
+                .with("reason", reason),
+            AppError::UnexpectedError(e) => {
+                tracing::error!("Request failed: {:?}", e);
+                problem
+            }
+            _ => problem.with_detail(&self.to_string()),
+        };
+
+        let mut response = problem.response();
+        match self {
+            AppError::RateLimited(usage) => usage.insert_headers(response.headers_mut()),
+            AppError::Unauthorized => {
+                response.headers_mut().insert(
+                    actix_web::http::header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic"),
+                );
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+impl From<ImportError> for AppError {
+    fn from(e: ImportError) -> Self {
+        match e {
+            ImportError::MissingConsentSource => {
+                let mut errors = validator::ValidationErrors::new();
+                errors.add(
+                    "consent_source",
+                    validator::ValidationError::new("required"),
+                );
+                errors.into()
+            }
+            ImportError::MissingColumn(_) => AppError::MalformedRequest(e.to_string()),
+            ImportError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<BotCheckError> for AppError {
+    fn from(e: BotCheckError) -> Self {
+        match e {
+            BotCheckError::Rejected(rejection) => rejection.into(),
+            BotCheckError::UnexpectedError(e) => e.into(),
+        }
+    }
+}
+
+impl From<TransitionError> for AppError {
+    fn from(e: TransitionError) -> Self {
+        match e {
+            TransitionError::NotFound => AppError::NotFound("Subscriber"),
+            TransitionError::NotAllowed { from, .. } => AppError::SubscriptionStatus(from),
+            TransitionError::Database(e) => anyhow::Error::from(e).into(),
+        }
+    }
+}
+
+impl From<InsertError> for AppError {
+    fn from(e: InsertError) -> Self {
+        match e {
+            InsertError::Exists => AppError::AlreadySubscribed,
+            InsertError::Database(e) => anyhow::Error::from(e).into(),
+        }
+    }
+}
+
+/// Middleware function handling the request with its id known to error
+/// responses, the id is sent in the `X-Request-Id` header too. Errors of inner
+/// middleware get their responses here, so they carry the id as well.
+pub fn with_request_id<S, B>(
+    req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<BoxBody>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: actix_web::body::MessageBody + 'static,
+{
+    let request_id = req.extensions().get::<RequestId>().copied();
+    let response = service.call(req);
+    let handle = async move {
+        match response.await {
+            Ok(mut response) => {
+                insert_request_id(response.headers_mut());
+                Ok(response.map_into_boxed_body())
+            }
+            Err(e) => {
+                let mut response = e.error_response();
+                insert_request_id(response.headers_mut());
+                Err(InternalError::from_response(e, response).into())
+            }
+        }
+    };
+    async move {
+        match request_id {
+            Some(request_id) => REQUEST_ID.scope(request_id, handle).await,
+            None => handle.await,
+        }
+    }
+}
+
+fn insert_request_id(headers: &mut HeaderMap) {
+    if let Ok(request_id) = REQUEST_ID.try_with(|id| id.to_string()) {
+        headers.insert(
+            HeaderName::from_static("x-request-id"),
+            HeaderValue::from_str(&request_id).expect("Uuid is a valid header value"),
+        );
+    }
+}
+
+/// Error of a request body or query which could not be read.
+pub fn malformed_request(e: impl std::fmt::Display) -> actix_web::Error {
+    AppError::MalformedRequest(e.to_string()).into()
+}
+
+/// Error of a path no route matches.
+pub fn not_found() -> actix_web::Error {
+    AppError::NotFound("Resource").into()
+}
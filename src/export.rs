@@ -0,0 +1,258 @@
+use std::str::FromStr;
+
+use actix_web::web::Bytes;
+use anyhow::Context;
+use futures::{Stream, TryStreamExt};
+use sqlx::PgPool;
+
+use crate::domains::subscribers::{self, SubscriptionFilter, SubscriptionRecord};
+
+/// Bytes of rows collected before they are sent on.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Fields of [`SubscriptionRecord`], the header is there even when no
+/// subscription matches.
+const CSV_HEADER: [&str; 13] = [
+    "id",
+    "email",
+    "name",
+    "status",
+    "subscribed_at",
+    "timezone",
+    "tracking_opt_out",
+    "consent_source",
+    "suppression",
+    "suppressed_at",
+    "confirmed_at",
+    "unsubscribed_at",
+    "expired_at",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// CSV with a header.
+    #[default]
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "ndjson" => Ok(Self::Ndjson),
+            other => Err(format!("{} is neither csv nor ndjson", other)),
+        }
+    }
+}
+
+/// Writes records in the format into a buffer which is taken by chunks.
+struct Encoder {
+    format: ExportFormat,
+    csv: csv::Writer<Vec<u8>>,
+    ndjson: Vec<u8>,
+}
+
+fn csv_writer() -> csv::Writer<Vec<u8>> {
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![])
+}
+
+impl Encoder {
+    fn new(format: ExportFormat) -> anyhow::Result<Self> {
+        let mut csv = csv_writer();
+        if format == ExportFormat::Csv {
+            csv.write_record(CSV_HEADER)?;
+        }
+        Ok(Self {
+            format,
+            csv,
+            ndjson: vec![],
+        })
+    }
+
+    fn write(&mut self, record: &SubscriptionRecord) -> anyhow::Result<()> {
+        match self.format {
+            ExportFormat::Csv => self.csv.serialize(record)?,
+            ExportFormat::Ndjson => {
+                serde_json::to_writer(&mut self.ndjson, record)?;
+                self.ndjson.push(b'\n');
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, the CSV writer keeps some more in its own buffer.
+    fn len(&self) -> usize {
+        self.csv.get_ref().len() + self.ndjson.len()
+    }
+
+    fn take(&mut self) -> anyhow::Result<Bytes> {
+        let bytes = match self.format {
+            ExportFormat::Csv => std::mem::replace(&mut self.csv, csv_writer()).into_inner()?,
+            ExportFormat::Ndjson => std::mem::take(&mut self.ndjson),
+        };
+        Ok(Bytes::from(bytes))
+    }
+}
+
+/// Subscriptions matching the filter in the format, sent in chunks as they
+/// are read from the database. Only one chunk is held in memory at a time.
+pub fn export(
+    filter: SubscriptionFilter,
+    format: ExportFormat,
+    pool: PgPool,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + 'static {
+    async_stream::try_stream! {
+        let mut encoder = Encoder::new(format)?;
+        let mut records = subscribers::stream(&filter, &pool);
+        let mut exported = 0u64;
+        while let Some(record) = records
+            .try_next()
+            .await
+            .context("Failed to read subscriptions to export.")?
+        {
+            encoder.write(&record)?;
+            exported += 1;
+            if encoder.len() >= CHUNK_SIZE {
+                yield encoder.take()?;
+            }
+        }
+        let rest = encoder.take()?;
+        if !rest.is_empty() {
+            yield rest;
+        }
+        tracing::info!("Exported {} subscriptions", exported);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use sqlx::PgPool;
+
+    use super::{export, ExportFormat, CSV_HEADER};
+    use crate::domains::subscriber::{ChangedBy, NewSubscriber, SubscriptionStatus};
+    use crate::domains::subscribers::{insert_subscriber, transition, SubscriptionFilter};
+
+    async fn exported(filter: SubscriptionFilter, format: ExportFormat, pool: &PgPool) -> String {
+        let chunks: Vec<_> = export(filter, format, pool.clone())
+            .try_collect()
+            .await
+            .unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    async fn add(name: &str, pool: &PgPool) {
+        let subscriber = NewSubscriber::parse(name, &format!("{}@gmail.com", name)).unwrap();
+        insert_subscriber(&subscriber, name, pool).await.unwrap();
+    }
+
+    #[sqlx::test]
+    fn csv_has_header_and_a_line_per_subscription(pool: PgPool) {
+        add("tom", &pool).await;
+        add("petr", &pool).await;
+
+        let csv = exported(SubscriptionFilter::default(), ExportFormat::Csv, &pool).await;
+
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("id,email,name,status,subscribed_at,"));
+        assert!(lines[1].contains(",tom@gmail.com,tom,pending_confirmation,"));
+        assert!(lines[2].contains(",petr@gmail.com,petr,"));
+    }
+
+    #[sqlx::test]
+    fn ndjson_is_filtered(pool: PgPool) {
+        add("tom", &pool).await;
+        add("petr", &pool).await;
+        let filter = SubscriptionFilter {
+            search: Some("pe".into()),
+            ..Default::default()
+        };
+
+        let ndjson = exported(filter, ExportFormat::Ndjson, &pool).await;
+
+        let lines: Vec<serde_json::Value> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["email"], "petr@gmail.com");
+    }
+
+    #[sqlx::test]
+    fn status_times_come_from_history(pool: PgPool) {
+        add("tom", &pool).await;
+        let id = sqlx::query!("select id from subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .id;
+        for status in [
+            SubscriptionStatus::Confirmed,
+            SubscriptionStatus::Unsubscribed,
+        ] {
+            transition(id, status, &ChangedBy::Subscriber, &pool)
+                .await
+                .unwrap();
+        }
+
+        let ndjson = exported(SubscriptionFilter::default(), ExportFormat::Ndjson, &pool).await;
+        let csv = exported(SubscriptionFilter::default(), ExportFormat::Csv, &pool).await;
+
+        let record: serde_json::Value = serde_json::from_str(ndjson.trim()).unwrap();
+        assert!(record["confirmed_at"].is_string());
+        assert!(record["unsubscribed_at"].is_string());
+        assert!(record["expired_at"].is_null());
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let header = reader.headers().unwrap().clone();
+        let row = reader.records().next().unwrap().unwrap();
+        assert_eq!(header.len(), row.len());
+        let mut columns: Vec<_> = header.iter().collect();
+        columns.sort();
+        let keys: Vec<_> = record
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(columns, keys);
+        let value = |column: &str| &row[header.iter().position(|c| c == column).unwrap()];
+        assert!(!value("confirmed_at").is_empty());
+        assert!(value("expired_at").is_empty());
+    }
+
+    #[sqlx::test]
+    fn empty_export_has_only_header(pool: PgPool) {
+        assert_eq!(
+            exported(SubscriptionFilter::default(), ExportFormat::Csv, &pool).await,
+            format!("{}\n", CSV_HEADER.join(","))
+        );
+        assert_eq!(
+            exported(SubscriptionFilter::default(), ExportFormat::Ndjson, &pool).await,
+            ""
+        );
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use anyhow::Context;
+use csv_core::{ReadRecordResult, Reader};
+use sqlx::PgPool;
+use uuid::Uuid;
+use validator::ValidationErrors;
+
+use crate::delivery::confirmation_email;
+use crate::domains::subscriber::NewSubscriber;
+use crate::domains::subscriber::SubscriptionStatus;
+use crate::domains::subscribers::{exists, insert_imported_subscriber, InsertError};
+use crate::domains::{queued_emails, suppressions::is_suppressed};
+use crate::links::TrackingLinks;
+
+/// Status imported subscribers start with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialStatus {
+    /// Each subscriber gets a confirmation email, as when subscribing.
+    Pending,
+    /// Subscribers gave consent elsewhere, its source is kept.
+    Confirmed,
+}
+
+impl FromStr for InitialStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "confirmed" => Ok(Self::Confirmed),
+            other => Err(format!("{} is neither pending nor confirmed", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub status: InitialStatus,
+    /// Where confirmed subscribers gave their consent, e.g. the name of the
+    /// list they come from.
+    pub consent_source: Option<String>,
+    /// Only check the rows, nothing is saved and no email is sent.
+    pub dry_run: bool,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImportError {
+    #[error("Subscribers imported as confirmed need a consent source.")]
+    MissingConsentSource,
+    #[error("The header has no {0} column.")]
+    MissingColumn(&'static str),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// Why a row was not imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowErrorKind {
+    /// Email, name or timezone do not pass validation.
+    Invalid,
+    /// The address is on an earlier row of the file.
+    Duplicate,
+    /// The address already has a subscription.
+    Exists,
+    /// The address bounced or complained before.
+    Suppressed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RowError {
+    /// Number of the row, the header is row 1.
+    pub row: u64,
+    pub email: String,
+    pub error: RowErrorKind,
+    pub detail: String,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// Rows without the header.
+    pub rows: u64,
+    /// Rows saved, or which would be saved in a dry run.
+    pub imported: u64,
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    /// Errors as CSV with a header, one row of the import per line. The
+    /// header is there even without any errors.
+    pub fn errors_csv(&self) -> Vec<u8> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(vec![]);
+        writer
+            .write_record(["row", "email", "error", "detail"])
+            .expect("Writing to memory does not fail");
+        for error in &self.errors {
+            writer
+                .serialize(error)
+                .expect("Writing to memory does not fail");
+        }
+        writer
+            .into_inner()
+            .expect("Writing to memory does not fail")
+    }
+}
+
+/// Splits CSV fed in chunks into records, so the input is never read whole.
+pub struct CsvDecoder {
+    reader: Reader,
+    output: Vec<u8>,
+    output_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+}
+
+impl Default for CsvDecoder {
+    fn default() -> Self {
+        Self {
+            reader: Reader::new(),
+            output: vec![0; 1024],
+            output_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+        }
+    }
+}
+
+impl CsvDecoder {
+    /// Records the chunk completes, the rest is kept for the next chunk.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Vec<String>> {
+        // An empty input ends the CSV.
+        if chunk.is_empty() {
+            return vec![];
+        }
+        self.decode(chunk)
+    }
+
+    /// The last record, when the input does not end with a new line.
+    pub fn finish(&mut self) -> Vec<Vec<String>> {
+        self.decode(&[])
+    }
+
+    fn decode(&mut self, mut input: &[u8]) -> Vec<Vec<String>> {
+        let mut records = vec![];
+        loop {
+            let (result, read, written, ends) = self.reader.read_record(
+                input,
+                &mut self.output[self.output_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            input = &input[read..];
+            self.output_len += written;
+            self.ends_len += ends;
+            match result {
+                ReadRecordResult::InputEmpty | ReadRecordResult::End => return records,
+                ReadRecordResult::OutputFull => self.output.resize(self.output.len() * 2, 0),
+                ReadRecordResult::OutputEndsFull => self.ends.resize(self.ends.len() * 2, 0),
+                ReadRecordResult::Record => {
+                    let mut start = 0;
+                    let record = self.ends[..self.ends_len]
+                        .iter()
+                        .map(|&end| {
+                            let field = String::from_utf8_lossy(&self.output[start..end]);
+                            start = end;
+                            field.into_owned()
+                        })
+                        .collect();
+                    records.push(record);
+                    self.output_len = 0;
+                    self.ends_len = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Positions of the columns read from the header.
+#[derive(Debug)]
+struct Columns {
+    email: usize,
+    name: usize,
+    timezone: Option<usize>,
+}
+
+impl Columns {
+    fn parse(header: &[String]) -> Result<Self, ImportError> {
+        let position = |column: &str| {
+            header.iter().position(|h| {
+                // Spreadsheets start files with a byte order mark.
+                h.trim_start_matches('\u{feff}')
+                    .trim()
+                    .eq_ignore_ascii_case(column)
+            })
+        };
+        Ok(Self {
+            email: position("email").ok_or(ImportError::MissingColumn("email"))?,
+            name: position("name").ok_or(ImportError::MissingColumn("name"))?,
+            timezone: position("timezone"),
+        })
+    }
+}
+
+/// Import of subscribers fed with records of a CSV with `email`, `name` and
+/// optionally `timezone` columns, the first record is the header.
+///
+/// Rows are saved one by one, a row which fails does not stop the import but
+/// is put into the report.
+pub struct Import<'a> {
+    options: ImportOptions,
+    links: &'a TrackingLinks,
+    pool: &'a PgPool,
+    columns: Option<Columns>,
+    seen: HashSet<String>,
+    report: ImportReport,
+}
+
+impl<'a> Import<'a> {
+    pub fn new(
+        options: ImportOptions,
+        links: &'a TrackingLinks,
+        pool: &'a PgPool,
+    ) -> Result<Self, ImportError> {
+        let has_consent_source = options
+            .consent_source
+            .as_deref()
+            .is_some_and(|source| !source.trim().is_empty());
+        if options.status == InitialStatus::Confirmed && !has_consent_source {
+            return Err(ImportError::MissingConsentSource);
+        }
+        Ok(Self {
+            report: ImportReport {
+                dry_run: options.dry_run,
+                ..Default::default()
+            },
+            options,
+            links,
+            pool,
+            columns: None,
+            seen: HashSet::new(),
+        })
+    }
+
+    pub async fn add(&mut self, record: Vec<String>) -> Result<(), ImportError> {
+        let columns = match &self.columns {
+            Some(columns) => columns,
+            None => {
+                self.columns = Some(Columns::parse(&record)?);
+                return Ok(());
+            }
+        };
+        self.report.rows += 1;
+        let row = self.report.rows + 1;
+        let field = |i: usize| record.get(i).map(String::as_str).unwrap_or_default();
+        let email = field(columns.email).to_owned();
+        let subscriber =
+            match NewSubscriber::parse(field(columns.name), &email).and_then(|subscriber| {
+                match columns.timezone {
+                    Some(timezone) => subscriber.with_timezone(field(timezone)),
+                    None => Ok(subscriber),
+                }
+            }) {
+                Ok(subscriber) => subscriber,
+                Err(errors) => {
+                    let detail = invalid_fields(&errors);
+                    self.reject(row, email, RowErrorKind::Invalid, &detail);
+                    return Ok(());
+                }
+            };
+
+        let address = subscriber.email().as_ref().to_lowercase();
+        if !self.seen.insert(address.clone()) {
+            self.reject(
+                row,
+                email,
+                RowErrorKind::Duplicate,
+                "Address is on an earlier row.",
+            );
+            return Ok(());
+        }
+        if exists(&address, self.pool)
+            .await
+            .context("Failed to check existing subscriptions.")?
+        {
+            self.reject(
+                row,
+                email,
+                RowErrorKind::Exists,
+                "Address is already subscribed.",
+            );
+            return Ok(());
+        }
+        if is_suppressed(&address, self.pool)
+            .await
+            .context("Failed to check suppression list.")?
+        {
+            self.reject(
+                row,
+                email,
+                RowErrorKind::Suppressed,
+                "Address must not get emails.",
+            );
+            return Ok(());
+        }
+
+        // The address may have subscribed since it was checked.
+        if !self.options.dry_run && !self.save(&subscriber).await? {
+            self.reject(
+                row,
+                email,
+                RowErrorKind::Exists,
+                "Address is already subscribed.",
+            );
+            return Ok(());
+        }
+        self.report.imported += 1;
+        Ok(())
+    }
+
+    /// Save the subscriber, returns `false` when the address is subscribed
+    /// already.
+    async fn save(&self, subscriber: &NewSubscriber) -> Result<bool, ImportError> {
+        let conf_token = Uuid::new_v4().to_string();
+        match self.options.status {
+            InitialStatus::Pending => {
+                let link = self.links.confirmation(&conf_token);
+                let email = confirmation_email(subscriber, &link, self.pool).await;
+                // The outbox worker sends the email once the subscriber is saved.
+                let mut transaction = self
+                    .pool
+                    .begin()
+                    .await
+                    .context("Failed to begin transaction.")?;
+                match insert_imported_subscriber(
+                    subscriber,
+                    &conf_token,
+                    SubscriptionStatus::PendingConfirmation,
+                    None,
+                    &mut transaction,
+                )
+                .await
+                {
+                    Err(InsertError::Exists) => return Ok(false),
+                    res => res.context("Failed to insert subscriber.")?,
+                }
+                queued_emails::enqueue(&email, &mut transaction)
+                    .await
+                    .context("Failed to queue confirmation email.")?;
+                transaction
+                    .commit()
+                    .await
+                    .context("Failed to commit imported subscriber.")?;
+            }
+            InitialStatus::Confirmed => {
+                let consent_source = self.options.consent_source.as_deref().unwrap_or_default();
+                match insert_imported_subscriber(
+                    subscriber,
+                    &conf_token,
+                    SubscriptionStatus::Confirmed,
+                    Some(consent_source.trim()),
+                    self.pool,
+                )
+                .await
+                {
+                    Err(InsertError::Exists) => return Ok(false),
+                    res => res.context("Failed to insert subscriber.")?,
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn reject(&mut self, row: u64, email: String, error: RowErrorKind, detail: &str) {
+        self.report.errors.push(RowError {
+            row,
+            email,
+            error,
+            detail: detail.into(),
+        });
+    }
+
+    /// Report of the import, a CSV without any rows has no header either.
+    pub fn finish(self) -> Result<ImportReport, ImportError> {
+        if self.columns.is_none() {
+            return Err(ImportError::MissingColumn("email"));
+        }
+        tracing::info!(
+            "Imported {} of {} subscribers{}",
+            self.report.imported,
+            self.report.rows,
+            if self.options.dry_run {
+                " in a dry run"
+            } else {
+                ""
+            }
+        );
+        Ok(self.report)
+    }
+}
+
+/// Names of the fields which are not valid, e.g. `Invalid email, name.`.
+fn invalid_fields(errors: &ValidationErrors) -> String {
+    let mut fields: Vec<_> = errors.errors().keys().copied().collect();
+    fields.sort_unstable();
+    format!("Invalid {}.", fields.join(", "))
+}
+
+/// Import subscribers of the CSV file, as the `import` command does.
+pub async fn import_file(
+    path: &std::path::Path,
+    options: ImportOptions,
+    links: &TrackingLinks,
+    pool: &PgPool,
+) -> Result<ImportReport, ImportError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}.", path.display()))?;
+    let mut import = Import::new(options, links, pool)?;
+    let mut decoder = CsvDecoder::default();
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut chunk)
+            .await
+            .with_context(|| format!("Failed to read {}.", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        for record in decoder.feed(&chunk[..read]) {
+            import.add(record).await?;
+        }
+    }
+    for record in decoder.finish() {
+        import.add(record).await?;
+    }
+    import.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+    use sqlx::PgPool;
+
+    use super::{CsvDecoder, Import, ImportError, ImportOptions, InitialStatus, RowErrorKind};
+    use crate::domains::subscriber::NewSubscriber;
+    use crate::domains::subscribers::insert_subscriber;
+    use crate::links::TrackingLinks;
+
+    fn decode(chunks: &[&str]) -> Vec<Vec<String>> {
+        let mut decoder = CsvDecoder::default();
+        let mut records: Vec<_> = chunks
+            .iter()
+            .flat_map(|chunk| decoder.feed(chunk.as_bytes()))
+            .collect();
+        records.extend(decoder.finish());
+        records
+    }
+
+    #[test]
+    fn records_are_split_across_chunks() {
+        let records = decode(&[
+            "email,na",
+            "me\ntom@gmail.com,\"Tom, ",
+            "the \"\"cat\"\"\"\n",
+            "petr@gmail.com,Petr",
+        ]);
+
+        assert_eq!(
+            records,
+            vec![
+                vec!["email", "name"],
+                vec!["tom@gmail.com", "Tom, the \"cat\""],
+                vec!["petr@gmail.com", "Petr"],
+            ]
+        );
+    }
+
+    #[test]
+    fn long_fields_are_decoded() {
+        let name = "a".repeat(5000);
+        let records = decode(&[&format!("name\n{}\n", name)]);
+
+        assert_eq!(records[1], vec![name]);
+    }
+
+    fn options(status: InitialStatus, dry_run: bool) -> ImportOptions {
+        ImportOptions {
+            status,
+            consent_source: Some("old list".into()),
+            dry_run,
+        }
+    }
+
+    async fn import(
+        csv: &str,
+        options: ImportOptions,
+        pool: &PgPool,
+    ) -> Result<super::ImportReport, ImportError> {
+        let links = TrackingLinks::new("http://localhost", Secret::new("secret".into()));
+        let mut import = Import::new(options, &links, pool)?;
+        for record in decode(&[csv]) {
+            import.add(record).await?;
+        }
+        import.finish()
+    }
+
+    #[sqlx::test]
+    fn rows_are_validated_and_deduplicated(pool: PgPool) {
+        let existing = NewSubscriber::parse("jan", "jan@gmail.com").unwrap();
+        insert_subscriber(&existing, "token", &pool).await.unwrap();
+        let csv = "Name,Email,Timezone\n\
+            tom,tom@gmail.com,Europe/Prague\n\
+            tom again,TOM@gmail.com,\n\
+            jan,Jan@gmail.com,\n\
+            nobody,not an email,Mars/Olympus\n";
+
+        let report = import(csv, options(InitialStatus::Confirmed, false), &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(report.rows, 4);
+        assert_eq!(report.imported, 1);
+        let errors: Vec<_> = report.errors.iter().map(|e| (e.row, e.error)).collect();
+        assert_eq!(
+            errors,
+            vec![
+                (3, RowErrorKind::Duplicate),
+                (4, RowErrorKind::Exists),
+                (5, RowErrorKind::Invalid),
+            ]
+        );
+        assert_eq!(report.errors[2].detail, "Invalid email.");
+        assert_eq!(
+            String::from_utf8(report.errors_csv())
+                .unwrap()
+                .lines()
+                .nth(3),
+            Some("5,not an email,invalid,Invalid email.")
+        );
+        let saved = sqlx::query!(
+            "select status, consent_source, timezone from subscriptions where email = 'tom@gmail.com'"
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(saved.status, "confirmed");
+        assert_eq!(saved.consent_source.as_deref(), Some("old list"));
+        assert_eq!(saved.timezone.as_deref(), Some("Europe/Prague"));
+    }
+
+    #[sqlx::test]
+    fn address_subscribed_meanwhile_is_not_saved(pool: PgPool) {
+        let links = TrackingLinks::new("http://localhost", Secret::new("secret".into()));
+        let tom = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+        insert_subscriber(&tom, "token", &pool).await.unwrap();
+
+        for status in [InitialStatus::Pending, InitialStatus::Confirmed] {
+            let import = Import::new(options(status, false), &links, &pool).unwrap();
+            assert!(!import.save(&tom).await.unwrap());
+        }
+        let queued = sqlx::query!(r#"select count(*) as "count!" from queued_emails"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(queued.count, 0);
+    }
+
+    #[sqlx::test]
+    fn pending_subscribers_get_confirmation_email(pool: PgPool) {
+        let report = import(
+            "email,name\ntom@gmail.com,tom\n",
+            options(InitialStatus::Pending, false),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.imported, 1);
+        let queued = sqlx::query!("select recipient from queued_emails")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].recipient, "tom@gmail.com");
+    }
+
+    #[sqlx::test]
+    fn dry_run_saves_nothing(pool: PgPool) {
+        let report = import(
+            "email,name\ntom@gmail.com,tom\n",
+            options(InitialStatus::Pending, true),
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.imported, 1);
+        let saved = sqlx::query!("select id from subscriptions")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(saved.is_empty());
+    }
+
+    #[sqlx::test]
+    fn header_and_consent_source_are_required(pool: PgPool) {
+        let mut no_source = options(InitialStatus::Confirmed, true);
+        no_source.consent_source = None;
+
+        assert!(matches!(
+            import("name\ntom\n", options(InitialStatus::Pending, true), &pool).await,
+            Err(ImportError::MissingColumn("email"))
+        ));
+        assert!(matches!(
+            import("email,name\n", no_source, &pool).await,
+            Err(ImportError::MissingConsentSource)
+        ));
+    }
+}
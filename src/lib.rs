@@ -1,7 +1,22 @@
 mod auth;
+pub mod bot_protection;
+pub mod circuit_breaker;
+pub mod clock;
 pub mod configuration;
+pub mod delivery;
 pub mod domains;
 pub mod email_client;
+pub mod error;
+pub mod export;
+pub mod import;
+pub mod links;
+pub mod outbox;
+pub mod problem;
+pub mod purge;
+pub mod rate_limit;
 pub mod routes;
+pub mod scheduler;
 pub mod startup;
+pub mod subscription_policy;
 pub mod telemetry;
+pub mod throttle;
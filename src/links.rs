@@ -0,0 +1,172 @@
+use hmac::{Hmac, Mac};
+use linkify::{LinkFinder, LinkKind};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Builds links of the application which are put into newsletters.
+///
+/// Click tokens are signed, so only links generated here are redirected.
+#[derive(Debug, Clone)]
+pub struct TrackingLinks {
+    base_url: String,
+    secret: Secret<String>,
+}
+
+impl TrackingLinks {
+    pub fn new(base_url: &str, secret: Secret<String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').into(),
+            secret,
+        }
+    }
+
+    /// Link confirming the subscription of the token.
+    pub fn confirmation(&self, token: &str) -> String {
+        format!("{}/subscriptions/confirm?token={}", self.base_url, token)
+    }
+
+    pub fn open_pixel(&self, recipient_id: Uuid) -> String {
+        format!("{}/tracking/open/{}", self.base_url, recipient_id)
+    }
+
+    /// Page where the recipient can opt out of tracking.
+    pub fn opt_out(&self, recipient_id: Uuid) -> String {
+        format!("{}/tracking/opt_out/{}", self.base_url, recipient_id)
+    }
+
+    pub fn click(&self, recipient_id: Uuid, link_id: i32) -> String {
+        format!(
+            "{}/r/{}",
+            self.base_url,
+            self.click_token(recipient_id, link_id)
+        )
+    }
+
+    pub fn click_token(&self, recipient_id: Uuid, link_id: i32) -> String {
+        let payload = format!("{}.{}", recipient_id.simple(), link_id);
+        let signature = hex::encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Recipient and link of a click token, `None` when it was not signed here.
+    pub fn verify_click_token(&self, token: &str) -> Option<(Uuid, i32)> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        self.mac(payload)
+            .verify_slice(&hex::decode(signature).ok()?)
+            .ok()?;
+        let (recipient_id, link_id) = payload.split_once('.')?;
+        Some((recipient_id.parse().ok()?, link_id.parse().ok()?))
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// Web links in the text, in html the `&amp;` entities are decoded.
+pub fn find_links(s: &str, html: bool) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    for link in web_links(s) {
+        let link = decode(link.as_str(), html);
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+/// Replace web links in the text by what `replace` returns for them, links
+/// for which it returns `None` are kept.
+pub fn rewrite_links(s: &str, html: bool, replace: impl Fn(&str) -> Option<String>) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut last = 0;
+    for link in web_links(s) {
+        if let Some(new) = replace(&decode(link.as_str(), html)) {
+            res.push_str(&s[last..link.start()]);
+            res.push_str(&new);
+            last = link.end();
+        }
+    }
+    res.push_str(&s[last..]);
+    res
+}
+
+fn web_links(s: &str) -> Vec<linkify::Link<'_>> {
+    let mut finder = LinkFinder::new();
+    finder.kinds(&[LinkKind::Url]);
+    finder
+        .links(s)
+        .filter(|link| {
+            link.as_str().starts_with("http://") || link.as_str().starts_with("https://")
+        })
+        .collect()
+}
+
+fn decode(link: &str, html: bool) -> String {
+    if html {
+        link.replace("&amp;", "&")
+    } else {
+        link.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links() -> TrackingLinks {
+        TrackingLinks::new("http://localhost/", Secret::new("secret".into()))
+    }
+
+    #[test]
+    fn signed_token_is_verified() {
+        let recipient_id = Uuid::new_v4();
+        let token = links().click_token(recipient_id, 7);
+        assert_eq!(links().verify_click_token(&token), Some((recipient_id, 7)));
+        assert!(links()
+            .click(recipient_id, 7)
+            .starts_with("http://localhost/r/"));
+    }
+
+    #[test]
+    fn tampered_token_is_refused() {
+        let recipient_id = Uuid::new_v4();
+        let token = links().click_token(recipient_id, 7);
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let tampered = format!("{}.8.{}", recipient_id.simple(), signature);
+        assert_eq!(links().verify_click_token(&tampered), None);
+        assert_eq!(links().verify_click_token("garbage"), None);
+
+        let other = TrackingLinks::new("http://localhost", Secret::new("other".into()));
+        assert_eq!(other.verify_click_token(&token), None);
+    }
+
+    #[test]
+    fn web_links_are_rewritten() {
+        let html =
+            r#"<a href="https://a.com/?x=1&amp;y=2">a</a> mailto:tom@gmail.com http://b.com"#;
+        assert_eq!(
+            find_links(html, true),
+            vec!["https://a.com/?x=1&y=2", "http://b.com"]
+        );
+        let rewritten = rewrite_links(html, true, |url| {
+            (url == "https://a.com/?x=1&y=2").then(|| "https://r.com/1".to_string())
+        });
+        assert_eq!(
+            rewritten,
+            r#"<a href="https://r.com/1">a</a> mailto:tom@gmail.com http://b.com"#
+        );
+    }
+
+    #[test]
+    fn text_links_are_not_decoded() {
+        let text = "Visit https://a.com/?x=1&amp;y=2.";
+        assert_eq!(find_links(text, false), vec!["https://a.com/?x=1&amp;y=2"]);
+    }
+}
@@ -1,15 +1,94 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
+use futures::TryStreamExt;
 use sqlx::PgPool;
+use tokio::io::AsyncWriteExt;
 use zero2prod::configuration::get_configuration;
+use zero2prod::domains::subscriber::SubscriptionStatus;
+use zero2prod::domains::subscribers::SubscriptionFilter;
+use zero2prod::export::{export, ExportFormat};
+use zero2prod::import::{import_file, ImportOptions, InitialStatus};
+use zero2prod::links::TrackingLinks;
+use zero2prod::purge::purge_pending;
 use zero2prod::startup::build;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
+#[derive(Parser)]
+#[command(about = "Newsletter server, runs it unless a command is given")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Import subscribers of a CSV file with email, name and timezone columns.
+    Import {
+        file: PathBuf,
+        /// Status the subscribers start with, `pending` or `confirmed`.
+        #[arg(long)]
+        status: InitialStatus,
+        /// Where confirmed subscribers gave their consent.
+        #[arg(long)]
+        consent_source: Option<String>,
+        /// Only check the rows, nothing is saved.
+        #[arg(long)]
+        dry_run: bool,
+        /// File to write rows which were not imported to, as CSV.
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Export subscribers matching the filters, as the admin API does.
+    Export {
+        /// `csv` or `ndjson`.
+        #[arg(long, default_value = "csv")]
+        format: ExportFormat,
+        /// File to write to instead of the standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        status: Option<SubscriptionStatus>,
+        #[arg(long)]
+        subscribed_after: Option<DateTime<Utc>>,
+        #[arg(long)]
+        subscribed_before: Option<DateTime<Utc>>,
+        /// List the subscribers were imported from.
+        #[arg(long)]
+        list: Option<String>,
+        /// Whether the address is on the suppression list.
+        #[arg(long)]
+        suppressed: Option<bool>,
+        /// Prefix of the email or the name.
+        #[arg(long)]
+        search: Option<String>,
+    },
+    /// Remind and purge pending subscriptions once, as the server does
+    /// periodically.
+    Purge,
+}
+
 #[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
+async fn main() -> anyhow::Result<()> {
     // logging stuff
     dotenv().ok();
-    let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
-    init_subscriber(subscriber);
+    let cli = Cli::parse();
+    // Commands may write their output to stdout.
+    if cli.command.is_none() {
+        init_subscriber(get_subscriber(
+            "zero2prod".into(),
+            "info".into(),
+            std::io::stdout,
+        ));
+    } else {
+        init_subscriber(get_subscriber(
+            "zero2prod".into(),
+            "info".into(),
+            std::io::stderr,
+        ));
+    }
     //LogTracer::init().expect("Failed to set logger for tracing.");
     //let env_filter = EnvFilter::try_from_default_env().unwrap_or(EnvFilter::new("info"));
     //let formatting_layer = BunyanFormattingLayer::new("zero2prod".into(), std::io::stdout);
@@ -21,7 +100,80 @@ async fn main() -> Result<(), std::io::Error> {
 
     let configuration = get_configuration().expect("Failed to load configuration.yaml");
     let pg_pool = PgPool::connect_lazy_with(configuration.database.with_db());
-    //sqlx::migrate!().run(<&your_pool OR &mut your_connection>).await?
-    let (server, _address) = build(pg_pool.clone(), configuration).expect("Failed to start app.");
-    server.await
+    match cli.command {
+        None => {
+            //sqlx::migrate!().run(<&your_pool OR &mut your_connection>).await?
+            let (server, _address) =
+                build(pg_pool.clone(), configuration).expect("Failed to start app.");
+            server.await?;
+        }
+        Some(Command::Import {
+            file,
+            status,
+            consent_source,
+            dry_run,
+            report,
+        }) => {
+            let links = TrackingLinks::new(
+                &configuration.application.base_url,
+                configuration.application.link_secret.clone(),
+            );
+            let options = ImportOptions {
+                status,
+                consent_source,
+                dry_run,
+            };
+            let result = import_file(&file, options, &links, &pg_pool).await?;
+            println!(
+                "{} of {} rows imported{}, {} not imported",
+                result.imported,
+                result.rows,
+                if dry_run { " (dry run)" } else { "" },
+                result.errors.len()
+            );
+            if let Some(report) = report {
+                std::fs::write(report, result.errors_csv())?;
+            }
+        }
+        Some(Command::Export {
+            format,
+            output,
+            status,
+            subscribed_after,
+            subscribed_before,
+            list,
+            suppressed,
+            search,
+        }) => {
+            let filter = SubscriptionFilter {
+                status,
+                subscribed_after,
+                subscribed_before,
+                list,
+                suppressed,
+                search,
+            };
+            let mut out: Box<dyn tokio::io::AsyncWrite + Unpin> = match output {
+                Some(path) => Box::new(tokio::fs::File::create(path).await?),
+                None => Box::new(tokio::io::stdout()),
+            };
+            let mut chunks = Box::pin(export(filter, format, pg_pool));
+            while let Some(chunk) = chunks.try_next().await? {
+                out.write_all(&chunk).await?;
+            }
+            out.flush().await?;
+        }
+        Some(Command::Purge) => {
+            let links = TrackingLinks::new(
+                &configuration.application.base_url,
+                configuration.application.link_secret.clone(),
+            );
+            let report = purge_pending(&configuration.purge, &links, Utc::now(), &pg_pool).await?;
+            println!(
+                "{} pending subscriptions reminded, {} purged",
+                report.reminded, report.purged
+            );
+        }
+    }
+    Ok(())
 }
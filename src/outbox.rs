@@ -0,0 +1,236 @@
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+
+use crate::{
+    circuit_breaker::CircuitState,
+    domains::queued_emails,
+    email_client::{self, EmailClient},
+};
+
+/// Attempts after which a queued email is given up.
+pub const MAX_ATTEMPTS: i32 = 8;
+
+/// Periodically send emails from the outbox.
+pub async fn run_outbox_worker(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    poll_interval: std::time::Duration,
+) {
+    loop {
+        if let Err(e) = send_queued_emails(&pool, &email_client).await {
+            tracing::error!("Failed to send queued emails {:?}", e);
+        }
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Send queued emails which are due, failed ones are tried again later with
+/// exponential backoff. Emails rejected for good or failed `MAX_ATTEMPTS`
+/// times are marked as failed. Nothing is sent while the circuit to the
+/// provider is open.
+///
+/// Returns how many emails were sent.
+#[tracing::instrument(name = "Sending queued emails", skip(pool, email_client))]
+pub async fn send_queued_emails(
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<usize> {
+    let mut sent = 0;
+    while email_client.circuit_state() != CircuitState::Open {
+        let mut transaction = pool.begin().await.context("Failed to begin transaction.")?;
+        let Some(queued) = queued_emails::next(&mut transaction)
+            .await
+            .context("Failed to get queued email.")?
+        else {
+            // Keeps emails failed on the way.
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit transaction.")?;
+            break;
+        };
+        match email_client.send(&queued.message).await {
+            Ok(()) => sent += 1,
+            Err(email_client::Error::Suppressed(to)) => {
+                tracing::warn!("Dropping queued email to suppressed {}", to);
+            }
+            // Tried again once the circuit half opens, not counted as attempt.
+            Err(email_client::Error::CircuitOpen) => break,
+            Err(e) if !e.is_permanent() && queued.attempts + 1 < MAX_ATTEMPTS => {
+                tracing::warn!("Queued email {} was not sent {:?}", queued.id, e);
+                let next_attempt_at = Utc::now() + backoff(queued.attempts);
+                queued_emails::postpone(queued.id, next_attempt_at, &mut transaction)
+                    .await
+                    .context("Failed to postpone queued email.")?;
+                transaction
+                    .commit()
+                    .await
+                    .context("Failed to commit transaction.")?;
+                continue;
+            }
+            // Kept with the error for whoever looks into it.
+            Err(e) => {
+                tracing::error!("Giving up queued email {} {:?}", queued.id, e);
+                queued_emails::fail(queued.id, &e.to_string(), &mut transaction)
+                    .await
+                    .context("Failed to fail queued email.")?;
+                transaction
+                    .commit()
+                    .await
+                    .context("Failed to commit transaction.")?;
+                continue;
+            }
+        }
+        queued_emails::remove(queued.id, &mut transaction)
+            .await
+            .context("Failed to remove queued email.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit transaction.")?;
+    }
+    Ok(sent)
+}
+
+/// Wait before the next attempt, doubled with each failed one.
+fn backoff(attempts: i32) -> Duration {
+    Duration::seconds(1 << attempts.clamp(0, 12))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use secrecy::Secret;
+    use sqlx::PgPool;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::{backoff, send_queued_emails, MAX_ATTEMPTS};
+    use crate::domains::queued_emails::enqueue;
+    use crate::domains::subscriber_email::SubscriberEmail;
+    use crate::email_client::{EmailClient, EmailMessage, Sender, Senders};
+
+    fn email(to: &str) -> EmailMessage {
+        EmailMessage::new(
+            &SubscriberEmail::parse(to).unwrap(),
+            "Subject",
+            "<p>Hi</p>",
+            "Hi",
+        )
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(0), Duration::seconds(1));
+        assert_eq!(backoff(3), Duration::seconds(8));
+    }
+
+    #[sqlx::test]
+    async fn failed_email_is_retried_until_max_attempts(pool: PgPool) {
+        let server = MockServer::start().await;
+        let client = EmailClient::new(
+            server.uri(),
+            Senders::single(Sender::from_address("sender@gmail.com").unwrap()),
+            100,
+            Secret::new("secret".into()),
+        );
+        enqueue(&email("tom@gmail.com"), &pool).await.unwrap();
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert_eq!(send_queued_emails(&pool, &client).await.unwrap(), 0);
+        let queued = sqlx::query!("select attempts from queued_emails")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(queued.attempts, 1);
+
+        // The last attempt, the email is kept as failed.
+        sqlx::query!(
+            "update queued_emails set attempts = $1, next_attempt_at = now()",
+            MAX_ATTEMPTS - 1
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        server.reset().await;
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        send_queued_emails(&pool, &client).await.unwrap();
+        let failed = sqlx::query!("select attempts, failed_at, last_error from queued_emails")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(failed.attempts, MAX_ATTEMPTS);
+        assert!(failed.failed_at.is_some());
+        assert!(failed.last_error.unwrap().contains("500"));
+    }
+
+    #[sqlx::test]
+    async fn rejected_email_is_not_retried(pool: PgPool) {
+        let server = MockServer::start().await;
+        let client = EmailClient::new(
+            server.uri(),
+            Senders::single(Sender::from_address("sender@gmail.com").unwrap()),
+            100,
+            Secret::new("secret".into()),
+        );
+        enqueue(&email("tom@gmail.com"), &pool).await.unwrap();
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        assert_eq!(send_queued_emails(&pool, &client).await.unwrap(), 0);
+        let failed = sqlx::query!("select attempts, failed_at from queued_emails")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(failed.attempts, 1);
+        assert!(failed.failed_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn unreadable_email_does_not_stop_the_outbox(pool: PgPool) {
+        let server = MockServer::start().await;
+        let client = EmailClient::new(
+            server.uri(),
+            Senders::single(Sender::from_address("sender@gmail.com").unwrap()),
+            100,
+            Secret::new("secret".into()),
+        );
+        sqlx::query!(
+            r#"
+            insert into queued_emails (id, recipient, message, created_at)
+            values (gen_random_uuid(), 'tom@gmail.com', '{}', now())
+            "#
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(send_queued_emails(&pool, &client).await.unwrap(), 0);
+        assert_eq!(send_queued_emails(&pool, &client).await.unwrap(), 0);
+        let failed = sqlx::query!(
+            r#"select count(*) as "count!" from queued_emails where failed_at is not null"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(failed.count, 1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use validator::{ValidationErrors, ValidationErrorsKind};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Error response body of RFC 7807, `application/problem+json`.
+///
+/// Members other than the standard ones are added by [`Problem::with`], e.g.
+/// `errors` with field errors of the request.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, serde_json::Value>,
+}
+
+/// Error of one field of a request.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FieldError {
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Problem {
+    /// Problem without a more specific type than its status.
+    pub fn new(status: StatusCode, title: &str) -> Self {
+        Self {
+            problem_type: "about:blank".into(),
+            title: title.into(),
+            status: status.as_u16(),
+            detail: None,
+            extensions: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with(mut self, member: &str, value: impl serde::Serialize) -> Self {
+        let value = serde_json::to_value(value).expect("Problem members serialize to JSON");
+        self.extensions.insert(member.into(), value);
+        self
+    }
+
+    /// Add errors of the fields as the `errors` member, nested fields are
+    /// joined by dots and items of lists by their index, e.g. `emails[1]`.
+    pub fn with_field_errors(self, errors: &ValidationErrors) -> Self {
+        let mut fields = BTreeMap::new();
+        collect_field_errors(errors, "", &mut fields);
+        self.with("errors", fields)
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type(PROBLEM_JSON)
+            .json(self)
+    }
+}
+
+fn collect_field_errors(
+    errors: &ValidationErrors,
+    prefix: &str,
+    fields: &mut BTreeMap<String, Vec<FieldError>>,
+) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                fields
+                    .entry(path)
+                    .or_default()
+                    .extend(errors.iter().map(|e| FieldError {
+                        code: e.code.to_string(),
+                        message: e.message.as_ref().map(|m| m.to_string()),
+                    }))
+            }
+            ValidationErrorsKind::Struct(errors) => collect_field_errors(errors, &path, fields),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_field_errors(errors, &format!("{}[{}]", path, index), fields);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+    use super::Problem;
+
+    #[test]
+    fn field_errors_are_listed_by_field() {
+        let mut errors = ValidationErrors::new();
+        errors.add("name", ValidationError::new("length"));
+        let mut email = ValidationError::new("email");
+        email.message = Some("Not an email address.".into());
+        errors.add("email", email);
+        let mut nested = ValidationErrors::new();
+        nested.add("city", ValidationError::new("required"));
+        errors.errors_mut().insert(
+            "addresses",
+            ValidationErrorsKind::List([(1, Box::new(nested))].into()),
+        );
+
+        let problem = Problem::new(StatusCode::BAD_REQUEST, "Invalid subscription")
+            .with_field_errors(&errors);
+
+        assert_eq!(
+            serde_json::to_value(problem).unwrap(),
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Invalid subscription",
+                "status": 400,
+                "errors": {
+                    "addresses[1].city": [{ "code": "required" }],
+                    "email": [{ "code": "email", "message": "Not an email address." }],
+                    "name": [{ "code": "length" }]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn response_is_problem_json() {
+        let response = Problem::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests")
+            .with_detail("Try again later.")
+            .response();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/problem+json"
+        );
+    }
+}
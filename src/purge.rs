@@ -0,0 +1,270 @@
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+
+use crate::{
+    clock::Clock,
+    configuration::{PurgeMode, PurgeSettings},
+    delivery::confirmation_reminder_email,
+    domains::{queued_emails, subscriber::NewSubscriber, subscribers},
+    links::TrackingLinks,
+};
+
+/// What one purge did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    pub reminded: usize,
+    /// Deleted or anonymized subscriptions, as the mode says.
+    pub purged: u64,
+}
+
+/// Periodically purge pending subscriptions.
+pub async fn run_purge(
+    pool: PgPool,
+    links: TrackingLinks,
+    clock: Arc<dyn Clock>,
+    settings: PurgeSettings,
+) {
+    loop {
+        if let Err(e) = purge_pending(&settings, &links, clock.now(), &pool).await {
+            tracing::error!("Failed to purge pending subscriptions {:?}", e);
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(settings.interval_seconds)).await;
+    }
+}
+
+/// Remind pending subscriptions older than the reminder period once, then
+/// delete or anonymize those older than the retention period. Subscriptions
+/// purged by this run are not reminded.
+#[tracing::instrument(name = "Purging pending subscriptions", skip(links, pool))]
+pub async fn purge_pending(
+    settings: &PurgeSettings,
+    links: &TrackingLinks,
+    now: DateTime<Utc>,
+    pool: &PgPool,
+) -> anyhow::Result<PurgeReport> {
+    let purge_before = now - Duration::days(settings.retention_days.into());
+    let reminded = match settings.reminder_after_days {
+        Some(days) => {
+            let remind_before = now - Duration::days(days.into());
+            send_reminders(purge_before, remind_before, now, links, pool).await?
+        }
+        None => 0,
+    };
+    let purged = match settings.mode {
+        PurgeMode::Delete => subscribers::delete_pending(purge_before, pool)
+            .await
+            .context("Failed to delete pending subscriptions.")?,
+        PurgeMode::Anonymize => subscribers::anonymize_pending(purge_before, pool)
+            .await
+            .context("Failed to anonymize pending subscriptions.")?,
+    };
+    tracing::info!(
+        "Reminded {} pending subscriptions, purged {} ({:?})",
+        reminded,
+        purged,
+        settings.mode
+    );
+    Ok(PurgeReport { reminded, purged })
+}
+
+/// Queue the reminder of each pending subscription made in the period.
+///
+/// Each subscription is claimed in the transaction which queues its reminder,
+/// so concurrent purges never remind it twice.
+async fn send_reminders(
+    subscribed_after: DateTime<Utc>,
+    subscribed_before: DateTime<Utc>,
+    now: DateTime<Utc>,
+    links: &TrackingLinks,
+    pool: &PgPool,
+) -> anyhow::Result<usize> {
+    let mut reminded = 0;
+    loop {
+        let mut transaction = pool.begin().await.context("Failed to begin transaction.")?;
+        let Some(subscription) = subscribers::claim_pending_to_remind(
+            subscribed_after,
+            subscribed_before,
+            &mut transaction,
+        )
+        .await
+        .context("Failed to get pending subscription to remind.")?
+        else {
+            break;
+        };
+        // Rows saved before the current validation rules get no reminder.
+        let subscriber = match NewSubscriber::parse(&subscription.name, &subscription.email) {
+            Ok(subscriber) => Some(subscriber),
+            Err(e) => {
+                tracing::warn!("Not reminding invalid subscriber {:?}", e);
+                None
+            }
+        };
+        if let (Some(subscriber), Some(token)) = (subscriber, &subscription.confirmation_token) {
+            let email =
+                confirmation_reminder_email(&subscriber, &links.confirmation(token), pool).await;
+            queued_emails::enqueue(&email, &mut transaction)
+                .await
+                .context("Failed to queue confirmation reminder.")?;
+            reminded += 1;
+        }
+        subscribers::set_reminded(subscription.id, now, &mut transaction)
+            .await
+            .context("Failed to set subscription reminded.")?;
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit reminder.")?;
+    }
+    Ok(reminded)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use secrecy::Secret;
+    use sqlx::PgPool;
+
+    use super::{purge_pending, PurgeReport};
+    use crate::configuration::{PurgeMode, PurgeSettings};
+    use crate::domains::subscriber::{ChangedBy, NewSubscriber, SubscriptionStatus};
+    use crate::domains::subscribers::{exists, insert_subscriber, transition};
+    use crate::links::TrackingLinks;
+
+    fn settings(mode: PurgeMode) -> PurgeSettings {
+        PurgeSettings {
+            retention_days: 30,
+            reminder_after_days: Some(7),
+            mode,
+            interval_seconds: 3600,
+        }
+    }
+
+    fn links() -> TrackingLinks {
+        TrackingLinks::new("http://localhost", Secret::new("secret".into()))
+    }
+
+    async fn add(name: &str, pool: &PgPool) {
+        let subscriber = NewSubscriber::parse(name, &format!("{}@gmail.com", name)).unwrap();
+        insert_subscriber(&subscriber, name, pool).await.unwrap();
+    }
+
+    async fn queued(pool: &PgPool) -> i64 {
+        sqlx::query!(r#"select count(*) as "count!" from queued_emails"#)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .count
+    }
+
+    #[sqlx::test]
+    fn pending_subscriptions_are_reminded_once(pool: PgPool) {
+        add("tom", &pool).await;
+        let settings = settings(PurgeMode::Delete);
+        let now = Utc::now();
+
+        let early = purge_pending(&settings, &links(), now + Duration::days(1), &pool)
+            .await
+            .unwrap();
+        let first = purge_pending(&settings, &links(), now + Duration::days(8), &pool)
+            .await
+            .unwrap();
+        let second = purge_pending(&settings, &links(), now + Duration::days(9), &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(early, PurgeReport::default());
+        assert_eq!(first.reminded, 1);
+        assert_eq!(second.reminded, 0);
+        assert_eq!(queued(&pool).await, 1);
+        assert!(exists("tom@gmail.com", &pool).await.unwrap());
+    }
+
+    #[sqlx::test]
+    fn concurrent_purges_remind_once(pool: PgPool) {
+        for name in ["tom", "petr", "jana", "eva"] {
+            add(name, &pool).await;
+        }
+        let settings = settings(PurgeMode::Delete);
+        let now = Utc::now() + Duration::days(8);
+        let links = links();
+
+        let (first, second) = tokio::join!(
+            purge_pending(&settings, &links, now, &pool),
+            purge_pending(&settings, &links, now, &pool)
+        );
+
+        assert_eq!(first.unwrap().reminded + second.unwrap().reminded, 4);
+        assert_eq!(queued(&pool).await, 4);
+    }
+
+    #[test]
+    fn reminder_has_to_come_before_purge() {
+        let mut settings = settings(PurgeMode::Delete);
+        assert!(settings.validate().is_ok());
+        settings.reminder_after_days = Some(30);
+        assert!(settings.validate().is_err());
+        settings.reminder_after_days = None;
+        assert!(settings.validate().is_ok());
+    }
+
+    #[sqlx::test]
+    fn stale_pending_subscriptions_are_deleted(pool: PgPool) {
+        add("tom", &pool).await;
+        add("petr", &pool).await;
+        let petr = sqlx::query!("select id from subscriptions where name = 'petr'")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .id;
+        transition(
+            petr,
+            SubscriptionStatus::Confirmed,
+            &ChangedBy::Subscriber,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let now = Utc::now() + Duration::days(31);
+        let report = purge_pending(&settings(PurgeMode::Delete), &links(), now, &pool)
+            .await
+            .unwrap();
+
+        // Too late for a reminder.
+        assert_eq!(
+            report,
+            PurgeReport {
+                reminded: 0,
+                purged: 1
+            }
+        );
+        assert_eq!(queued(&pool).await, 0);
+        assert!(!exists("tom@gmail.com", &pool).await.unwrap());
+        assert!(exists("petr@gmail.com", &pool).await.unwrap());
+    }
+
+    #[sqlx::test]
+    fn anonymized_subscriptions_free_the_address(pool: PgPool) {
+        add("tom", &pool).await;
+        let mut settings = settings(PurgeMode::Anonymize);
+        settings.reminder_after_days = None;
+
+        let now = Utc::now() + Duration::days(31);
+        let report = purge_pending(&settings, &links(), now, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(report.purged, 1);
+        assert!(!exists("tom@gmail.com", &pool).await.unwrap());
+        let saved = sqlx::query!("select name, status, confirmation_token from subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(saved.name, "");
+        assert_eq!(saved.status, "expired");
+        assert_eq!(saved.confirmation_token, None);
+        add("tom", &pool).await;
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+use actix_web::web::Data;
+use actix_web::ResponseError;
+use anyhow::Context;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use futures::future::LocalBoxFuture;
+use ipnet::IpNet;
+use sqlx::PgPool;
+
+use crate::clock::Clock;
+use crate::configuration::{RateLimit, RateLimitSettings, RateLimitStore};
+use crate::error::AppError;
+
+/// Routes sharing a limit, requests of one client to any of them are counted
+/// together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    /// Subscriptions and tracking, open to anyone.
+    Public,
+    Auth,
+    Admin,
+}
+
+impl RouteGroup {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RouteGroup::Public => "public",
+            RouteGroup::Auth => "auth",
+            RouteGroup::Admin => "admin",
+        }
+    }
+}
+
+/// Requests counted in the current window of a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub limit: u32,
+    pub count: u32,
+    /// Seconds until the window ends.
+    pub reset_after: i64,
+}
+
+impl Usage {
+    pub fn exceeded(&self) -> bool {
+        self.count > self.limit
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.limit.saturating_sub(self.count)
+    }
+
+    /// `RateLimit-*` headers, `Retry-After` too when the limit is exceeded.
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        let mut insert = |name: &'static str, value: String| {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(&value).expect("Numbers are valid header values"),
+            );
+        };
+        insert("ratelimit-limit", self.limit.to_string());
+        insert("ratelimit-remaining", self.remaining().to_string());
+        insert("ratelimit-reset", self.reset_after.to_string());
+        if self.exceeded() {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.reset_after));
+        }
+    }
+}
+
+/// Counts requests in fixed windows, in memory of this instance or in the
+/// database shared by all instances.
+#[derive(Debug)]
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    store: Store,
+}
+
+#[derive(Debug)]
+enum Store {
+    Memory(Mutex<HashMap<String, Window>>),
+    Postgres(PgPool),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    end: DateTime<Utc>,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings, pool: PgPool) -> Self {
+        let store = match settings.store {
+            RateLimitStore::Memory => Store::Memory(Mutex::new(HashMap::new())),
+            RateLimitStore::Postgres => Store::Postgres(pool),
+        };
+        Self { settings, store }
+    }
+
+    pub fn limit(&self, group: RouteGroup) -> RateLimit {
+        match group {
+            RouteGroup::Public => self.settings.public,
+            RouteGroup::Auth => self.settings.auth,
+            RouteGroup::Admin => self.settings.admin,
+        }
+    }
+
+    /// Count a subscription of the email address, case of its local part
+    /// does not matter to most mail servers so it is ignored.
+    pub async fn hit_email(&self, email: &str, now: DateTime<Utc>) -> anyhow::Result<Usage> {
+        let key = format!("email:{}", email.to_lowercase());
+        self.hit(&key, self.settings.per_email, now).await
+    }
+
+    /// Count a request of the key in the current window.
+    pub async fn hit(
+        &self,
+        key: &str,
+        limit: RateLimit,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Usage> {
+        let window_end = window_end(now, limit.window_seconds);
+        let count = match &self.store {
+            Store::Memory(windows) => {
+                let mut windows = windows.lock().unwrap();
+                let window = windows.entry(key.to_owned()).or_insert(Window {
+                    end: window_end,
+                    count: 0,
+                });
+                if window.end != window_end {
+                    *window = Window {
+                        end: window_end,
+                        count: 0,
+                    };
+                }
+                window.count += 1;
+                window.count
+            }
+            Store::Postgres(pool) => {
+                sqlx::query!(
+                    r#"insert into rate_limits (key, window_end, count) values ($1, $2, 1)
+                    on conflict (key) do update set
+                        count = case when rate_limits.window_end = excluded.window_end
+                            then rate_limits.count + 1 else 1 end,
+                        window_end = excluded.window_end
+                    returning count"#,
+                    key,
+                    window_end
+                )
+                .fetch_one(pool)
+                .await
+                .context("Failed to count request.")?
+                .count as u32
+            }
+        };
+        Ok(Usage {
+            limit: limit.requests,
+            count,
+            // Whole seconds, rounded up so a client never retries too early.
+            reset_after: ((window_end - now).num_milliseconds() + 999) / 1000,
+        })
+    }
+
+    /// Forget windows which ended.
+    pub async fn prune(&self, now: DateTime<Utc>) -> anyhow::Result<()> {
+        match &self.store {
+            Store::Memory(windows) => windows.lock().unwrap().retain(|_, w| w.end > now),
+            Store::Postgres(pool) => {
+                sqlx::query!("delete from rate_limits where window_end <= $1", now)
+                    .execute(pool)
+                    .await
+                    .context("Failed to delete ended rate limit windows.")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Address of the client, taken from `X-Forwarded-For` only when the
+    /// request came from a trusted proxy.
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        client_ip(peer, forwarded_for, &self.settings.trusted_proxies)
+    }
+}
+
+/// Periodically forget ended windows.
+pub async fn run_pruning(
+    limiter: std::sync::Arc<RateLimiter>,
+    clock: std::sync::Arc<dyn Clock>,
+    interval: std::time::Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = limiter.prune(clock.now()).await {
+            tracing::error!("Failed to prune rate limits {:?}", e);
+        }
+    }
+}
+
+/// End of the fixed window `now` falls into.
+fn window_end(now: DateTime<Utc>, window_seconds: u64) -> DateTime<Utc> {
+    let window = window_seconds.max(1) as i64;
+    let start = now.timestamp() - now.timestamp().rem_euclid(window);
+    Utc.timestamp_opt(start, 0).unwrap() + Duration::seconds(window)
+}
+
+/// The right-most address of the forwarded chain which is not a trusted
+/// proxy, addresses left of it could be made up by the client.
+fn client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut client = peer?;
+    if !is_trusted(&client) {
+        return Some(client);
+    }
+    let chain = forwarded_for.unwrap_or_default().split(',').rev();
+    for hop in chain {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// Middleware limiting requests of each client address to a route group.
+///
+/// Responses carry `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset` headers, rejected requests get 429 with `Retry-After`.
+/// Requests are let through when the store fails.
+pub struct RateLimiting {
+    group: RouteGroup,
+}
+
+impl RateLimiting {
+    pub fn new(group: RouteGroup) -> Self {
+        Self { group }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiting
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitingMiddleware {
+            service: Rc::new(service),
+            group: self.group,
+        }))
+    }
+}
+
+pub struct RateLimitingMiddleware<S> {
+    service: Rc<S>,
+    group: RouteGroup,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let group = self.group;
+        Box::pin(async move {
+            let limiter = req
+                .app_data::<Data<RateLimiter>>()
+                .expect("Rate limiter not set")
+                .clone();
+            let clock = req
+                .app_data::<Data<dyn Clock>>()
+                .expect("Clock not set")
+                .clone();
+            let forwarded_for = req
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok());
+            let usage = match limiter.client_ip(req.peer_addr().map(|a| a.ip()), forwarded_for) {
+                Some(ip) => {
+                    let key = format!("{}:{}", group.as_str(), ip);
+                    limiter
+                        .hit(&key, limiter.limit(group), clock.now())
+                        .await
+                        .map_err(|e| tracing::warn!("Failed to rate limit request {:?}", e))
+                        .ok()
+                }
+                None => None,
+            };
+
+            match usage {
+                Some(usage) if usage.exceeded() => {
+                    tracing::warn!("Rate limit of {} routes exceeded", group.as_str());
+                    Ok(req
+                        .into_response(AppError::RateLimited(usage).error_response())
+                        .map_into_right_body())
+                }
+                _ => {
+                    let mut response = service.call(req).await?;
+                    if let Some(usage) = usage {
+                        usage.insert_headers(response.headers_mut());
+                    }
+                    Ok(response.map_into_left_body())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use sqlx::PgPool;
+
+    use super::{client_ip, RateLimiter};
+    use crate::configuration::{RateLimit, RateLimitSettings, RateLimitStore};
+
+    fn limiter(store: RateLimitStore, pool: PgPool) -> RateLimiter {
+        let limit = RateLimit {
+            requests: 2,
+            window_seconds: 60,
+        };
+        RateLimiter::new(
+            RateLimitSettings {
+                store,
+                trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+                public: limit,
+                auth: limit,
+                admin: limit,
+                per_email: limit,
+            },
+            pool,
+        )
+    }
+
+    async fn counts_in_windows(limiter: RateLimiter) {
+        let start = Utc.with_ymd_and_hms(2023, 7, 25, 10, 0, 15).unwrap();
+        let limit = limiter.limit(super::RouteGroup::Public);
+
+        let mut usages = Vec::new();
+        for now in [
+            start,
+            start + Duration::seconds(10),
+            start + Duration::seconds(20),
+        ] {
+            usages.push(limiter.hit("tom", limit, now).await.unwrap());
+        }
+        let other = limiter.hit("petr", limit, start).await.unwrap();
+        let next_window = limiter
+            .hit("tom", limit, start + Duration::seconds(45))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            usages.iter().map(|u| u.remaining()).collect::<Vec<_>>(),
+            [1, 0, 0]
+        );
+        assert!(!usages[1].exceeded());
+        assert!(usages[2].exceeded());
+        assert_eq!(usages[2].reset_after, 25);
+        assert_eq!(other.count, 1);
+        assert_eq!(next_window.count, 1);
+    }
+
+    #[sqlx::test]
+    async fn memory_store_counts_in_windows(pool: PgPool) {
+        counts_in_windows(limiter(RateLimitStore::Memory, pool)).await;
+    }
+
+    #[sqlx::test]
+    async fn postgres_store_counts_in_windows(pool: PgPool) {
+        counts_in_windows(limiter(RateLimitStore::Postgres, pool)).await;
+    }
+
+    #[sqlx::test]
+    async fn ended_windows_are_pruned(pool: PgPool) {
+        let limiter = limiter(RateLimitStore::Postgres, pool.clone());
+        let now = Utc::now();
+        let limit = limiter.limit(super::RouteGroup::Admin);
+        limiter.hit("tom", limit, now).await.unwrap();
+
+        limiter.prune(now + Duration::minutes(2)).await.unwrap();
+
+        let left = sqlx::query!("select key from rate_limits")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn forwarded_address_is_trusted_only_from_proxies() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let ip = |s: &str| Some(s.parse().unwrap());
+
+        assert_eq!(
+            client_ip(ip("1.2.3.4"), Some("5.6.7.8"), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("5.6.7.8"), &trusted),
+            ip("5.6.7.8")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("9.9.9.9, 5.6.7.8, 10.0.0.2"), &trusted),
+            ip("5.6.7.8")
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), None, &trusted), ip("10.0.0.1"));
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), Some("not an address"), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -1,5 +1,20 @@
-use actix_web::{HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder};
+
+use crate::circuit_breaker::CircuitState;
+use crate::email_client::EmailClient;
 
 pub async fn health_check() -> impl Responder {
     HttpResponse::Ok().finish()
 }
+
+#[derive(serde::Serialize)]
+struct EmailProviderHealth {
+    circuit: CircuitState,
+}
+
+/// State of the circuit breaker in front of the email provider.
+pub async fn email_provider_health(email_client: web::Data<EmailClient>) -> impl Responder {
+    HttpResponse::Ok().json(EmailProviderHealth {
+        circuit: email_client.circuit_state(),
+    })
+}
@@ -0,0 +1,251 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::{
+    clock::Clock,
+    delivery::recipient_vars,
+    domains::{
+        issue::{earliest_send_at, Issue, IssueDraft, IssueStatus},
+        issues,
+        subscriber_email::SubscriberEmail,
+        subscribers::get_subscriber,
+        template::{RenderedEmail, TemplateName},
+        templates,
+        tracking::{get_click_stats, get_open_stats, LinkStats, OpenStats},
+    },
+    email_client::{EmailClient, EmailMessage},
+    error::AppError,
+};
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PreviewParams {
+    subscriber_id: Option<Uuid>,
+}
+
+#[derive(serde::Deserialize, Debug, Validate)]
+pub struct TestSendParams {
+    #[validate(length(min = 1, max = 10))]
+    emails: Vec<SubscriberEmail>,
+}
+
+/// Either a moment the issue is sent at, or a local time at which each
+/// subscriber gets it.
+#[derive(serde::Deserialize, Debug)]
+pub struct ScheduleParams {
+    send_at: Option<DateTime<Utc>>,
+    local_send_at: Option<NaiveDateTime>,
+}
+
+/// Issue with its delivery statistics.
+#[derive(serde::Serialize, Debug)]
+pub struct IssueDetail {
+    #[serde(flatten)]
+    issue: Issue,
+    opens: OpenStats,
+    clicks: Vec<LinkStats>,
+}
+
+async fn get_issue(id: Uuid, pool: &PgPool) -> Result<Issue, AppError> {
+    issues::get(id, pool)
+        .await
+        .context("Failed to get issue.")?
+        .ok_or(AppError::NotFound("Issue"))
+}
+
+async fn render_issue(issue: &Issue, pool: &PgPool) -> Result<RenderedEmail, AppError> {
+    let layout = templates::get_or_default(TemplateName::NewsletterLayout, pool).await;
+    Ok(issue.render(&layout)?)
+}
+
+#[tracing::instrument(name = "Listing issues", skip(pool))]
+pub async fn list_issues(pool: web::Data<PgPool>) -> Result<HttpResponse, AppError> {
+    let res = issues::get_all(&pool)
+        .await
+        .context("Failed to get issues.")?;
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[tracing::instrument(name = "Creating issue draft", skip(pool))]
+pub async fn create_issue(
+    params: web::Json<IssueDraft>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let issue = issues::insert_draft(&params, &pool)
+        .await
+        .context("Failed to save issue.")?;
+    Ok(HttpResponse::Created().json(issue))
+}
+
+#[tracing::instrument(name = "Getting issue", skip(pool))]
+pub async fn get_issue_detail(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let issue = get_issue(*id, &pool).await?;
+    let opens = get_open_stats(issue.id, &pool)
+        .await
+        .context("Failed to get open stats.")?;
+    let clicks = get_click_stats(issue.id, &pool)
+        .await
+        .context("Failed to get click stats.")?;
+    Ok(HttpResponse::Ok().json(IssueDetail {
+        issue,
+        opens,
+        clicks,
+    }))
+}
+
+#[tracing::instrument(name = "Updating issue draft", skip(pool))]
+pub async fn update_issue(
+    id: web::Path<Uuid>,
+    params: web::Json<IssueDraft>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    get_issue(*id, &pool).await?;
+    let issue = issues::update_draft(*id, &params, &pool)
+        .await
+        .context("Failed to update issue.")?
+        .ok_or(AppError::NotDraft)?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+#[tracing::instrument(name = "Deleting issue draft", skip(pool))]
+pub async fn delete_issue(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    get_issue(*id, &pool).await?;
+    if !issues::delete_draft(*id, &pool)
+        .await
+        .context("Failed to delete issue.")?
+    {
+        return Err(AppError::NotDraft);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(name = "Previewing issue", skip(pool))]
+pub async fn preview_issue(
+    id: web::Path<Uuid>,
+    params: web::Query<PreviewParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let issue = get_issue(*id, &pool).await?;
+    let email = render_issue(&issue, &pool).await?;
+    let email = match params.subscriber_id {
+        Some(subscriber_id) => {
+            let subscriber = get_subscriber(subscriber_id, pool.get_ref())
+                .await
+                .context("Failed to get subscriber.")?
+                .ok_or(AppError::NotFound("Subscriber"))?;
+            email.personalize(&recipient_vars(&subscriber))
+        }
+        None => email.personalize(&[("name", "Jane Doe"), ("email", "jane.doe@example.com")]),
+    };
+    Ok(HttpResponse::Ok().json(email))
+}
+
+#[tracing::instrument(name = "Sending test of issue", skip(pool, email_client))]
+pub async fn send_test_issue(
+    id: web::Path<Uuid>,
+    params: web::Json<TestSendParams>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+) -> Result<HttpResponse, AppError> {
+    params.validate()?;
+    let issue = get_issue(*id, &pool).await?;
+    let email = render_issue(&issue, &pool).await?;
+
+    for address in &params.emails {
+        let email = email.personalize(&[("name", "Jane Doe"), ("email", address.as_ref())]);
+        let message = EmailMessage::new(
+            address,
+            &format!("[TEST] {}", email.subject),
+            &email.html,
+            &email.text,
+        )
+        .sent_as(TemplateName::NewsletterLayout.identity());
+        email_client
+            .send(&message)
+            .await
+            .context("Failed to send test email.")?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Hand the issue to the scheduler to be sent right away, a failed issue is
+/// sent again to subscribers it did not reach.
+#[tracing::instrument(name = "Sending issue", skip(pool, clock))]
+pub async fn send_issue_now(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, AppError> {
+    let issue = get_issue(*id, &pool).await?;
+    if !matches!(issue.status, IssueStatus::Draft | IssueStatus::Failed) {
+        return Err(AppError::NotDraft);
+    }
+    issue.validate()?;
+    render_issue(&issue, &pool).await?;
+
+    let issue = issues::schedule(*id, clock.now(), None, &pool)
+        .await
+        .context("Failed to schedule issue.")?
+        .ok_or(AppError::NotDraft)?;
+    Ok(HttpResponse::Accepted().json(issue))
+}
+
+#[tracing::instrument(name = "Scheduling issue", skip(pool, clock))]
+pub async fn schedule_issue(
+    id: web::Path<Uuid>,
+    params: web::Json<ScheduleParams>,
+    pool: web::Data<PgPool>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, AppError> {
+    let issue = get_issue(*id, &pool).await?;
+    if !matches!(
+        issue.status,
+        IssueStatus::Draft | IssueStatus::Scheduled | IssueStatus::Failed
+    ) {
+        return Err(AppError::NotDraft);
+    }
+    issue.validate()?;
+    let (field, send_at) = match (params.send_at, params.local_send_at) {
+        (Some(send_at), None) => ("send_at", send_at),
+        (None, Some(local_send_at)) => ("local_send_at", earliest_send_at(local_send_at)),
+        _ => {
+            let mut errors = ValidationErrors::new();
+            errors.add("send_at", ValidationError::new("one_of"));
+            return Err(errors.into());
+        }
+    };
+    // Local time has to be ahead in every timezone.
+    if send_at <= clock.now() {
+        let mut errors = ValidationErrors::new();
+        errors.add(field, ValidationError::new("future"));
+        return Err(errors.into());
+    }
+
+    let issue = issues::schedule(*id, send_at, params.local_send_at, &pool)
+        .await
+        .context("Failed to schedule issue.")?
+        .ok_or(AppError::NotDraft)?;
+    Ok(HttpResponse::Ok().json(issue))
+}
+
+#[tracing::instrument(name = "Cancelling scheduled issue", skip(pool))]
+pub async fn unschedule_issue(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    get_issue(*id, &pool).await?;
+    let issue = issues::cancel_schedule(*id, &pool)
+        .await
+        .context("Failed to cancel scheduled issue.")?
+        .ok_or(AppError::NotScheduled)?;
+    Ok(HttpResponse::Ok().json(issue))
+}
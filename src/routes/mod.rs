@@ -1,9 +1,27 @@
 pub mod health_check;
+pub mod issues;
 pub mod newsletters;
+pub mod subscribers;
+pub mod subscription_policy;
 pub mod subscriptions;
 pub mod subscriptions_confirm;
+pub mod templates;
+pub mod tracking;
+pub mod webhooks;
 
 pub use health_check::*;
+pub use issues::{
+    create_issue, delete_issue, get_issue_detail, list_issues, preview_issue, schedule_issue,
+    send_issue_now, send_test_issue, unschedule_issue, update_issue,
+};
 pub use newsletters::post_newsletter;
+pub use subscribers::{
+    confirm_subscriber, delete_subscriber, export_subscribers, get_subscriber_detail,
+    import_subscribers, list_subscribers, unsubscribe_subscriber,
+};
+pub use subscription_policy::reload_subscription_policy;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use templates::{get_template_versions, list_templates, preview_template, put_template};
+pub use tracking::{follow_link, opt_out_form, opt_out_of_tracking, track_open};
+pub use webhooks::postmark_webhook;
@@ -1,57 +1,66 @@
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{web, HttpResponse};
 use anyhow::Context;
-use reqwest::StatusCode;
 use sqlx::PgPool;
+use validator::Validate;
 
-use crate::{domains::subscribers::get_confirmed_subscriber_emails, email_client::EmailClient};
+use crate::error::AppError;
+use crate::{
+    delivery::send_issue,
+    domains::{
+        issue::{IssueDraft, IssueStatus},
+        issues,
+        newsletter::{is_valid_content, Content},
+        template::TemplateName,
+        templates,
+    },
+    email_client::EmailClient,
+    links::TrackingLinks,
+};
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Validate)]
 pub struct Params {
+    #[validate(length(min = 1, max = 998))]
     title: String,
+    #[validate(custom = "is_valid_content")]
     content: Content,
+    #[serde(default)]
+    track_opens: bool,
+    #[serde(default)]
+    track_clicks: bool,
 }
 
-#[derive(serde::Deserialize, Debug)]
-pub struct Content {
-    html: String,
-    text: String,
-}
-
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
-}
-
-impl ResponseError for Error {
-    fn status_code(&self) -> reqwest::StatusCode {
-        match *self {
-            Error::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        }
-    }
-}
-
-#[tracing::instrument(name = "Sending newsletter", skip(pool, email_client))]
+/// Send the newsletter right away, it is kept as an issue like the ones
+/// prepared as drafts.
+#[tracing::instrument(name = "Sending newsletter", skip(pool, email_client, links))]
 pub async fn post_newsletter(
     params: web::Json<Params>,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
-) -> Result<HttpResponse, Error> {
-    let emails = get_confirmed_subscriber_emails(&pool)
+    links: web::Data<TrackingLinks>,
+) -> Result<HttpResponse, AppError> {
+    params.validate()?;
+    let params = params.into_inner();
+    let draft = IssueDraft {
+        title: params.title,
+        content: Some(params.content),
+        track_opens: params.track_opens,
+        track_clicks: params.track_clicks,
+    };
+    let issue = issues::insert_draft(&draft, &pool)
         .await
-        .context("Failed to get confirmed emails.")?;
+        .context("Failed to save issue.")?;
 
-    for email in emails {
-        email_client
-            .send_email(
-                &email,
-                &params.title,
-                &params.content.html,
-                &params.content.text,
-            )
-            .await
-            .with_context(|| format!("Failed to send newsletter."))?;
-    }
+    let layout = templates::get_or_default(TemplateName::NewsletterLayout, &pool).await;
+    let email = issue.render(&layout)?;
+    send_issue(
+        &issue,
+        IssueStatus::Draft,
+        &email,
+        &links,
+        &pool,
+        &email_client,
+    )
+    .await?;
 
     Ok(HttpResponse::Ok().finish())
 }
@@ -0,0 +1,234 @@
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use anyhow::Context;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::{
+    domains::subscriber::{ChangedBy, SubscriptionStatus},
+    domains::subscribers::{
+        self, HistoryEvent, SubscriptionCursor, SubscriptionFilter, SubscriptionRecord,
+    },
+    error::AppError,
+    export::{export, ExportFormat},
+    import::{CsvDecoder, Import, ImportOptions, InitialStatus},
+    links::TrackingLinks,
+};
+
+/// Page of the list, read from the same query as the filter.
+#[derive(serde::Deserialize, Debug, Validate)]
+pub struct PageParams {
+    cursor: Option<String>,
+    #[validate(range(min = 1, max = 100))]
+    limit: Option<i64>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct SubscriberPage {
+    subscribers: Vec<SubscriptionRecord>,
+    /// Cursor of the next page, `None` on the last one.
+    next_cursor: Option<String>,
+}
+
+/// Subscriber with the events of its subscription.
+#[derive(serde::Serialize, Debug)]
+pub struct SubscriberDetail {
+    #[serde(flatten)]
+    subscriber: SubscriptionRecord,
+    history: Vec<HistoryEvent>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ImportParams {
+    status: InitialStatus,
+    consent_source: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    report: ReportFormat,
+}
+
+/// Format of the import report, `csv` lists only the rows which failed and is
+/// sent as a file to download.
+#[derive(serde::Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+async fn get_record(id: Uuid, pool: &PgPool) -> Result<SubscriptionRecord, AppError> {
+    subscribers::get_record(id, pool)
+        .await
+        .context("Failed to get subscriber.")?
+        .ok_or(AppError::NotFound("Subscriber"))
+}
+
+#[tracing::instrument(name = "Listing subscribers", skip(pool))]
+pub async fn list_subscribers(
+    filter: web::Query<SubscriptionFilter>,
+    page: web::Query<PageParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    page.validate()?;
+    let after = match &page.cursor {
+        Some(cursor) => Some(SubscriptionCursor::decode(cursor).ok_or_else(|| {
+            let mut errors = ValidationErrors::new();
+            errors.add("cursor", ValidationError::new("cursor"));
+            errors
+        })?),
+        None => None,
+    };
+    let limit = page.limit.unwrap_or(50);
+
+    // One more than the page tells whether there is a next one.
+    let mut subscribers = subscribers::search(&filter, after, limit + 1, &pool)
+        .await
+        .context("Failed to search subscribers.")?;
+    let next_cursor = if subscribers.len() as i64 > limit {
+        subscribers.truncate(limit as usize);
+        subscribers
+            .last()
+            .map(|last| SubscriptionCursor::after(last).encode())
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(SubscriberPage {
+        subscribers,
+        next_cursor,
+    }))
+}
+
+/// Subscribers matching the same filter as the list, as a file to download.
+/// Rows are sent as they are read, the export is never held whole.
+#[tracing::instrument(name = "Exporting subscribers", skip(pool))]
+pub async fn export_subscribers(
+    filter: web::Query<SubscriptionFilter>,
+    params: web::Query<ExportParams>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let format = params.format;
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "subscribers.{}",
+                format.extension()
+            ))],
+        })
+        .streaming(
+            export(filter.into_inner(), format, pool.get_ref().clone())
+                .inspect_err(|e| tracing::error!("Export of subscribers failed: {:?}", e)),
+        )
+}
+
+#[tracing::instrument(name = "Getting subscriber", skip(pool))]
+pub async fn get_subscriber_detail(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let subscriber = get_record(*id, &pool).await?;
+    let history = subscribers::get_history(*id, &pool)
+        .await
+        .context("Failed to get history of subscriber.")?;
+    Ok(HttpResponse::Ok().json(SubscriberDetail {
+        subscriber,
+        history,
+    }))
+}
+
+#[tracing::instrument(name = "Confirming subscriber by admin", skip(credentials, pool))]
+pub async fn confirm_subscriber(
+    id: web::Path<Uuid>,
+    credentials: BasicAuth,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    subscribers::transition(
+        *id,
+        SubscriptionStatus::Confirmed,
+        &ChangedBy::Admin(credentials.user_id().to_string()),
+        pool.get_ref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(get_record(*id, &pool).await?))
+}
+
+#[tracing::instrument(name = "Unsubscribing subscriber by admin", skip(credentials, pool))]
+pub async fn unsubscribe_subscriber(
+    id: web::Path<Uuid>,
+    credentials: BasicAuth,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    subscribers::transition(
+        *id,
+        SubscriptionStatus::Unsubscribed,
+        &ChangedBy::Admin(credentials.user_id().to_string()),
+        pool.get_ref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(get_record(*id, &pool).await?))
+}
+
+#[tracing::instrument(name = "Deleting subscriber by admin", skip(pool))]
+pub async fn delete_subscriber(
+    id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    if !subscribers::delete(*id, &pool)
+        .await
+        .context("Failed to delete subscriber.")?
+    {
+        return Err(AppError::NotFound("Subscriber"));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Import subscribers of the CSV body, it is read as it arrives.
+#[tracing::instrument(name = "Importing subscribers", skip(body, pool, links))]
+pub async fn import_subscribers(
+    params: web::Query<ImportParams>,
+    mut body: web::Payload,
+    pool: web::Data<PgPool>,
+    links: web::Data<TrackingLinks>,
+) -> Result<HttpResponse, AppError> {
+    let params = params.into_inner();
+    let options = ImportOptions {
+        status: params.status,
+        consent_source: params.consent_source,
+        dry_run: params.dry_run,
+    };
+    let mut import = Import::new(options, &links, &pool)?;
+    let mut decoder = CsvDecoder::default();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| AppError::MalformedRequest(e.to_string()))?;
+        for record in decoder.feed(&chunk) {
+            import.add(record).await?;
+        }
+    }
+    for record in decoder.finish() {
+        import.add(record).await?;
+    }
+    let report = import.finish()?;
+
+    if params.report == ReportFormat::Csv {
+        return Ok(HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header(ContentDisposition {
+                disposition: DispositionType::Attachment,
+                parameters: vec![DispositionParam::Filename("import-errors.csv".into())],
+            })
+            .body(report.errors_csv()));
+    }
+    Ok(HttpResponse::Ok().json(report))
+}
@@ -0,0 +1,25 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+
+use crate::error::AppError;
+use crate::subscription_policy::SubscriptionPolicy;
+
+#[derive(serde::Serialize)]
+struct ReloadedDomains {
+    blocked: usize,
+    allowed: usize,
+}
+
+/// Read the domains file of the policy again, e.g. after it was edited.
+#[tracing::instrument(name = "Reloading subscription policy", skip(policy))]
+pub async fn reload_subscription_policy(
+    policy: web::Data<SubscriptionPolicy>,
+) -> Result<HttpResponse, AppError> {
+    let lists = policy
+        .reload()
+        .context("Failed to reload domains of the subscription policy.")?;
+    Ok(HttpResponse::Ok().json(ReloadedDomains {
+        blocked: lists.blocked.len(),
+        allowed: lists.allowed.len(),
+    }))
+}
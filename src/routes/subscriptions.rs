@@ -1,77 +1,143 @@
-use actix_web::{web, HttpResponse};
-use actix_web::{HttpRequest, ResponseError};
+use actix_web::dev::Payload;
+use actix_web::HttpRequest;
+use actix_web::{web, FromRequest, HttpMessage, HttpResponse};
 use anyhow::Context;
-use reqwest::StatusCode;
+use futures::future::LocalBoxFuture;
 use sqlx::PgPool;
 use uuid::Uuid;
+use validator::{ValidationError, ValidationErrors};
 
+use crate::bot_protection::{BotFields, BotProtection};
+use crate::clock::Clock;
+use crate::delivery::confirmation_email;
+use crate::domains::queued_emails;
+use crate::domains::subscriber::NewSubscriber;
 use crate::domains::subscribers::insert_subscriber;
-use crate::{domains::subscriber::NewSubscriber, email_client::EmailClient};
+use crate::domains::suppressions::is_suppressed;
+use crate::error::AppError;
+use crate::rate_limit::RateLimiter;
+use crate::subscription_policy::SubscriptionPolicy;
 
+/// Subscription sent as a form or as JSON.
 #[derive(serde::Deserialize, Debug)]
 pub struct FormData {
     name: String,
     email: String,
+    /// IANA timezone, e.g. `Europe/Prague`.
+    #[serde(default)]
+    timezone: String,
+    #[serde(flatten)]
+    bot: BotFields,
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum Error {
-    #[error(transparent)]
-    ValidationErrors(#[from] validator::ValidationErrors),
-    #[error(transparent)]
-    UnexpectedError(#[from] anyhow::Error),
-}
+/// Subscription from a body of either content type, `application/json` (or
+/// another `+json` type) is read as JSON and anything else as a form.
+#[derive(Debug)]
+pub struct SubscriptionBody(FormData);
+
+impl FromRequest for SubscriptionBody {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
-impl ResponseError for Error {
-    fn status_code(&self) -> reqwest::StatusCode {
-        use Error::*;
-        match *self {
-            ValidationErrors(_) => StatusCode::BAD_REQUEST,
-            UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req.content_type();
+        if content_type == "application/json" || content_type.ends_with("+json") {
+            let json = web::Json::<FormData>::from_request(req, payload);
+            Box::pin(async move { Ok(Self(json.await?.into_inner())) })
+        } else {
+            let form = web::Form::<FormData>::from_request(req, payload);
+            Box::pin(async move { Ok(Self(form.await?.into_inner())) })
         }
     }
 }
 
+#[derive(serde::Serialize)]
+struct SubscriptionForm {
+    form_token: String,
+}
+
+/// Token the subscribe form is rendered with, it is sent back in the
+/// `form_token` field.
+pub async fn subscription_form(
+    bot_protection: web::Data<BotProtection>,
+    clock: web::Data<dyn Clock>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(SubscriptionForm {
+        form_token: bot_protection.form_token(clock.now()),
+    })
+}
+
+/// Proof-of-work challenge of the subscribe form, its token and solution are
+/// sent back in the `challenge` and `solution` fields.
+pub async fn subscription_challenge(
+    bot_protection: web::Data<BotProtection>,
+    clock: web::Data<dyn Clock>,
+) -> HttpResponse {
+    match bot_protection.challenge(clock.now()) {
+        Some(challenge) => HttpResponse::Ok().json(challenge),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
 //#[post("/subscriptions")]
-#[tracing::instrument(name = "Reaching a new subscriber endpoint", skip(pool, email_client))]
+#[tracing::instrument(
+    name = "Reaching a new subscriber endpoint",
+    skip(pool, policy, bot_protection, rate_limiter, clock)
+)]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    SubscriptionBody(form): SubscriptionBody,
     pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
+    policy: web::Data<SubscriptionPolicy>,
+    bot_protection: web::Data<BotProtection>,
+    rate_limiter: web::Data<RateLimiter>,
+    clock: web::Data<dyn Clock>,
     req: HttpRequest,
-) -> Result<HttpResponse, Error> {
-    let subscriber = NewSubscriber::parse(&form.name, &form.email)?;
-    let conf_token = Uuid::new_v4().to_string();
-
-    insert_subscriber(&subscriber, &conf_token, &pool)
+) -> Result<HttpResponse, AppError> {
+    // Bots are not told they were caught.
+    if bot_protection.is_honeypot_filled(&form.bot) {
+        tracing::warn!("Dropping subscription with filled honeypot");
+        return Ok(HttpResponse::Ok().finish());
+    }
+    let subscriber =
+        NewSubscriber::parse(&form.name, &form.email)?.with_timezone(&form.timezone)?;
+    policy.check(subscriber.email())?;
+    bot_protection.verify(&form.bot, clock.now()).await?;
+    // Such an address bounced or complained before, it must not get any email.
+    if is_suppressed(subscriber.email().as_ref(), &pool)
         .await
-        .context("Failed to insert subscriber.")?;
-
-    send_email(subscriber.email(), email_client, req, &conf_token)
+        .context("Failed to check suppression list.")?
+    {
+        let mut errors = ValidationErrors::new();
+        errors.add("email", ValidationError::new("suppressed"));
+        return Err(errors.into());
+    }
+    // Confirmation emails must not flood one inbox, whatever address the
+    // requests come from. Rejected requests send nothing, so only these count.
+    match rate_limiter
+        .hit_email(subscriber.email().as_ref(), clock.now())
         .await
-        .context("Failed to send confirmation email.")?;
-
-    Ok(HttpResponse::Ok().into())
-}
-
-#[tracing::instrument(name = "Sending confirmation email to subscriber", skip(email_client))]
-async fn send_email(
-    email: &str,
-    email_client: web::Data<EmailClient>,
-    req: HttpRequest,
-    token: &str,
-) -> reqwest::Result<()> {
+    {
+        Ok(usage) if usage.exceeded() => return Err(AppError::RateLimited(usage)),
+        Ok(_) => {}
+        Err(e) => tracing::warn!("Failed to rate limit subscriptions of email {:?}", e),
+    }
+    let conf_token = Uuid::new_v4().to_string();
     let mut confirmation_link = req
         .url_for_static("confirm")
         .expect("Generating confirm link failed.");
-    confirmation_link.set_query(Some(format!("token={}", token).as_ref()));
+    confirmation_link.set_query(Some(format!("token={}", conf_token).as_ref()));
+    let email = confirmation_email(&subscriber, confirmation_link.as_str(), &pool).await;
 
-    email_client
-        .send_email(
-            email,
-            "Welcome!",
-            &format!("Welcome to our newsletter!<br/> Click <a href='{}'>here</a> to confirm your subscription.", &confirmation_link),
-            &format!("Welcome to our newsletter! Visit {} to confirm your subscription.", &confirmation_link),
-        )
+    // The outbox worker sends the email once the subscriber is saved.
+    let mut transaction = pool.begin().await.context("Failed to begin transaction.")?;
+    insert_subscriber(&subscriber, &conf_token, &mut transaction).await?;
+    queued_emails::enqueue(&email, &mut transaction)
         .await
+        .context("Failed to queue confirmation email.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit new subscriber.")?;
+
+    Ok(HttpResponse::Ok().into())
 }
@@ -1,7 +1,11 @@
-use actix_web::{error, web, HttpResponse};
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
 use sqlx::PgPool;
 
-use crate::domains::subscribers::confirm_subscriber;
+use crate::delivery::welcome_email;
+use crate::domains::queued_emails;
+use crate::domains::subscribers::{confirm_subscriber, get_subscriber, TransitionError};
+use crate::error::AppError;
 
 #[derive(serde::Deserialize, Debug)]
 pub struct Params {
@@ -13,9 +17,30 @@ pub struct Params {
 pub async fn confirm(
     params: web::Query<Params>,
     pool: web::Data<PgPool>,
-) -> actix_web::Result<HttpResponse> {
-    confirm_subscriber(&params.token, &pool)
+) -> Result<HttpResponse, AppError> {
+    // The welcome email is queued with the confirmation, a request which
+    // failed to queue it confirms again when retried.
+    let mut transaction = pool.begin().await.context("Failed to begin transaction.")?;
+    let confirmed = match confirm_subscriber(&params.token, &mut transaction).await {
+        Ok(confirmed) => confirmed,
+        Err(TransitionError::NotFound) => return Err(AppError::InvalidToken),
+        Err(e) => return Err(e.into()),
+    };
+    // Only the first confirmation is welcomed.
+    if let Some(id) = confirmed {
+        let subscriber = get_subscriber(id, &mut transaction)
+            .await
+            .context("Failed to get confirmed subscriber.")?;
+        if let Some(subscriber) = subscriber {
+            let email = welcome_email(&subscriber, &pool).await;
+            queued_emails::enqueue(&email, &mut transaction)
+                .await
+                .context("Failed to queue welcome email.")?;
+        }
+    }
+    transaction
+        .commit()
         .await
-        .map_err(|_| error::ErrorUnauthorized("invalid params"))?;
+        .context("Failed to commit confirmation.")?;
     Ok(HttpResponse::Ok().into())
 }
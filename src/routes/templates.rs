@@ -0,0 +1,72 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use validator::Validate;
+
+use crate::domains::{
+    template::{NewTemplate, Template, TemplateName},
+    templates,
+};
+use crate::error::AppError;
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PreviewParams {
+    version: Option<i32>,
+}
+
+#[tracing::instrument(name = "Listing templates", skip(pool))]
+pub async fn list_templates(pool: web::Data<PgPool>) -> Result<HttpResponse, AppError> {
+    let mut res = Vec::new();
+    for name in TemplateName::ALL {
+        let template = templates::get_latest(name, &pool)
+            .await
+            .context("Failed to get template.")?
+            .unwrap_or_else(|| Template::default_for(name));
+        res.push(template);
+    }
+    Ok(HttpResponse::Ok().json(res))
+}
+
+#[tracing::instrument(name = "Listing template versions", skip(pool))]
+pub async fn get_template_versions(
+    template_name: web::Path<TemplateName>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let versions = templates::get_versions(*template_name, &pool)
+        .await
+        .context("Failed to get template versions.")?;
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[tracing::instrument(name = "Saving template", skip(pool))]
+pub async fn put_template(
+    template_name: web::Path<TemplateName>,
+    params: web::Json<NewTemplate>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    params.validate()?;
+    let template = templates::add_version(*template_name, &params, &pool)
+        .await
+        .context("Failed to save template.")?;
+    Ok(HttpResponse::Created().json(template))
+}
+
+#[tracing::instrument(name = "Previewing template", skip(pool))]
+pub async fn preview_template(
+    template_name: web::Path<TemplateName>,
+    params: web::Query<PreviewParams>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    let template = match params.version {
+        Some(0) => Template::default_for(*template_name),
+        Some(version) => templates::get_version(*template_name, version, &pool)
+            .await
+            .context("Failed to get template.")?
+            .ok_or(AppError::NotFound("Template"))?,
+        None => templates::get_latest(*template_name, &pool)
+            .await
+            .context("Failed to get template.")?
+            .unwrap_or_else(|| Template::default_for(*template_name)),
+    };
+    Ok(HttpResponse::Ok().json(template.render(&template_name.sample_data())))
+}
@@ -0,0 +1,105 @@
+use actix_web::{
+    http::header::{self, ContentType},
+    web, HttpRequest, HttpResponse,
+};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::{domains::tracking, links::TrackingLinks};
+
+/// Transparent 1×1 GIF.
+const PIXEL: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// Serve the tracking pixel, the image is returned even when the open cannot
+/// be recorded so mail clients do not show a broken image.
+#[tracing::instrument(name = "Tracking open", skip(pool, req))]
+pub async fn track_open(
+    recipient_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok());
+    if let Err(e) = tracking::record_open(*recipient_id, user_agent, &pool).await {
+        tracing::error!("Failed to record open {:?}", e);
+    }
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(PIXEL.as_slice())
+}
+
+/// Page asking to confirm the opt-out, links in emails may be followed by
+/// scanners which must not opt the recipient out.
+pub async fn opt_out_form(recipient_id: web::Path<Uuid>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html>
+  <head><meta charset="utf-8"><title>Stop tracking</title></head>
+  <body>
+    <p>Do you want to stop tracking of opens of newsletters and clicks in them?</p>
+    <form method="post" action="/tracking/opt_out/{}">
+      <button type="submit">Stop tracking</button>
+    </form>
+  </body>
+</html>
+"#,
+            recipient_id
+        ))
+}
+
+#[tracing::instrument(name = "Opting out of tracking", skip(pool))]
+pub async fn opt_out_of_tracking(
+    recipient_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    if !tracking::opt_out(*recipient_id, &pool)
+        .await
+        .context("Failed to opt out of tracking.")?
+    {
+        return Err(AppError::NotFound("Tracking link"));
+    }
+    Ok(HttpResponse::Ok()
+        .body("Opens of newsletters and clicks in them will not be tracked anymore."))
+}
+
+/// Redirect to the original link of the issue and record the click.
+///
+/// Only links signed by the application and stored for the issue of the
+/// delivery are followed, anything else is not found.
+#[tracing::instrument(name = "Following link", skip(pool, links, req))]
+pub async fn follow_link(
+    token: web::Path<String>,
+    pool: web::Data<PgPool>,
+    links: web::Data<TrackingLinks>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    let (recipient_id, link_id) = links
+        .verify_click_token(&token)
+        .ok_or(AppError::NotFound("Tracking link"))?;
+    let url = tracking::get_link_url(recipient_id, link_id, &pool)
+        .await
+        .context("Failed to get link.")?
+        .ok_or(AppError::NotFound("Tracking link"))?;
+
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok());
+    if let Err(e) = tracking::record_click(recipient_id, link_id, user_agent, &pool).await {
+        tracing::error!("Failed to record click {:?}", e);
+    }
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .finish())
+}
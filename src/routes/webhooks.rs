@@ -0,0 +1,41 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::domains::suppressions::{suppress, SuppressionReason};
+use crate::error::AppError;
+
+/// Bounce or spam complaint webhook payload of Postmark, only the fields we
+/// use.
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PostmarkEvent {
+    record_type: String,
+    #[serde(rename = "Type", default)]
+    kind: String,
+    email: String,
+}
+
+impl PostmarkEvent {
+    /// Soft bounces and other events do not suppress the address.
+    fn suppression_reason(&self) -> Option<SuppressionReason> {
+        match (self.record_type.as_str(), self.kind.as_str()) {
+            ("Bounce", "HardBounce") => Some(SuppressionReason::Bounced),
+            ("SpamComplaint", _) => Some(SuppressionReason::Complained),
+            _ => None,
+        }
+    }
+}
+
+#[tracing::instrument(name = "Receiving Postmark webhook", skip(pool))]
+pub async fn postmark_webhook(
+    event: web::Json<PostmarkEvent>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError> {
+    if let Some(reason) = event.suppression_reason() {
+        suppress(&event.email, reason, &pool)
+            .await
+            .context("Failed to suppress address.")?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
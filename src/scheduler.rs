@@ -0,0 +1,210 @@
+use anyhow::Context;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use sqlx::PgPool;
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use crate::{
+    clock::Clock,
+    delivery::{deliver_issue, send_to_subscribers},
+    domains::{
+        issue::{local_send_at_in, Issue, IssueStatus},
+        issues::{self, Delivery},
+        subscribers::{get_confirmed_subscribers_in_timezone, get_confirmed_timezones},
+        template::{RenderedEmail, TemplateName},
+        templates,
+    },
+    email_client::EmailClient,
+    links::TrackingLinks,
+};
+
+/// What the scheduler needs to know about the application.
+#[derive(Debug, Clone)]
+pub struct SchedulerContext {
+    pub poll_interval: Duration,
+    /// Subscribers without a timezone get local time issues in this one.
+    pub default_timezone: Tz,
+    pub links: TrackingLinks,
+}
+
+/// Periodically send scheduled issues which are due.
+pub async fn run_scheduler(
+    pool: PgPool,
+    email_client: Arc<EmailClient>,
+    clock: Arc<dyn Clock>,
+    context: SchedulerContext,
+) {
+    loop {
+        if let Err(e) = send_due_issues(clock.now(), &context, &pool, &email_client).await {
+            tracing::error!("Failed to send scheduled issues {:?}", e);
+        }
+        tokio::time::sleep(context.poll_interval).await;
+    }
+}
+
+/// Issues sending without any activity for this long are considered stuck.
+const STALE_SENDING_MINUTES: i64 = 15;
+
+/// Send all issues scheduled before `now`, local time issues are split into
+/// deliveries per timezone which are sent once they are due. An issue whose
+/// sending fails is marked as failed and the others are still sent.
+///
+/// Returns how many issues and deliveries were sent.
+#[tracing::instrument(name = "Sending due issues", skip(pool, email_client))]
+pub async fn send_due_issues(
+    now: DateTime<Utc>,
+    context: &SchedulerContext,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<usize> {
+    let stale_before = now - chrono::Duration::minutes(STALE_SENDING_MINUTES);
+    let reclaimed = issues::reclaim_stale(stale_before, now, pool)
+        .await
+        .context("Failed to reclaim stale issues.")?;
+    if reclaimed > 0 {
+        tracing::warn!(
+            "Reclaimed {} issues and deliveries stuck in sending",
+            reclaimed
+        );
+    }
+
+    let mut sent = 0;
+    while let Some(issue) = issues::claim_due(now, pool)
+        .await
+        .context("Failed to claim due issue.")?
+    {
+        match send_claimed(&issue, context, pool, email_client).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(e) => fail(issue.id, e, pool).await,
+        }
+    }
+
+    while let Some(delivery) = issues::claim_due_delivery(now, pool)
+        .await
+        .context("Failed to claim due delivery.")?
+    {
+        match send_delivery(&delivery, context, pool, email_client).await {
+            Ok(()) => sent += 1,
+            Err(e) => fail(delivery.issue_id, e, pool).await,
+        }
+    }
+    Ok(sent)
+}
+
+/// Send the claimed issue, or plan its deliveries when it is sent at local
+/// time. Returns `true` when the issue was sent.
+async fn send_claimed(
+    issue: &Issue,
+    context: &SchedulerContext,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<bool> {
+    let email = match render(issue, pool).await {
+        Ok(email) => email,
+        Err(e) => {
+            // Issues are validated when scheduled, this is only a safety net.
+            tracing::error!("Scheduled issue {} is not valid {:?}", issue.id, e);
+            issues::transition(issue.id, IssueStatus::Sending, IssueStatus::Draft, pool)
+                .await
+                .context("Failed to return the issue to drafts.")?;
+            return Ok(false);
+        }
+    };
+    match issue.local_send_at {
+        Some(local_send_at) => {
+            plan_deliveries(issue, local_send_at, context.default_timezone, pool).await?;
+            Ok(false)
+        }
+        None => {
+            deliver_issue(issue, &email, &context.links, pool, email_client).await?;
+            Ok(true)
+        }
+    }
+}
+
+async fn fail(issue_id: Uuid, error: anyhow::Error, pool: &PgPool) {
+    tracing::error!("Failed to send issue {} {:?}", issue_id, error);
+    if let Err(e) = issues::fail(issue_id, &format!("{:#}", error), pool).await {
+        tracing::error!("Failed to mark issue {} as failed {:?}", issue_id, e);
+    }
+}
+
+async fn render(issue: &Issue, pool: &PgPool) -> anyhow::Result<RenderedEmail> {
+    let layout = templates::get_or_default(TemplateName::NewsletterLayout, pool).await;
+    Ok(issue.render(&layout)?)
+}
+
+#[tracing::instrument(name = "Planning local time delivery", skip(issue, pool), fields(issue_id = %issue.id))]
+async fn plan_deliveries(
+    issue: &Issue,
+    local_send_at: NaiveDateTime,
+    default_timezone: Tz,
+    pool: &PgPool,
+) -> anyhow::Result<()> {
+    let deliveries: Vec<(String, DateTime<Utc>)> =
+        get_confirmed_timezones(default_timezone.name(), pool)
+            .await
+            .context("Failed to get timezones of subscribers.")?
+            .into_iter()
+            .map(|timezone| {
+                let tz = timezone.parse::<Tz>().unwrap_or_else(|_| {
+                    tracing::warn!("Unknown timezone {}, using default", timezone);
+                    default_timezone
+                });
+                (timezone, local_send_at_in(local_send_at, tz))
+            })
+            .collect();
+
+    if deliveries.is_empty() {
+        issues::transition(issue.id, IssueStatus::Sending, IssueStatus::Sent, pool)
+            .await
+            .context("Failed to mark the issue as sent.")?;
+        return Ok(());
+    }
+    issues::insert_deliveries(issue.id, &deliveries, pool)
+        .await
+        .context("Failed to plan deliveries.")?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Sending issue delivery", skip(pool, email_client))]
+async fn send_delivery(
+    delivery: &Delivery,
+    context: &SchedulerContext,
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> anyhow::Result<()> {
+    let issue = issues::get(delivery.issue_id, pool)
+        .await
+        .context("Failed to get issue.")?
+        .context("Issue of the delivery is missing.")?;
+    let email = render(&issue, pool).await?;
+    let subscribers = get_confirmed_subscribers_in_timezone(
+        &delivery.timezone,
+        context.default_timezone.name(),
+        pool,
+    )
+    .await
+    .context("Failed to get subscribers.")?;
+    send_to_subscribers(
+        &issue,
+        &email,
+        &subscribers,
+        &context.links,
+        pool,
+        email_client,
+    )
+    .await?;
+
+    if issues::finish_delivery(delivery, pool)
+        .await
+        .context("Failed to finish delivery.")?
+    {
+        issues::transition(issue.id, IssueStatus::Sending, IssueStatus::Sent, pool)
+            .await
+            .context("Failed to mark the issue as sent.")?;
+    }
+    Ok(())
+}
@@ -1,17 +1,49 @@
 use actix_web::dev::Server;
 use actix_web::web::Data;
-use actix_web::{web, App, HttpServer};
+use actix_web::{web, App, HttpResponse, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use sqlx::{PgPool, Pool, Postgres};
 use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 
 use crate::auth;
-use crate::configuration::{ApplicationSettings, Settings};
+use crate::bot_protection::BotProtection;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::clock::{Clock, SystemClock};
+use crate::configuration::{ApplicationSettings, Settings, WebhookSettings};
 use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, post_newsletter, subscribe};
+use crate::error::{malformed_request, not_found, with_request_id};
+use crate::links::TrackingLinks;
+use crate::outbox::run_outbox_worker;
+use crate::purge::run_purge;
+use crate::rate_limit::{run_pruning, RateLimiter, RateLimiting, RouteGroup};
+use crate::routes::{
+    confirm, confirm_subscriber, create_issue, delete_issue, delete_subscriber,
+    email_provider_health, export_subscribers, follow_link, get_issue_detail,
+    get_subscriber_detail, get_template_versions, health_check, import_subscribers, list_issues,
+    list_subscribers, list_templates, opt_out_form, opt_out_of_tracking, post_newsletter,
+    postmark_webhook, preview_issue, preview_template, put_template, reload_subscription_policy,
+    schedule_issue, send_issue_now, send_test_issue, subscribe, subscription_challenge,
+    subscription_form, track_open, unschedule_issue, unsubscribe_subscriber, update_issue,
+};
+use crate::scheduler::{run_scheduler, SchedulerContext};
+use crate::subscription_policy::SubscriptionPolicy;
+use crate::throttle::Throttle;
 
 pub fn build(pool: Pool<Postgres>, configuration: Settings) -> std::io::Result<(Server, String)> {
+    build_with_clock(pool, configuration, Arc::new(SystemClock))
+}
+
+/// Build the server and start the scheduler of newsletter issues and the
+/// purge of pending subscriptions, all use the given clock, and the outbox
+/// worker.
+pub fn build_with_clock(
+    pool: Pool<Postgres>,
+    configuration: Settings,
+    clock: Arc<dyn Clock>,
+) -> std::io::Result<(Server, String)> {
     let address = format!(
         "{}:{}",
         configuration.application.host, configuration.application.port
@@ -25,46 +57,194 @@ pub fn build(pool: Pool<Postgres>, configuration: Settings) -> std::io::Result<(
         configuration.application.host,
         listener.local_addr()?.port()
     );
-    let email_client = EmailClient::new(
+    let mut email_client = EmailClient::new(
         configuration.email_client.base_url,
-        configuration.email_client.sender,
+        configuration.email_client.senders,
         configuration.email_client.timeout_milliseconds,
         configuration.email_client.token,
+    )
+    .with_suppression_list(pool.clone())
+    .with_throttle(Throttle::new(
+        configuration.email_client.max_per_second,
+        configuration.email_client.max_in_flight,
+    ))
+    .with_circuit_breaker(CircuitBreaker::new(
+        configuration.email_client.circuit_breaker.failure_ratio,
+        configuration.email_client.circuit_breaker.window,
+        Duration::from_millis(configuration.email_client.circuit_breaker.open_milliseconds),
+    ));
+    if configuration.email_client.batch_api {
+        email_client = email_client.with_batch_api();
+    }
+    let email_client = Data::new(email_client);
+    let policy = SubscriptionPolicy::new(&configuration.subscription_policy)?;
+    let rate_limiter = Data::new(RateLimiter::new(configuration.rate_limit, pool.clone()));
+    let bot_protection = BotProtection::new(
+        configuration.bot_protection,
+        configuration.application.form_secret.clone(),
+        pool.clone(),
+    );
+    let links = TrackingLinks::new(
+        &configuration.application.base_url,
+        configuration.application.link_secret.clone(),
     );
+    tokio::spawn(run_scheduler(
+        pool.clone(),
+        email_client.clone().into_inner(),
+        clock.clone(),
+        SchedulerContext {
+            poll_interval: Duration::from_millis(
+                configuration.scheduler.poll_interval_milliseconds,
+            ),
+            default_timezone: configuration.newsletter.default_timezone,
+            links: links.clone(),
+        },
+    ));
+    tokio::spawn(run_outbox_worker(
+        pool.clone(),
+        email_client.clone().into_inner(),
+        Duration::from_millis(configuration.outbox.poll_interval_milliseconds),
+    ));
+    tokio::spawn(run_purge(
+        pool.clone(),
+        links.clone(),
+        clock.clone(),
+        configuration.purge,
+    ));
+    tokio::spawn(run_pruning(
+        rate_limiter.clone().into_inner(),
+        clock.clone(),
+        Duration::from_secs(600),
+    ));
     //sqlx::migrate!().run(<&your_pool OR &mut your_connection>).await?
-    let server = run(listener, pool, email_client, configuration.application)?;
+    let server = run(
+        listener,
+        pool,
+        email_client,
+        clock,
+        links,
+        policy,
+        bot_protection,
+        rate_limiter,
+        configuration.application,
+        configuration.webhook,
+    )?;
     Ok((server, final_address))
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run(
     listener: TcpListener,
     pg_pool: PgPool,
-    email_client: EmailClient,
+    email_client: Data<EmailClient>,
+    clock: Arc<dyn Clock>,
+    links: TrackingLinks,
+    policy: SubscriptionPolicy,
+    bot_protection: BotProtection,
+    rate_limiter: Data<RateLimiter>,
     application_settings: ApplicationSettings,
+    webhook_settings: WebhookSettings,
 ) -> std::io::Result<Server> {
+    let links = Data::new(links);
+    let policy = Data::new(policy);
+    let bot_protection = Data::new(bot_protection);
     let db_pool = Data::new(pg_pool);
-    let email_client = Data::new(email_client);
+    let clock: Data<dyn Clock> = Data::from(clock);
     let app_data = Data::new(application_settings);
+    let webhook_settings = Data::new(webhook_settings);
 
     let server = HttpServer::new(move || {
         App::new()
+            .wrap_fn(with_request_id)
             .wrap(TracingLogger::default())
             //.wrap(HttpAuthentication::basic(auth::basic_auth_validator))
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .route(
+                "/health_check/email_provider",
+                web::get().to(email_provider_health),
+            )
+            .service(
+                web::scope("/subscriptions")
+                    .wrap(RateLimiting::new(RouteGroup::Public))
+                    .route("", web::post().to(subscribe))
+                    .route("/form", web::get().to(subscription_form))
+                    .route("/challenge", web::get().to(subscription_challenge))
+                    .service(web::resource("/confirm").name("confirm").to(confirm)),
+            )
+            .service(
+                web::scope("/tracking")
+                    .wrap(RateLimiting::new(RouteGroup::Public))
+                    .route("/open/{id}", web::get().to(track_open))
+                    .route("/opt_out/{id}", web::get().to(opt_out_form))
+                    .route("/opt_out/{id}", web::post().to(opt_out_of_tracking)),
+            )
+            .service(
+                web::resource("/r/{token}")
+                    .wrap(RateLimiting::new(RouteGroup::Public))
+                    .route(web::get().to(follow_link)),
+            )
             .service(
                 web::scope("/auth")
-                    .wrap(HttpAuthentication::basic(auth::basic_auth_validator))
+                    .wrap(HttpAuthentication::with_fn(auth::basic_auth_validator))
+                    .wrap(RateLimiting::new(RouteGroup::Auth))
                     .route("/newsletters", web::post().to(post_newsletter)),
             )
             .service(
-                web::resource("/subscriptions/confirm")
-                    .name("confirm")
-                    .to(confirm),
+                web::scope("/webhooks")
+                    .wrap(HttpAuthentication::with_fn(auth::webhook_auth_validator))
+                    .route("/postmark", web::post().to(postmark_webhook)),
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(HttpAuthentication::with_fn(auth::basic_auth_validator))
+                    .wrap(RateLimiting::new(RouteGroup::Admin))
+                    .route("/templates", web::get().to(list_templates))
+                    .route("/templates/{name}", web::get().to(get_template_versions))
+                    .route("/templates/{name}", web::put().to(put_template))
+                    .route("/templates/{name}/preview", web::get().to(preview_template))
+                    .route("/issues", web::get().to(list_issues))
+                    .route("/issues", web::post().to(create_issue))
+                    .route("/issues/{id}", web::get().to(get_issue_detail))
+                    .route("/issues/{id}", web::put().to(update_issue))
+                    .route("/issues/{id}", web::delete().to(delete_issue))
+                    .route("/issues/{id}/preview", web::get().to(preview_issue))
+                    .route("/issues/{id}/test", web::post().to(send_test_issue))
+                    .route("/issues/{id}/send", web::post().to(send_issue_now))
+                    .route("/issues/{id}/schedule", web::put().to(schedule_issue))
+                    .route("/issues/{id}/schedule", web::delete().to(unschedule_issue))
+                    .route("/subscribers", web::get().to(list_subscribers))
+                    .route("/subscribers/export", web::get().to(export_subscribers))
+                    .route("/subscribers/import", web::post().to(import_subscribers))
+                    .route("/subscribers/{id}", web::get().to(get_subscriber_detail))
+                    .route("/subscribers/{id}", web::delete().to(delete_subscriber))
+                    .route(
+                        "/subscribers/{id}/confirm",
+                        web::post().to(confirm_subscriber),
+                    )
+                    .route(
+                        "/subscribers/{id}/unsubscribe",
+                        web::post().to(unsubscribe_subscriber),
+                    )
+                    .route(
+                        "/subscription_policy/reload",
+                        web::post().to(reload_subscription_policy),
+                    ),
             )
+            .app_data(web::JsonConfig::default().error_handler(|e, _| malformed_request(e)))
+            .app_data(web::FormConfig::default().error_handler(|e, _| malformed_request(e)))
+            .app_data(web::QueryConfig::default().error_handler(|e, _| malformed_request(e)))
+            // Paths which do not parse are as unknown as any other.
+            .app_data(web::PathConfig::default().error_handler(|_, _| not_found()))
+            .default_service(web::to(|| async { Err::<HttpResponse, _>(not_found()) }))
             .app_data(db_pool.clone())
             .app_data(email_client.clone())
+            .app_data(clock.clone())
+            .app_data(links.clone())
+            .app_data(policy.clone())
+            .app_data(bot_protection.clone())
+            .app_data(rate_limiter.clone())
             .app_data(app_data.clone())
+            .app_data(webhook_settings.clone())
     })
     .listen(listener)?
     .run();
@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::configuration::SubscriptionPolicySettings;
+use crate::domains::subscriber_email::SubscriberEmail;
+
+/// Why an address may not subscribe, serialized as the reason code of the
+/// rejection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, thiserror::Error)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    #[error("Domain of the address is blocked.")]
+    BlockedDomain,
+    #[error("Address of a role account, not a person.")]
+    RoleAccount,
+    #[error("Address has too many plus aliases.")]
+    AliasDepth,
+}
+
+/// Rules for addresses which may subscribe.
+///
+/// Domains are read from a file, one per line, `#` starts a comment. A
+/// domain blocks its subdomains too. Lines starting with `!` are allowed
+/// domains, addresses there skip the blocklist and the role account check.
+#[derive(Debug)]
+pub struct SubscriptionPolicy {
+    domains_file: Option<PathBuf>,
+    domains: RwLock<DomainLists>,
+    role_accounts: HashSet<String>,
+    max_alias_depth: usize,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DomainLists {
+    pub blocked: HashSet<String>,
+    pub allowed: HashSet<String>,
+}
+
+impl SubscriptionPolicy {
+    pub fn new(settings: &SubscriptionPolicySettings) -> std::io::Result<Self> {
+        let policy = Self {
+            domains_file: settings.domains_file.as_ref().map(PathBuf::from),
+            domains: RwLock::new(DomainLists::default()),
+            role_accounts: settings
+                .role_accounts
+                .iter()
+                .map(|account| account.to_lowercase())
+                .collect(),
+            max_alias_depth: settings.max_alias_depth,
+        };
+        policy.reload()?;
+        Ok(policy)
+    }
+
+    /// Read the domains file again, the current lists are kept when it fails.
+    pub fn reload(&self) -> std::io::Result<DomainLists> {
+        let lists = match &self.domains_file {
+            Some(file) => parse_domains(&std::fs::read_to_string(file)?),
+            None => DomainLists::default(),
+        };
+        tracing::info!(
+            "Subscription policy loaded with {} blocked and {} allowed domains",
+            lists.blocked.len(),
+            lists.allowed.len()
+        );
+        *self.domains.write().unwrap() = lists.clone();
+        Ok(lists)
+    }
+
+    pub fn check(&self, email: &SubscriberEmail) -> Result<(), Rejection> {
+        let (local, domain) = email
+            .as_ref()
+            .rsplit_once('@')
+            .expect("Subscriber email has a domain");
+        let mut aliases = local.split('+');
+        let account = aliases.next().unwrap_or_default();
+        if aliases.count() > self.max_alias_depth {
+            return Err(Rejection::AliasDepth);
+        }
+
+        let domains = self.domains.read().unwrap();
+        if matches(&domains.allowed, domain) {
+            return Ok(());
+        }
+        if matches(&domains.blocked, domain) {
+            return Err(Rejection::BlockedDomain);
+        }
+        if self.role_accounts.contains(&account.to_lowercase()) {
+            return Err(Rejection::RoleAccount);
+        }
+        Ok(())
+    }
+}
+
+fn parse_domains(content: &str) -> DomainLists {
+    let mut lists = DomainLists::default();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (list, domain) = match line.strip_prefix('!') {
+            Some(domain) => (&mut lists.allowed, domain.trim()),
+            None => (&mut lists.blocked, line),
+        };
+        if domain.is_empty() {
+            continue;
+        }
+        // Stored as the domains of subscriber emails are.
+        match idna::domain_to_ascii(domain) {
+            Ok(domain) => {
+                list.insert(domain);
+            }
+            Err(_) => tracing::warn!("Skipping invalid domain {} of the policy", domain),
+        }
+    }
+    lists
+}
+
+/// The domain or any of its parents is in the list.
+fn matches(list: &HashSet<String>, domain: &str) -> bool {
+    let mut domain = domain;
+    loop {
+        if list.contains(domain) {
+            return true;
+        }
+        match domain.split_once('.') {
+            Some((_, parent)) => domain = parent,
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::{Rejection, SubscriptionPolicy};
+    use crate::configuration::SubscriptionPolicySettings;
+    use crate::domains::subscriber_email::SubscriberEmail;
+
+    fn policy(domains: &str) -> SubscriptionPolicy {
+        let file = std::env::temp_dir().join(format!("domains-{}.txt", Uuid::new_v4()));
+        std::fs::write(&file, domains).unwrap();
+        SubscriptionPolicy::new(&SubscriptionPolicySettings {
+            domains_file: Some(file.to_string_lossy().into()),
+            role_accounts: vec!["noreply".into(), "Admin".into()],
+            max_alias_depth: 1,
+        })
+        .unwrap()
+    }
+
+    fn check(policy: &SubscriptionPolicy, email: &str) -> Result<(), Rejection> {
+        policy.check(&SubscriberEmail::parse(email).unwrap())
+    }
+
+    #[test]
+    fn blocked_domains_and_their_subdomains_are_rejected() {
+        let policy = policy("# disposable\nmailinator.com\n\n!ok.mailinator.com # ours\n");
+
+        assert_eq!(
+            check(&policy, "tom@mailinator.com"),
+            Err(Rejection::BlockedDomain)
+        );
+        assert_eq!(
+            check(&policy, "tom@eu.Mailinator.com"),
+            Err(Rejection::BlockedDomain)
+        );
+        assert_eq!(check(&policy, "tom@ok.mailinator.com"), Ok(()));
+        assert_eq!(check(&policy, "tom@gmail.com"), Ok(()));
+    }
+
+    #[test]
+    fn role_accounts_are_rejected_unless_domain_is_allowed() {
+        let policy = policy("!example.com");
+
+        assert_eq!(
+            check(&policy, "noreply@gmail.com"),
+            Err(Rejection::RoleAccount)
+        );
+        assert_eq!(
+            check(&policy, "admin+news@gmail.com"),
+            Err(Rejection::RoleAccount)
+        );
+        assert_eq!(check(&policy, "admin@example.com"), Ok(()));
+    }
+
+    #[test]
+    fn alias_depth_is_limited() {
+        let policy = policy("");
+
+        assert_eq!(check(&policy, "tom+news@gmail.com"), Ok(()));
+        assert_eq!(
+            check(&policy, "tom+news+daily@gmail.com"),
+            Err(Rejection::AliasDepth)
+        );
+    }
+
+    #[test]
+    fn reload_reads_the_file_again() {
+        let policy = policy("");
+        assert_eq!(check(&policy, "tom@mailinator.com"), Ok(()));
+
+        std::fs::write(policy.domains_file.as_ref().unwrap(), "mailinator.com").unwrap();
+        let lists = policy.reload().unwrap();
+
+        assert_eq!(lists.blocked.len(), 1);
+        assert_eq!(
+            check(&policy, "tom@mailinator.com"),
+            Err(Rejection::BlockedDomain)
+        );
+    }
+
+    #[test]
+    fn failed_reload_keeps_lists() {
+        let policy = policy("mailinator.com");
+        std::fs::remove_file(policy.domains_file.as_ref().unwrap()).unwrap();
+
+        assert!(policy.reload().is_err());
+        assert_eq!(
+            check(&policy, "tom@mailinator.com"),
+            Err(Rejection::BlockedDomain)
+        );
+    }
+}
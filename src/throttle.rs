@@ -0,0 +1,163 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Limits how fast and how many requests at once are made to a service.
+///
+/// Requests are let through by a token bucket refilled at `per_second`
+/// tokens a second, holding at most `per_second` tokens, and at most
+/// `max_in_flight` of them may run at the same time.
+#[derive(Debug)]
+pub struct Throttle {
+    per_second: f64,
+    max_in_flight: usize,
+    bucket: Mutex<Bucket>,
+    in_flight: Semaphore,
+    waiting: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Permission to make one request, it is in flight until dropped.
+pub struct Permit<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Throttle {
+    pub fn new(per_second: u32, max_in_flight: usize) -> Self {
+        let per_second = f64::from(per_second.max(1));
+        let max_in_flight = max_in_flight.max(1);
+        Self {
+            per_second,
+            max_in_flight,
+            bucket: Mutex::new(Bucket {
+                tokens: per_second,
+                refilled_at: Instant::now(),
+            }),
+            in_flight: Semaphore::new(max_in_flight),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait until a request may be made.
+    pub async fn acquire(&self) -> Permit<'_> {
+        // Counted until the wait ends, also when the caller stops waiting.
+        let _waiting = Waiting::new(&self.waiting);
+        let permit = self
+            .in_flight
+            .acquire()
+            .await
+            .expect("Semaphore of the throttle is never closed");
+        while let Err(wait) = self.take_token() {
+            tokio::time::sleep(wait).await;
+        }
+        Permit { _permit: permit }
+    }
+
+    /// Take a token from the bucket or tell how long to wait for one.
+    fn take_token(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.per_second);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    /// Number of requests running now.
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.in_flight.available_permits()
+    }
+
+    /// Number of requests waiting for their turn.
+    pub fn backlog(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+}
+
+/// One request in the backlog of the throttle.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn new(waiting: &'a AtomicUsize) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        Self(waiting)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::Throttle;
+
+    #[tokio::test]
+    async fn requests_over_the_rate_wait() {
+        let throttle = Throttle::new(10, 100);
+
+        let start = Instant::now();
+        for _ in 0..15 {
+            drop(throttle.acquire().await);
+        }
+
+        // The first 10 go at once, the other 5 are let through 100ms apart.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_are_limited() {
+        let throttle = Arc::new(Throttle::new(1000, 2));
+
+        let first = throttle.acquire().await;
+        let _second = throttle.acquire().await;
+        assert_eq!(throttle.in_flight(), 2);
+
+        let waiting = tokio::spawn({
+            let throttle = throttle.clone();
+            async move {
+                let _permit = throttle.acquire().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(throttle.backlog(), 1);
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.await.unwrap();
+        assert_eq!(throttle.backlog(), 0);
+        assert_eq!(throttle.in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_wait_leaves_the_backlog() {
+        let throttle = Throttle::new(1000, 1);
+        let _permit = throttle.acquire().await;
+
+        let waited = tokio::time::timeout(Duration::from_millis(50), throttle.acquire()).await;
+
+        assert!(waited.is_err());
+        assert_eq!(throttle.backlog(), 0);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::helpers::spawn_app;
+use sqlx::{Pool, Postgres};
+
+async fn problem(response: reqwest::Response) -> (String, serde_json::Value) {
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/problem+json"
+    );
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .expect("Response has a request id")
+        .to_str()
+        .unwrap()
+        .to_owned();
+    (request_id, response.json().await.unwrap())
+}
+
+#[sqlx::test]
+async fn unknown_routes_return_not_found_problem(pool: Pool<Postgres>) {
+    let app = spawn_app(pool).await;
+
+    let response = reqwest::get(format!("{}/unknown", app.address))
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(response.status().as_u16(), 404);
+    let (request_id, problem) = problem(response).await;
+    assert_eq!(problem["code"], "not_found");
+    assert_eq!(problem["correlation_id"], request_id);
+}
+
+#[sqlx::test]
+async fn errors_of_middleware_carry_the_request_id(pool: Pool<Postgres>) {
+    let app = spawn_app(pool).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/subscription_policy/reload", app.address))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(response.status().as_u16(), 401);
+    let response_challenge = response.headers()["www-authenticate"].clone();
+    let (request_id, problem) = problem(response).await;
+    assert_eq!(problem["code"], "unauthorized");
+    assert_eq!(problem["status"], 401);
+    assert_eq!(response_challenge, "Basic");
+    assert_eq!(problem["correlation_id"], request_id);
+}
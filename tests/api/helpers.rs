@@ -1,5 +1,8 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use actix_web::dev::ServerHandle;
+use chrono::Utc;
 use once_cell::sync::Lazy;
 use reqwest::{header::CONTENT_TYPE, Response};
 use sqlx::{PgPool, Pool, Postgres};
@@ -8,9 +11,10 @@ use wiremock::{
     Mock, MockServer, ResponseTemplate,
 };
 use zero2prod::{
-    configuration::{get_configuration, ApplicationSettings},
+    clock::FakeClock,
+    configuration::{get_configuration, ApplicationSettings, Settings, WebhookSettings},
     domains::users,
-    startup::build,
+    startup::build_with_clock,
     telemetry::{get_subscriber, init_subscriber},
 };
 
@@ -29,25 +33,58 @@ pub struct TestApp {
     pub address: String,
     pub email_client: MockServer,
     pub app_settings: ApplicationSettings,
+    pub webhook_settings: WebhookSettings,
+    pub clock: Arc<FakeClock>,
+    server_handle: ServerHandle,
+}
+
+impl Drop for TestApp {
+    // Workers of the server would otherwise outlive the test and keep holding
+    // connections of the test pool. The stop command is sent right away, the
+    // returned future only waits for its completion.
+    fn drop(&mut self) {
+        drop(self.server_handle.stop(false));
+    }
 }
 
 pub async fn spawn_app(pool: Pool<Postgres>) -> TestApp {
+    spawn_app_with(pool, |_| {}).await
+}
+
+/// Spawn the app with configuration changed by `configure`.
+pub async fn spawn_app_with(
+    pool: Pool<Postgres>,
+    configure: impl FnOnce(&mut Settings),
+) -> TestApp {
     Lazy::force(&TRACING);
     let email_client = MockServer::start().await;
     let email_base_url = email_client.uri();
 
     std::env::set_var("APP_APPLICATION__PORT", "0");
     std::env::set_var("APP_EMAIL_CLIENT__BASE_URL", &email_base_url);
-    let configuration = get_configuration().expect("Failed to load configuration.yaml");
+    std::env::set_var("APP_SCHEDULER__POLL_INTERVAL_MILLISECONDS", "50");
+    std::env::set_var("APP_OUTBOX__POLL_INTERVAL_MILLISECONDS", "50");
+    let mut configuration = get_configuration().expect("Failed to load configuration.yaml");
+    // Emails are sent one by one unless a test asks for batches.
+    configuration.email_client.batch_api = false;
+    configure(&mut configuration);
     let app_settings = configuration.application.clone();
+    let webhook_settings = configuration.webhook.clone();
+
+    let clock = Arc::new(FakeClock::new(Utc::now()));
 
-    let (server, address) = build(pool.clone(), configuration).expect("Failed to start app.");
+    let (server, address) =
+        build_with_clock(pool.clone(), configuration, clock.clone()).expect("Failed to start app.");
+    let server_handle = server.handle();
     tokio::spawn(server);
 
     TestApp {
         address: format!("http://{}", address),
         email_client,
         app_settings,
+        webhook_settings,
+        clock,
+        server_handle,
     }
 }
 
@@ -69,11 +106,21 @@ pub async fn post_subscription(params: &HashMap<&str, &str>, app: &TestApp) -> R
         .mount_as_scoped(&app.email_client)
         .await;
 
-    reqwest::Client::new()
+    let sent = app.email_client.received_requests().await.unwrap().len();
+    let response = reqwest::Client::new()
         .post(format!("{}/subscriptions", &app.address))
         .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
         .form(&params)
         .send()
         .await
-        .expect("Failed to request endpoint.")
+        .expect("Failed to request endpoint.");
+
+    // The confirmation email is sent by the outbox worker.
+    for _ in 0..100 {
+        if app.email_client.received_requests().await.unwrap().len() > sent {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    response
 }
@@ -0,0 +1,703 @@
+use crate::helpers::{create_user, spawn_app, TestApp};
+use secrecy::ExposeSecret;
+use sqlx::{PgPool, Pool, Postgres};
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use zero2prod::clock::Clock;
+use zero2prod::domains::{
+    subscriber::NewSubscriber,
+    subscribers::{confirm_subscriber, insert_subscriber},
+};
+
+#[sqlx::test]
+async fn draft_can_be_created_and_updated(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let response = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "title": "Work in progress" }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(201, response.status());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(issue["status"], "draft");
+    assert_eq!(issue["content"], serde_json::Value::Null);
+
+    let response = client
+        .put(format!(
+            "{}/admin/issues/{}",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Finished",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    let issue: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(issue["title"], "Finished");
+    assert_eq!(issue["content"]["markdown"], "Hello");
+
+    let response = client
+        .get(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    let issues: Vec<serde_json::Value> = response.json().await.unwrap();
+    assert_eq!(issues.len(), 1);
+}
+
+#[sqlx::test]
+async fn incomplete_draft_cannot_be_sent(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "title": "No content yet" }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount_as_scoped(&app.email_client)
+        .await;
+
+    let response = client
+        .post(format!(
+            "{}/admin/issues/{}/send",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(400, response.status());
+
+    let status = sqlx::query!("select status from newsletter_issues")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .status;
+    assert_eq!(status, "draft");
+}
+
+#[sqlx::test]
+async fn sent_issue_is_no_longer_editable(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", &pool).await.unwrap();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Hello {{name}}",
+            "content": { "html": "<p>Hi {{name}}</p>", "text": "Hi {{name}}" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+    let issue_url = format!(
+        "{}/admin/issues/{}",
+        app.address,
+        issue["id"].as_str().unwrap()
+    );
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_client)
+        .await;
+
+    let response = client
+        .post(format!("{}/send", issue_url))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(202, response.status());
+    let sent: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(sent["status"], "scheduled");
+
+    wait_for_emails(&app, 1).await;
+    for _ in 0..100 {
+        if issue_status(&pool).await == "sent" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(issue_status(&pool).await, "sent");
+    let email_request = &app.email_client.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Hello tom");
+    assert_eq!(body["TextBody"], "Hi tom");
+
+    let response = client
+        .post(format!("{}/send", issue_url))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(409, response.status());
+
+    let response = client
+        .delete(&issue_url)
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(409, response.status());
+}
+
+#[sqlx::test]
+async fn preview_is_personalized_for_subscriber(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", &pool)
+        .await
+        .unwrap();
+    let subscriber_id = sqlx::query!("select id from subscriptions")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .id;
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "News",
+            "content": { "markdown": "Hi **{{name}}**" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    let response = client
+        .get(format!(
+            "{}/admin/issues/{}/preview?subscriber_id={}",
+            app.address,
+            issue["id"].as_str().unwrap(),
+            subscriber_id
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert!(preview["html"]
+        .as_str()
+        .unwrap()
+        .contains("<strong>tom</strong>"));
+    assert_eq!(preview["text"], "Hi tom");
+}
+
+#[sqlx::test]
+async fn test_send_goes_only_to_given_addresses(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", &pool).await.unwrap();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "News",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount_as_scoped(&app.email_client)
+        .await;
+
+    let response = client
+        .post(format!(
+            "{}/admin/issues/{}/test",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "emails": ["editor@email.com", "boss@email.com"] }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    let recipients: Vec<String> = app
+        .email_client
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            assert_eq!(body["Subject"], "[TEST] News");
+            body["To"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(recipients, vec!["editor@email.com", "boss@email.com"]);
+
+    let status = sqlx::query!("select status from newsletter_issues")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .status;
+    assert_eq!(status, "draft");
+}
+
+#[sqlx::test]
+async fn test_send_rejects_invalid_addresses(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "News",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    let response = client
+        .post(format!(
+            "{}/admin/issues/{}/test",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "emails": ["not an email"] }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(400, response.status());
+}
+
+#[sqlx::test]
+async fn scheduled_issue_is_sent_when_due(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", &pool).await.unwrap();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Morning news",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_client)
+        .await;
+
+    let send_at = app.clock.now() + chrono::Duration::hours(12);
+    let response = client
+        .put(format!(
+            "{}/admin/issues/{}/schedule",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "send_at": send_at.to_rfc3339() }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    let scheduled: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(scheduled["status"], "scheduled");
+
+    // Several scheduler rounds pass before the issue is due.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(issue_status(&pool).await, "scheduled");
+
+    app.clock.advance(chrono::Duration::hours(12));
+    for _ in 0..100 {
+        if issue_status(&pool).await == "sent" {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("Scheduled issue was not sent.");
+}
+
+#[sqlx::test]
+async fn stuck_issue_is_sent_again(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", &pool).await.unwrap();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Morning news",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_client)
+        .await;
+    // As if the server stopped while sending the issue.
+    sqlx::query!("update newsletter_issues set status = 'sending'")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(issue_status(&pool).await, "sending");
+
+    app.clock.advance(chrono::Duration::minutes(20));
+    wait_for_emails(&app, 1).await;
+    for _ in 0..100 {
+        if issue_status(&pool).await == "sent" {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("Stuck issue was not sent.");
+}
+
+#[sqlx::test]
+async fn scheduled_issue_can_be_cancelled(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Morning news",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+    let schedule_url = format!(
+        "{}/admin/issues/{}/schedule",
+        app.address,
+        issue["id"].as_str().unwrap()
+    );
+
+    let send_at = app.clock.now() + chrono::Duration::hours(1);
+    client
+        .put(&schedule_url)
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "send_at": send_at.to_rfc3339() }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .error_for_status()
+        .unwrap();
+
+    let response = client
+        .delete(&schedule_url)
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    let draft: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(draft["status"], "draft");
+    assert_eq!(draft["send_at"], serde_json::Value::Null);
+
+    let response = client
+        .delete(&schedule_url)
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(409, response.status());
+}
+
+#[sqlx::test]
+async fn issue_cannot_be_scheduled_into_the_past(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Morning news",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    let send_at = app.clock.now() - chrono::Duration::minutes(1);
+    let response = client
+        .put(format!(
+            "{}/admin/issues/{}/schedule",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "send_at": send_at.to_rfc3339() }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(400, response.status());
+    assert_eq!(issue_status(&pool).await, "draft");
+}
+
+#[sqlx::test]
+async fn local_time_issue_is_delivered_per_timezone(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let prague = NewSubscriber::parse("tom", "tom@gmail.com")
+        .unwrap()
+        .with_timezone("Europe/Prague")
+        .unwrap();
+    insert_subscriber(&prague, "conf_token", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", &pool).await.unwrap();
+    // Without timezone, gets the issue in the default UTC.
+    let unknown = NewSubscriber::parse("petr", "petr@gmail.com").unwrap();
+    insert_subscriber(&unknown, "conf_token2", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token2", &pool).await.unwrap();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let issue: serde_json::Value = client
+        .post(format!("{}/admin/issues", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Morning news",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_client)
+        .await;
+
+    let local_send_at = (app.clock.now() + chrono::Duration::days(2))
+        .date_naive()
+        .and_hms_opt(8, 0, 0)
+        .unwrap();
+    let response = client
+        .put(format!(
+            "{}/admin/issues/{}/schedule",
+            app.address,
+            issue["id"].as_str().unwrap()
+        ))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({ "local_send_at": local_send_at }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    // 08:00 in Prague is at 06:00 or 07:00 UTC, depending on daylight saving.
+    let prague_send_at = chrono::TimeZone::from_utc_datetime(&chrono::Utc, &local_send_at)
+        - chrono::Duration::hours(1);
+    app.clock
+        .advance(prague_send_at - app.clock.now() + chrono::Duration::minutes(1));
+    assert_eq!(wait_for_emails(&app, 1).await, vec!["tom@gmail.com"]);
+    assert_eq!(issue_status(&pool).await, "sending");
+
+    app.clock.advance(chrono::Duration::hours(1));
+    assert_eq!(
+        wait_for_emails(&app, 2).await,
+        vec!["tom@gmail.com", "petr@gmail.com"]
+    );
+    for _ in 0..100 {
+        if issue_status(&pool).await == "sent" {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("Local time issue was not sent.");
+}
+
+/// Wait until the scheduler sends `count` emails, returns their recipients.
+async fn wait_for_emails(app: &TestApp, count: usize) -> Vec<String> {
+    for _ in 0..100 {
+        let requests = app.email_client.received_requests().await.unwrap();
+        if requests.len() >= count {
+            return requests
+                .iter()
+                .map(|r| {
+                    let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+                    body["To"].as_str().unwrap().to_owned()
+                })
+                .collect();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("Scheduler did not send {} emails.", count);
+}
+
+async fn issue_status(pool: &PgPool) -> String {
+    sqlx::query!("select status from newsletter_issues")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .status
+}
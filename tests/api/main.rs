@@ -1,5 +1,12 @@
+mod errors;
 mod health_check;
 mod helpers;
+mod issues;
 mod newsletters;
+mod rate_limit;
+mod subscribers;
 mod subscriptions;
 mod subscriptions_confirm;
+mod templates;
+mod tracking;
+mod webhooks;
@@ -1,4 +1,4 @@
-use crate::helpers::{create_user, post_subscription, spawn_app};
+use crate::helpers::{create_user, post_subscription, spawn_app, spawn_app_with};
 use secrecy::ExposeSecret;
 use sqlx::{Pool, Postgres};
 use std::collections::HashMap;
@@ -21,7 +21,7 @@ async fn delivered_to_subscribed_users(pool: Pool<Postgres>) {
     let subscriber = NewSubscriber::parse("petr", "petr@gmail.com").unwrap();
     let _ = insert_subscriber(&subscriber, "conf_token2", &pool).await;
 
-    let _ = confirm_subscriber("conf_token", &pool).await.unwrap();
+    confirm_subscriber("conf_token", &pool).await.unwrap();
 
     let user = create_user(
         "password",
@@ -56,6 +56,55 @@ async fn delivered_to_subscribed_users(pool: Pool<Postgres>) {
     assert!(response.status().is_success());
 }
 
+#[sqlx::test]
+async fn markdown_content_is_rendered_into_html_and_text(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    let _ = insert_subscriber(&subscriber, "conf_token", &pool).await;
+    confirm_subscriber("conf_token", &pool).await.unwrap();
+
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let request = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "markdown": "Hello **reader**, see [our blog](https://blog.com).",
+        }
+    });
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_client)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/auth/newsletters", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&request)
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    let email_request = &app.email_client.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("<title>Newsletter title</title>"));
+    assert!(html.contains("<strong>reader</strong>"));
+    assert_eq!(
+        body["TextBody"],
+        "Hello reader, see our blog [1].\n\nLinks:\n[1] https://blog.com"
+    );
+}
+
 #[sqlx::test]
 async fn not_delivered_to_unsubscribed_users(pool: Pool<Postgres>) {
     let app = spawn_app(pool.clone()).await;
@@ -149,3 +198,79 @@ async fn unauthorised_request(pool: Pool<Postgres>) {
         .expect("Failed to send request.");
     assert_eq!(401, response.status());
 }
+
+#[sqlx::test]
+async fn delivered_by_batch_with_result_per_recipient(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool.clone(), |c| c.email_client.batch_api = true).await;
+    for (name, email) in [("tom", "tom@gmail.com"), ("petr", "petr@gmail.com")] {
+        let subscriber = NewSubscriber::parse(name, email).unwrap();
+        insert_subscriber(&subscriber, email, &pool).await.unwrap();
+        confirm_subscriber(email, &pool).await.unwrap();
+    }
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK", "MessageID": "first-id" },
+            { "ErrorCode": 300, "Message": "Invalid email request" }
+        ])))
+        .expect(1)
+        .mount(&app.email_client)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/auth/newsletters", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    let email_request = &app.email_client.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let recipients: Vec<&str> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["To"].as_str().unwrap())
+        .collect();
+    assert_eq!(body[0]["Tag"], "newsletter");
+    assert_eq!(
+        body[0]["From"],
+        "\"Zero2Prod Newsletter\" <newsletter@gmail.com>"
+    );
+    assert_eq!(body[0]["ReplyTo"], "editor@gmail.com");
+    assert!(body[0]["Metadata"]["recipient_id"].is_string());
+
+    let results = sqlx::query!(
+        r#"
+        select s.email, r.status, r.message_id, r.error from newsletter_issue_recipients r
+        join subscriptions s on s.id = r.subscriber_id
+        "#
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+    let result_of = |email: &str| results.iter().find(|r| r.email == email).unwrap();
+    assert_eq!(result_of(recipients[0]).status, "sent");
+    assert_eq!(
+        result_of(recipients[0]).message_id.as_deref(),
+        Some("first-id")
+    );
+    assert_eq!(result_of(recipients[1]).status, "failed");
+    assert_eq!(
+        result_of(recipients[1]).error.as_deref(),
+        Some("300: Invalid email request")
+    );
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use sqlx::{Pool, Postgres};
+
+use crate::helpers::{post_subscription, spawn_app_with, TestApp};
+
+async fn get_form(app: &TestApp, forwarded_for: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/subscriptions/form", app.address))
+        .header("X-Forwarded-For", forwarded_for)
+        .send()
+        .await
+        .expect("Failed to request endpoint.")
+}
+
+#[sqlx::test]
+async fn public_routes_are_limited_per_client(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool, |c| {
+        c.rate_limit.public.requests = 2;
+        c.rate_limit.public.window_seconds = 60;
+    })
+    .await;
+
+    let first = get_form(&app, "1.2.3.4").await;
+    assert_eq!(200, first.status());
+    assert_eq!(first.headers()["ratelimit-limit"], "2");
+    assert_eq!(first.headers()["ratelimit-remaining"], "1");
+    assert_eq!(200, get_form(&app, "1.2.3.4").await.status());
+
+    let limited = get_form(&app, "1.2.3.4").await;
+    assert_eq!(429, limited.status());
+    assert_eq!(limited.headers()["ratelimit-remaining"], "0");
+    let retry_after: i64 = limited.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=60).contains(&retry_after));
+
+    // The forwarded address is not trusted, all requests come from the test.
+    assert_eq!(429, get_form(&app, "5.6.7.8").await.status());
+    let health = reqwest::get(format!("{}/health_check", app.address))
+        .await
+        .unwrap();
+    assert_eq!(200, health.status());
+
+    app.clock.advance(chrono::Duration::seconds(60));
+    assert_eq!(200, get_form(&app, "1.2.3.4").await.status());
+}
+
+#[sqlx::test]
+async fn forwarded_address_is_limited_behind_trusted_proxy(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool, |c| {
+        c.rate_limit.public.requests = 1;
+        c.rate_limit.trusted_proxies = vec!["127.0.0.0/8".parse().unwrap()];
+    })
+    .await;
+
+    assert_eq!(200, get_form(&app, "1.2.3.4").await.status());
+    assert_eq!(429, get_form(&app, "1.2.3.4").await.status());
+    assert_eq!(200, get_form(&app, "5.6.7.8").await.status());
+}
+
+#[sqlx::test]
+async fn subscriptions_of_one_email_are_limited(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool, |c| {
+        c.rate_limit.store = zero2prod::configuration::RateLimitStore::Postgres;
+        c.rate_limit.per_email.requests = 1;
+    })
+    .await;
+    let params = HashMap::from([("name", "le guin"), ("email", "le_guin@email.com")]);
+    assert!(post_subscription(&params, &app).await.status().is_success());
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .form(&HashMap::from([
+            ("name", "ursula"),
+            ("email", "LE_GUIN@email.com "),
+        ]))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(429, response.status());
+    assert!(response.headers().contains_key("retry-after"));
+}
+
+#[sqlx::test]
+async fn rejected_subscriptions_do_not_count_for_email(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool, |c| {
+        c.rate_limit.per_email.requests = 1;
+        c.bot_protection.min_fill_seconds = 3;
+    })
+    .await;
+    let form: serde_json::Value = get_form(&app, "1.2.3.4").await.json().await.unwrap();
+    let form_token = form["form_token"].as_str().unwrap();
+    let params = HashMap::from([
+        ("name", "le guin"),
+        ("email", "le_guin@email.com"),
+        ("form_token", form_token),
+    ]);
+
+    for _ in 0..2 {
+        let response = reqwest::Client::new()
+            .post(format!("{}/subscriptions", app.address))
+            .form(&params)
+            .send()
+            .await
+            .expect("Failed to request endpoint.");
+        assert_eq!(400, response.status());
+    }
+
+    app.clock.advance(chrono::Duration::seconds(5));
+    assert!(post_subscription(&params, &app).await.status().is_success());
+}
@@ -0,0 +1,296 @@
+use crate::helpers::{create_user, spawn_app, TestApp};
+use secrecy::ExposeSecret;
+use sqlx::{Pool, Postgres};
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use zero2prod::domains::{
+    subscriber::NewSubscriber,
+    subscribers::{confirm_subscriber, insert_subscriber},
+    users,
+};
+
+async fn setup(app: &TestApp, pool: &Pool<Postgres>) -> users::User {
+    for (name, token) in [("tom", "tom_token"), ("tomas", "tomas_token"), ("petr", "")] {
+        let subscriber = NewSubscriber::parse(name, &format!("{}@gmail.com", name)).unwrap();
+        insert_subscriber(&subscriber, token, pool).await.unwrap();
+    }
+    confirm_subscriber("tom_token", pool).await.unwrap();
+    create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        pool,
+    )
+    .await
+    .expect("Cannot create a user")
+}
+
+async fn get(app: &TestApp, user: &users::User, url: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("{}/admin/subscribers{}", app.address, url))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.")
+}
+
+async fn names(response: reqwest::Response) -> (Vec<String>, Option<String>) {
+    assert_eq!(200, response.status());
+    let page: serde_json::Value = response.json().await.unwrap();
+    let names = page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["name"].as_str().unwrap().to_owned())
+        .collect();
+    (names, page["next_cursor"].as_str().map(String::from))
+}
+
+#[sqlx::test]
+async fn subscribers_are_listed_by_pages(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    let (first, cursor) = names(get(&app, &user, "?search=tom&limit=1").await).await;
+    let (second, last_cursor) = names(
+        get(
+            &app,
+            &user,
+            &format!("?search=tom&limit=1&cursor={}", cursor.unwrap()),
+        )
+        .await,
+    )
+    .await;
+    let (confirmed, _) = names(get(&app, &user, "?status=confirmed").await).await;
+
+    assert_eq!(first, vec!["tomas"]);
+    assert_eq!(second, vec!["tom"]);
+    assert_eq!(last_cursor, None);
+    assert_eq!(confirmed, vec!["tom"]);
+}
+
+#[sqlx::test]
+async fn invalid_pages_are_rejected(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    for query in ["?cursor=nonsense", "?limit=0", "?limit=1000"] {
+        let response = get(&app, &user, query).await;
+
+        assert_eq!(400, response.status(), "{}", query);
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["code"], "invalid_fields");
+    }
+}
+
+#[sqlx::test]
+async fn admin_changes_status_of_subscriber(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+    let client = reqwest::Client::new();
+    let petr: serde_json::Value = get(&app, &user, "?search=petr").await.json().await.unwrap();
+    let id = petr["subscribers"][0]["id"].as_str().unwrap().to_owned();
+
+    let post = |action: &str| {
+        client
+            .post(format!(
+                "{}/admin/subscribers/{}/{}",
+                app.address, id, action
+            ))
+            .basic_auth(&user.username, Some("password"))
+            .send()
+    };
+    let response = post("confirm").await.unwrap();
+    assert_eq!(200, response.status());
+    let subscriber: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(subscriber["status"], "confirmed");
+
+    let response = post("confirm").await.unwrap();
+    assert_eq!(409, response.status());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "subscription_status_conflict");
+
+    let response = post("unsubscribe").await.unwrap();
+    assert_eq!(200, response.status());
+
+    let detail: serde_json::Value = get(&app, &user, &format!("/{}", id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail["status"], "unsubscribed");
+    let history = detail["history"].as_array().unwrap();
+    assert_eq!(history[0]["event"], "pending_confirmation");
+    assert_eq!(history[1]["event"], "confirmed");
+    assert_eq!(history[1]["detail"], format!("admin:{}", user.username));
+    assert_eq!(history[2]["event"], "unsubscribed");
+}
+
+#[sqlx::test]
+async fn deleted_subscriber_keeps_deliveries_of_issues(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+    let client = reqwest::Client::new();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_client)
+        .await;
+    let response = client
+        .post(format!("{}/auth/newsletters", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "html": "<p>Hello</p>", "text": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    let tom: serde_json::Value = get(&app, &user, "?status=confirmed")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let id = tom["subscribers"][0]["id"].as_str().unwrap().to_owned();
+    let detail: serde_json::Value = get(&app, &user, &format!("/{}", id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail["history"][2]["event"], "sent");
+
+    let delete = || {
+        client
+            .delete(format!("{}/admin/subscribers/{}", app.address, id))
+            .basic_auth(&user.username, Some("password"))
+            .send()
+    };
+    assert_eq!(204, delete().await.unwrap().status());
+    assert_eq!(404, delete().await.unwrap().status());
+    assert_eq!(404, get(&app, &user, &format!("/{}", id)).await.status());
+
+    let recipients = sqlx::query!("select subscriber_id from newsletter_issue_recipients")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(recipients.len(), 1);
+    assert_eq!(recipients[0].subscriber_id, None);
+}
+
+async fn import(app: &TestApp, user: &users::User, query: &str, csv: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/admin/subscribers/import{}", app.address, query))
+        .basic_auth(&user.username, Some("password"))
+        .header("content-type", "text/csv")
+        .body(csv.to_owned())
+        .send()
+        .await
+        .expect("Failed to send request.")
+}
+
+#[sqlx::test]
+async fn imported_pending_subscribers_get_confirmation_email(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_client)
+        .await;
+
+    let response = import(
+        &app,
+        &user,
+        "?status=pending",
+        "email,name\njan@gmail.com,jan\ntom@gmail.com,tom\n",
+    )
+    .await;
+
+    assert_eq!(200, response.status());
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["imported"], 1);
+    assert_eq!(report["errors"][0]["error"], "exists");
+    for _ in 0..100 {
+        if !app
+            .email_client
+            .received_requests()
+            .await
+            .unwrap()
+            .is_empty()
+        {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let (pending, _) = names(get(&app, &user, "?search=jan").await).await;
+    assert_eq!(pending, vec!["jan"]);
+}
+
+#[sqlx::test]
+async fn import_reports_errors_as_csv(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    let response = import(
+        &app,
+        &user,
+        "?status=confirmed&consent_source=old+list&dry_run=true&report=csv",
+        "email,name\njan@gmail.com,jan\nnot an email,x\n",
+    )
+    .await;
+
+    assert_eq!(200, response.status());
+    assert_eq!(response.headers()["content-type"], "text/csv");
+    assert_eq!(
+        response.text().await.unwrap(),
+        "row,email,error,detail\n3,not an email,invalid,Invalid email.\n"
+    );
+    let (jan, _) = names(get(&app, &user, "?search=jan").await).await;
+    assert!(jan.is_empty());
+}
+
+#[sqlx::test]
+async fn import_needs_consent_source_and_header(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    let response = import(&app, &user, "?status=confirmed", "email,name\n").await;
+    assert_eq!(400, response.status());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "invalid_fields");
+
+    let response = import(&app, &user, "?status=pending", "email\njan@gmail.com\n").await;
+    assert_eq!(400, response.status());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "malformed_request");
+}
+
+#[sqlx::test]
+async fn subscribers_are_exported_with_filters(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    let response = get(&app, &user, "/export?search=tom").await;
+    assert_eq!(200, response.status());
+    assert_eq!(response.headers()["content-type"], "text/csv");
+    let csv = response.text().await.unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("id,email,name,status,"));
+    assert!(lines[1].contains(",tom@gmail.com,tom,confirmed,"));
+
+    let response = get(&app, &user, "/export?format=ndjson&status=confirmed").await;
+    assert_eq!(200, response.status());
+    let ndjson = response.text().await.unwrap();
+    let rows: Vec<serde_json::Value> = ndjson
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["email"], "tom@gmail.com");
+}
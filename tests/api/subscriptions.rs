@@ -1,8 +1,16 @@
 use reqwest::header::CONTENT_TYPE;
+use secrecy::ExposeSecret;
 use sqlx::{Pool, Postgres};
 use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::bot_protection::{solve, Challenge};
+use zero2prod::domains::subscriber::NewSubscriber;
+use zero2prod::domains::subscribers::insert_subscriber;
 
-use crate::helpers::{post_subscription, spawn_app};
+use crate::helpers::{create_user, post_subscription, spawn_app, spawn_app_with, TestApp};
 
 #[sqlx::test]
 async fn subscriptions_works(pool: Pool<Postgres>) {
@@ -19,11 +27,12 @@ async fn subscriptions_works(pool: Pool<Postgres>) {
     assert_eq!(&saved.name, params.get("name").unwrap());
     assert_eq!(&saved.email, params.get("email").unwrap());
     assert_eq!(&saved.status, "pending_confirmation");
-    assert_ne!(saved.confirmation_token.len(), 0);
+    assert_ne!(saved.confirmation_token.unwrap().len(), 0);
 
     let email_request = &app.email_client.received_requests().await.unwrap()[0];
 
     let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["From"], "\"Zero2Prod\" <test@gmail.com>");
 
     let get_link = |s: &str| {
         let links: Vec<linkify::Link<'_>> = linkify::LinkFinder::new()
@@ -34,8 +43,8 @@ async fn subscriptions_works(pool: Pool<Postgres>) {
         links[0].as_str().to_owned()
     };
 
-    let html_link = get_link(&body["html"].to_string());
-    let text_link = get_link(&body["text"].to_string());
+    let html_link = get_link(&body["HtmlBody"].to_string());
+    let text_link = get_link(&body["TextBody"].to_string());
     assert_eq!(html_link, text_link);
 
     assert!(response.status().is_success());
@@ -55,6 +64,52 @@ async fn subscriptions_works(pool: Pool<Postgres>) {
     assert_eq!(&saved.status, "confirmed");
 }
 
+#[sqlx::test]
+async fn confirmed_subscriber_is_welcomed_once(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "tom_token", &pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_client)
+        .await;
+    let confirm = || {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/subscriptions/confirm?token=tom_token",
+                app.address
+            ))
+            .send()
+    };
+
+    assert!(confirm().await.unwrap().status().is_success());
+    assert!(confirm().await.unwrap().status().is_success());
+
+    // The welcome email is sent by the outbox worker.
+    for _ in 0..100 {
+        if !app
+            .email_client
+            .received_requests()
+            .await
+            .unwrap()
+            .is_empty()
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let requests = app.email_client.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["Subject"], "Welcome!");
+    assert_eq!(body["To"], "tom@gmail.com");
+}
+
 #[sqlx::test]
 async fn subscriptions_doesnt_works_by_invalid_fields(pool: Pool<Postgres>) {
     let app = spawn_app(pool.clone()).await;
@@ -72,6 +127,103 @@ async fn subscriptions_doesnt_works_by_invalid_fields(pool: Pool<Postgres>) {
     assert!(response.status().is_client_error());
 }
 
+#[sqlx::test]
+async fn subscriptions_accept_json(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_client)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .json(&serde_json::json!({
+            "name": "le guin",
+            "email": "le_guin@email.com",
+            "timezone": "Europe/Prague"
+        }))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert!(response.status().is_success());
+    let saved = sqlx::query!("select name, email, timezone from subscriptions")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.email, "le_guin@email.com");
+    assert_eq!(saved.timezone.as_deref(), Some("Europe/Prague"));
+}
+
+#[sqlx::test]
+async fn invalid_subscriptions_return_problem_details(pool: Pool<Postgres>) {
+    let app = spawn_app(pool).await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/subscriptions", app.address);
+    let params = HashMap::from([("name", ""), ("email", "not an email")]);
+
+    let by_json = client.post(&url).json(&params).send().await.unwrap();
+    let by_form = client.post(&url).form(&params).send().await.unwrap();
+
+    for response in [by_json, by_form] {
+        assert_eq!(400, response.status());
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["code"], "invalid_fields");
+        assert_eq!(problem["errors"]["name"][0]["code"], "length");
+        assert_eq!(problem["errors"]["email"][0]["code"], "email");
+    }
+}
+
+#[sqlx::test]
+async fn subscribing_again_is_a_conflict(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let subscriber = NewSubscriber::parse("le guin", "le_guin@email.com").unwrap();
+    insert_subscriber(&subscriber, "le_guin_token", &pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .json(&serde_json::json!({
+            "name": "le guin",
+            "email": "le_guin@email.com",
+        }))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(409, response.status());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "already_subscribed");
+    let saved = sqlx::query!(r#"select count(*) as "count!" from queued_emails"#)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 0);
+}
+
+#[sqlx::test]
+async fn malformed_json_returns_problem_details(pool: Pool<Postgres>) {
+    let app = spawn_app(pool).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header(CONTENT_TYPE, "application/json")
+        .body(r#"{"name": "le guin""#)
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(400, response.status());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "malformed_request");
+    assert!(problem["detail"].is_string());
+}
+
 #[sqlx::test]
 async fn subscriptions_doesnt_works_by_missing_fields(pool: Pool<Postgres>) {
     let app = spawn_app(pool).await;
@@ -94,3 +246,280 @@ async fn subscriptions_doesnt_works_by_missing_fields(pool: Pool<Postgres>) {
         assert_eq!(response.status().as_u16(), 400);
     }
 }
+
+#[sqlx::test]
+async fn subscriptions_stores_timezone(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let params = HashMap::from([
+        ("name", "le guin"),
+        ("email", "le_guin@email.com"),
+        ("timezone", "America/Los_Angeles"),
+    ]);
+
+    let response = post_subscription(&params, &app).await;
+    assert!(response.status().is_success());
+
+    let saved = sqlx::query!("select timezone from subscriptions")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.timezone.as_deref(), Some("America/Los_Angeles"));
+}
+
+#[sqlx::test]
+async fn subscriptions_doesnt_works_by_unknown_timezone(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let params = HashMap::from([
+        ("name", "le guin"),
+        ("email", "le_guin@email.com"),
+        ("timezone", "Earthsea/Roke"),
+    ]);
+
+    let response = client
+        .post(format!("{}/subscriptions", app.address))
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .form(&params)
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(400, response.status());
+}
+
+#[sqlx::test]
+async fn subscriptions_rejected_by_policy_return_reason(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let cases = [
+        ("noreply@gmail.com", "role_account"),
+        ("le_guin@mailinator.com", "blocked_domain"),
+        ("le_guin+books+daily@gmail.com", "alias_depth"),
+    ];
+
+    for (email, reason) in cases {
+        let response = client
+            .post(format!("{}/subscriptions", app.address))
+            .form(&HashMap::from([("name", "le guin"), ("email", email)]))
+            .send()
+            .await
+            .expect("Failed to request endpoint.");
+
+        assert_eq!(400, response.status());
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["reason"], reason, "{}", email);
+    }
+    let saved = sqlx::query!("select count(*) as \"count!\" from subscriptions")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 0);
+}
+
+#[sqlx::test]
+async fn blocked_domains_are_reloaded(pool: Pool<Postgres>) {
+    let domains_file = std::env::temp_dir().join(format!("domains-{}.txt", uuid::Uuid::new_v4()));
+    std::fs::write(&domains_file, "").unwrap();
+    let app = spawn_app_with(pool.clone(), |c| {
+        c.subscription_policy.domains_file = Some(domains_file.to_string_lossy().into());
+    })
+    .await;
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+    let params = HashMap::from([("name", "le guin"), ("email", "le_guin@earthsea.com")]);
+    assert!(post_subscription(&params, &app).await.status().is_success());
+
+    std::fs::write(&domains_file, "earthsea.com\n!roke.earthsea.com\n").unwrap();
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/subscription_policy/reload", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+    assert_eq!(200, response.status());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, serde_json::json!({ "blocked": 1, "allowed": 1 }));
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .form(&HashMap::from([
+            ("name", "ged"),
+            ("email", "ged@earthsea.com"),
+        ]))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+    assert_eq!(400, response.status());
+    let params = HashMap::from([("name", "ged"), ("email", "ged@roke.earthsea.com")]);
+    assert!(post_subscription(&params, &app).await.status().is_success());
+}
+
+#[sqlx::test]
+async fn filled_honeypot_is_silently_dropped(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .form(&HashMap::from([
+            ("name", "le guin"),
+            ("email", "le_guin@email.com"),
+            ("website", "https://spam.com"),
+        ]))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert!(response.status().is_success());
+    let saved = sqlx::query!("select count(*) as \"count!\" from subscriptions")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 0);
+}
+
+#[sqlx::test]
+async fn bot_protection_checks_fill_time_and_challenge(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool.clone(), |c| {
+        c.bot_protection.min_fill_seconds = 3;
+        c.bot_protection.proof_of_work_difficulty = 8;
+    })
+    .await;
+    let get_json = |path: &'static str| {
+        let url = format!("{}{}", app.address, path);
+        async move {
+            let response = reqwest::get(url)
+                .await
+                .expect("Failed to request endpoint.");
+            assert_eq!(200, response.status());
+            response.json::<serde_json::Value>().await.unwrap()
+        }
+    };
+    let form = get_json("/subscriptions/form").await;
+    let form_token = form["form_token"].as_str().unwrap().to_owned();
+    let challenge = get_json("/subscriptions/challenge").await;
+    let challenge = Challenge {
+        token: challenge["token"].as_str().unwrap().to_owned(),
+        difficulty: challenge["difficulty"].as_u64().unwrap() as u8,
+    };
+    let solution = solve(&challenge);
+    let mut params = HashMap::from([
+        ("name", "le guin"),
+        ("email", "le_guin@email.com"),
+        ("form_token", &form_token),
+        ("challenge", &challenge.token),
+        ("solution", &solution),
+    ]);
+
+    assert_eq!(rejection(&params, &app).await, "too_fast");
+
+    app.clock.advance(chrono::Duration::seconds(5));
+    params.remove("solution");
+    assert_eq!(rejection(&params, &app).await, "wrong_solution");
+    params.insert("solution", &solution);
+    assert!(post_subscription(&params, &app).await.status().is_success());
+
+    // A challenge is solved only once.
+    params.insert("email", "ged@email.com");
+    assert_eq!(rejection(&params, &app).await, "invalid_challenge");
+}
+
+#[sqlx::test]
+async fn challenge_is_not_found_when_proof_of_work_is_off(pool: Pool<Postgres>) {
+    let app = spawn_app(pool).await;
+
+    let response = reqwest::get(format!("{}/subscriptions/challenge", app.address))
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(404, response.status());
+}
+
+/// Reason code of a rejected subscription.
+async fn rejection(params: &HashMap<&str, &str>, app: &TestApp) -> String {
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .form(params)
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+    assert_eq!(400, response.status());
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["reason"].as_str().unwrap().to_owned()
+}
+
+#[sqlx::test]
+async fn subscribe_succeeds_while_provider_is_down(pool: Pool<Postgres>) {
+    let app = spawn_app_with(pool.clone(), |c| {
+        c.email_client.circuit_breaker.failure_ratio = 1.0;
+        c.email_client.circuit_breaker.window = 1;
+        c.email_client.circuit_breaker.open_milliseconds = 500;
+    })
+    .await;
+    let provider_health = || async {
+        let response = reqwest::get(format!("{}/health_check/email_provider", app.address))
+            .await
+            .expect("Failed to request endpoint.");
+        let body: serde_json::Value = response.json().await.unwrap();
+        body["circuit"].as_str().unwrap().to_owned()
+    };
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .mount(&app.email_client)
+        .await;
+
+    // Posted without `post_subscription`, which mocks a working provider.
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .form(&HashMap::from([
+            ("name", "le guin"),
+            ("email", "le_guin@email.com"),
+        ]))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert!(response.status().is_success());
+    let saved = sqlx::query!("select email from subscriptions")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.email, "le_guin@email.com");
+    let provider_health = &provider_health;
+    wait_for(move || async move { provider_health().await == "open" }).await;
+
+    // Sent again once the provider is back.
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_client)
+        .await;
+    let email_client = &app.email_client;
+    wait_for(move || async move { email_client.received_requests().await.unwrap().len() == 2 })
+        .await;
+    let requests = app.email_client.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+    assert_eq!(body["To"], "le_guin@email.com");
+    assert_eq!(provider_health().await, "closed");
+}
+
+/// Wait until the outbox worker makes the condition true.
+async fn wait_for<F, Fut>(condition: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    for _ in 0..100 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Waiting for the outbox worker timed out.");
+}
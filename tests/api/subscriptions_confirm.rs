@@ -16,3 +16,22 @@ async fn rejected_without_token(pool: Pool<Postgres>) {
         .expect("Failed to send subscriptions confirm request");
     assert_eq!(response.status().as_u16(), 400);
 }
+
+#[sqlx::test]
+async fn unknown_token_is_unauthorized(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get(format!(
+            "{}/subscriptions/confirm?token=unknown",
+            app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to send subscriptions confirm request");
+
+    assert_eq!(response.status().as_u16(), 401);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["code"], "invalid_token");
+}
@@ -0,0 +1,129 @@
+use crate::helpers::{create_user, post_subscription, spawn_app};
+use secrecy::ExposeSecret;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+
+#[sqlx::test]
+async fn stored_confirmation_template_is_used(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let template = serde_json::json!({
+        "subject": "Please confirm, {{name}}",
+        "html": "<a href='{{confirmation_link}}'>Confirm</a>",
+        "text": "Confirm at {{confirmation_link}}",
+    });
+    let response = client
+        .put(format!("{}/admin/templates/confirmation", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&template)
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(201, response.status());
+    let saved: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(saved["version"], 1);
+
+    let params = HashMap::from([("name", "le guin"), ("email", "le_guin@email.com")]);
+    let response = post_subscription(&params, &app).await;
+    assert!(response.status().is_success());
+
+    let email_request = &app.email_client.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Please confirm, le guin");
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .starts_with("Confirm at http://"));
+}
+
+#[sqlx::test]
+async fn preview_renders_sample_data(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/templates/welcome/preview", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert!(preview["html"].as_str().unwrap().contains("Jane Doe"));
+    assert!(preview["text"].as_str().unwrap().contains("Jane Doe"));
+}
+
+#[sqlx::test]
+async fn invalid_template_is_rejected(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let template = serde_json::json!({
+        "subject": "Subject",
+        "html": "",
+        "text": "text",
+    });
+    let response = client
+        .put(format!("{}/admin/templates/welcome", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&template)
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(400, response.status());
+}
+
+#[sqlx::test]
+async fn unknown_template_is_not_found(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/templates/unknown/preview", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(404, response.status());
+}
+
+#[sqlx::test]
+async fn unauthorised_request(pool: Pool<Postgres>) {
+    let app = spawn_app(pool).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/admin/templates", app.address))
+        .basic_auth("name", Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(401, response.status());
+}
@@ -0,0 +1,277 @@
+use crate::helpers::{create_user, spawn_app, TestApp};
+use reqwest::header::{CONTENT_TYPE, LOCATION, USER_AGENT};
+use secrecy::ExposeSecret;
+use sqlx::{Pool, Postgres};
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use zero2prod::domains::{
+    subscriber::NewSubscriber,
+    subscribers::{confirm_subscriber, insert_subscriber},
+    users,
+};
+
+/// Send a newsletter with a link, returns the html and text parts.
+async fn send_newsletter(
+    app: &TestApp,
+    user: &users::User,
+    track_opens: bool,
+    track_clicks: bool,
+) -> (String, String) {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_client)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/auth/newsletters", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Hello, see [our site](https://example.com/?a=1&b=2)." },
+            "track_opens": track_opens,
+            "track_clicks": track_clicks
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    let requests = app.email_client.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    (
+        body["HtmlBody"].as_str().unwrap().to_owned(),
+        body["TextBody"].as_str().unwrap().to_owned(),
+    )
+}
+
+/// Path of the first tracking url with the prefix, the url points to the
+/// configured base url instead of the test server.
+fn tracking_path(s: &str, prefix: &str) -> Option<String> {
+    let start = s.find(prefix)?;
+    let end = start
+        + s[start..]
+            .find(|c: char| c == '"' || c.is_whitespace())
+            .unwrap_or(s.len() - start);
+    Some(s[start..end].to_owned())
+}
+
+fn pixel_path(html: &str) -> Option<String> {
+    tracking_path(html, "/tracking/open/")
+}
+
+/// Confirmed subscriber and an admin user.
+async fn setup(app: &TestApp, pool: &Pool<Postgres>) -> users::User {
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", pool).await.unwrap();
+    create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        pool,
+    )
+    .await
+    .expect("Cannot create a user")
+}
+
+#[sqlx::test]
+async fn opens_are_not_tracked_by_default(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    let (html, text) = send_newsletter(&app, &user, false, false).await;
+
+    assert_eq!(pixel_path(&html), None);
+    assert_eq!(tracking_path(&text, "/tracking/opt_out/"), None);
+}
+
+#[sqlx::test]
+async fn opens_are_recorded_by_pixel(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = setup(&app, &pool).await;
+
+    let (html, _) = send_newsletter(&app, &user, true, false).await;
+    let pixel_path = pixel_path(&html).expect("Missing tracking pixel");
+
+    for _ in 0..2 {
+        let response = client
+            .get(format!("{}{}", app.address, pixel_path))
+            .header(USER_AGENT, "Thunderbird")
+            .send()
+            .await
+            .expect("Failed to send request.");
+        assert!(response.status().is_success());
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/gif");
+        assert_eq!(response.bytes().await.unwrap().len(), 43);
+    }
+
+    let event = sqlx::query!("select user_agent from open_events limit 1")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(event.user_agent.as_deref(), Some("Thunderbird"));
+
+    let issue_id = sqlx::query!("select id from newsletter_issues")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .id;
+    let issue: serde_json::Value = client
+        .get(format!("{}/admin/issues/{}", app.address, issue_id))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        issue["opens"],
+        serde_json::json!({ "unique": 1, "total": 2 })
+    );
+}
+
+#[sqlx::test]
+async fn opted_out_subscriber_is_not_tracked(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::new();
+    let user = setup(&app, &pool).await;
+
+    let (html, text) = send_newsletter(&app, &user, true, false).await;
+    let opt_out_path = tracking_path(&html, "/tracking/opt_out/").expect("Missing opt-out link");
+    assert_eq!(
+        tracking_path(&text, "/tracking/opt_out/"),
+        Some(opt_out_path.clone())
+    );
+
+    // Following the link only asks for confirmation.
+    let response = client
+        .get(format!("{}{}", app.address, opt_out_path))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<form method=\"post\""));
+    let (html, _) = send_newsletter(&app, &user, true, false).await;
+    assert!(pixel_path(&html).is_some());
+
+    let response = client
+        .post(format!("{}{}", app.address, opt_out_path))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert!(response.status().is_success());
+
+    let (html, _) = send_newsletter(&app, &user, true, false).await;
+    assert_eq!(pixel_path(&html), None);
+}
+
+#[sqlx::test]
+async fn unknown_pixel_still_returns_image(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/tracking/open/{}",
+            app.address,
+            uuid::Uuid::new_v4()
+        ))
+        .send()
+        .await
+        .expect("Failed to send request.");
+
+    assert!(response.status().is_success());
+    assert_eq!(response.headers()[CONTENT_TYPE], "image/gif");
+}
+
+#[sqlx::test]
+async fn links_are_not_rewritten_by_default(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let user = setup(&app, &pool).await;
+
+    let (html, text) = send_newsletter(&app, &user, false, false).await;
+
+    assert!(html.contains("href=\"https://example.com/?a=1&amp;b=2\""));
+    assert!(text.contains("https://example.com/?a=1&b=2"));
+}
+
+#[sqlx::test]
+async fn clicks_are_recorded_and_redirected(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let user = setup(&app, &pool).await;
+
+    let (html, text) = send_newsletter(&app, &user, false, true).await;
+    assert!(!html.contains("example.com"));
+    assert!(!text.contains("example.com"));
+    let link_path = tracking_path(&text, "/r/").expect("Missing tracked link");
+    assert_eq!(tracking_path(&html, "/r/"), Some(link_path.clone()));
+
+    let response = client
+        .get(format!("{}{}", app.address, link_path))
+        .send()
+        .await
+        .expect("Failed to send request.");
+    assert_eq!(302, response.status());
+    assert_eq!(response.headers()[LOCATION], "https://example.com/?a=1&b=2");
+
+    let issue_id = sqlx::query!("select id from newsletter_issues")
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .id;
+    let issue: serde_json::Value = client
+        .get(format!("{}/admin/issues/{}", app.address, issue_id))
+        .basic_auth(&user.username, Some("password"))
+        .send()
+        .await
+        .expect("Failed to send request.")
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        issue["clicks"],
+        serde_json::json!([{ "url": "https://example.com/?a=1&b=2", "unique": 1, "total": 1 }])
+    );
+}
+
+#[sqlx::test]
+async fn tampered_link_is_not_redirected(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let user = setup(&app, &pool).await;
+
+    let (_, text) = send_newsletter(&app, &user, false, true).await;
+    let link_path = tracking_path(&text, "/r/").expect("Missing tracked link");
+    let (payload, signature) = link_path.rsplit_once('.').unwrap();
+    let (recipient, _) = payload.rsplit_once('.').unwrap();
+
+    for path in [
+        format!("{}.{}.{}", recipient, 999, signature),
+        format!("{}.{}", payload, "00"),
+        "/r/https://evil.com".to_string(),
+    ] {
+        let response = client
+            .get(format!("{}{}", app.address, path))
+            .send()
+            .await
+            .expect("Failed to send request.");
+        assert_eq!(404, response.status());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::helpers::{create_user, spawn_app, TestApp};
+use reqwest::{header::CONTENT_TYPE, Response};
+use secrecy::ExposeSecret;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use wiremock::{
+    matchers::{method, path},
+    Mock, ResponseTemplate,
+};
+
+use zero2prod::domains::{
+    subscriber::NewSubscriber,
+    subscribers::{confirm_subscriber, insert_subscriber},
+};
+
+fn hard_bounce(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "MessageStream": "outbound",
+        "ID": 4323372036854775807u64,
+        "Type": "HardBounce",
+        "TypeCode": 1,
+        "Name": "Hard bounce",
+        "Tag": "",
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "ServerID": 23,
+        "Description": "The server was unable to deliver your message (ex: unknown user, mailbox not found).",
+        "Details": "Test bounce details",
+        "Email": email,
+        "From": "sender@example.com",
+        "BouncedAt": "2023-07-14T16:09:19Z",
+        "DumpAvailable": true,
+        "Inactive": true,
+        "CanActivate": true,
+        "Subject": "Newsletter"
+    })
+}
+
+fn spam_complaint(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "MessageStream": "outbound",
+        "ID": 42,
+        "Type": "SpamComplaint",
+        "TypeCode": 512,
+        "Name": "Spam complaint",
+        "MessageID": "00000000-0000-0000-0000-000000000000",
+        "ServerID": 1234,
+        "Description": "The subscriber explicitly marked this message as spam.",
+        "Email": email,
+        "From": "sender@example.com",
+        "BouncedAt": "2023-07-14T16:09:19Z",
+        "Inactive": true,
+        "Subject": "Newsletter"
+    })
+}
+
+async fn post_webhook(app: &TestApp, payload: &serde_json::Value) -> Response {
+    reqwest::Client::new()
+        .post(format!("{}/webhooks/postmark", app.address))
+        .basic_auth(
+            &app.webhook_settings.username,
+            Some(app.webhook_settings.password.expose_secret()),
+        )
+        .json(payload)
+        .send()
+        .await
+        .expect("Failed to send request.")
+}
+
+async fn subscribe(pool: &Pool<Postgres>) {
+    let subscriber = NewSubscriber::parse("tom", "tom@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token", pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token", pool).await.unwrap();
+}
+
+async fn subscription_status(pool: &Pool<Postgres>) -> String {
+    sqlx::query!("select status from subscriptions")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .status
+}
+
+#[sqlx::test]
+async fn hard_bounce_suppresses_address(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    subscribe(&pool).await;
+
+    let response = post_webhook(&app, &hard_bounce("Tom@gmail.com")).await;
+
+    assert!(response.status().is_success());
+    assert_eq!(subscription_status(&pool).await, "bounced");
+    let suppression = sqlx::query!("select email, reason from suppressions")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(suppression.email, "tom@gmail.com");
+    assert_eq!(suppression.reason, "bounced");
+}
+
+#[sqlx::test]
+async fn spam_complaint_suppresses_address(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    subscribe(&pool).await;
+
+    let response = post_webhook(&app, &spam_complaint("tom@gmail.com")).await;
+
+    assert!(response.status().is_success());
+    assert_eq!(subscription_status(&pool).await, "complained");
+}
+
+#[sqlx::test]
+async fn soft_bounce_is_ignored(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    subscribe(&pool).await;
+    let mut payload = hard_bounce("tom@gmail.com");
+    payload["Type"] = "SoftBounce".into();
+
+    let response = post_webhook(&app, &payload).await;
+
+    assert!(response.status().is_success());
+    assert_eq!(subscription_status(&pool).await, "confirmed");
+}
+
+#[sqlx::test]
+async fn suppressed_address_cannot_subscribe_again(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    post_webhook(&app, &spam_complaint("tom@gmail.com")).await;
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount_as_scoped(&app.email_client)
+        .await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .form(&HashMap::from([
+            ("name", "tom"),
+            ("email", "tom@gmail.com"),
+        ]))
+        .send()
+        .await
+        .expect("Failed to request endpoint.");
+
+    assert_eq!(400, response.status());
+}
+
+#[sqlx::test]
+async fn newsletter_skips_suppressed_address(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    subscribe(&pool).await;
+    let subscriber = NewSubscriber::parse("petr", "petr@gmail.com").unwrap();
+    insert_subscriber(&subscriber, "conf_token2", &pool)
+        .await
+        .unwrap();
+    confirm_subscriber("conf_token2", &pool).await.unwrap();
+    // Suppressed without the subscription knowing, e.g. added by hand.
+    sqlx::query!(
+        "insert into suppressions (email, reason, created_at) values ('tom@gmail.com', 'bounced', now())"
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let user = create_user(
+        "password",
+        app.app_settings.hash_secret.expose_secret(),
+        &pool,
+    )
+    .await
+    .expect("Cannot create a user");
+
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_client)
+        .await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/auth/newsletters", app.address))
+        .basic_auth(&user.username, Some("password"))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": { "markdown": "Hello" }
+        }))
+        .send()
+        .await
+        .expect("Failed to send request.");
+
+    assert!(response.status().is_success());
+    let email_request = &app.email_client.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "petr@gmail.com");
+}
+
+#[sqlx::test]
+async fn unauthorised_webhook(pool: Pool<Postgres>) {
+    let app = spawn_app(pool.clone()).await;
+    subscribe(&pool).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/webhooks/postmark", app.address))
+        .basic_auth(&app.webhook_settings.username, Some("wrong"))
+        .json(&hard_bounce("tom@gmail.com"))
+        .send()
+        .await
+        .expect("Failed to send request.");
+
+    assert_eq!(401, response.status());
+    assert_eq!(subscription_status(&pool).await, "confirmed");
+}